@@ -1,4 +1,8 @@
 fn main() {
+    // Host builds only run the unit tests of the protocol modules.
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() != Ok("xtensa") {
+        return;
+    }
     linker_be_nice();
     // Add defmt linker script
     println!("cargo:rustc-link-arg=-Tdefmt.x");
@@ -9,27 +9,52 @@
 use log::{info, error};
 use defmt_rtt as _;
 use static_cell::StaticCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use embassy_executor::Spawner;
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_net::{Stack, StackResources};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
 use esp_hal::{
     clock::CpuClock, 
     timer::timg::TimerGroup,
     i2c::master::{Config, I2c},
-    delay::Delay,
     time::Rate,
     gpio::Io,
+    rtc_cntl::reset_reason,
+    system::{software_reset, Cpu},
+    uart::Uart,
+    Async,
 };
-
-use scd4x::Scd4x;
-use sgp4x::Sgp41;
-use pmsx003::PmsX003Sensor;
-
-use air_quality_monitor::wifi::{WiFiFacade, WiFiFacadeConfig};
-use air_quality_monitor::mqtt::{MqttFacade, MqttFacadeConfig};
-use air_quality_monitor::mdns::{MdnsFacade};
-use air_quality_monitor::home_assistant::{HomeAssistantFacade, HomeAssistantFacadeConfig};
+use embedded_io_async::Write as _;
+use heapless::{String, Vec};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, FlashRegion, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_storage::FlashStorage;
+
+use air_quality_monitor::sensirion::{Scd41, Sgp41};
+use air_quality_monitor::pms5003::Pms5003;
+
+use air_quality_monitor::config::ConfigStore;
+use air_quality_monitor::config_record::{ConfigError, DeviceConfig, CONFIG_KEYS};
+use air_quality_monitor::wifi::{WiFiFacade, WiFiLinkMonitor};
+use air_quality_monitor::mqtt::{MqttBroker, MqttFacade, MqttPublisher, MQTT_MAX_BROKERS};
+use air_quality_monitor::mdns::{MdnsFacade, MdnsResponderConfig, MdnsService};
+use air_quality_monitor::dns_sd::UnicastDnsFacade;
+use air_quality_monitor::broker_lookup::NetworkBrokerLookup;
+use air_quality_monitor::home_assistant::HomeAssistantFacade;
+use air_quality_monitor::telemetry::DeviceTelemetry;
+use air_quality_monitor::web::{WebServerConfig, WebServerFacade, WEB_SERVER_PORT};
+use air_quality_monitor::provisioning::{ProvisioningPortal, PROVISIONING_SSID_PREFIX};
+use air_quality_monitor::sensor::{
+    run_sensor, ClimateReading, GasIndexReading, Measurement, MeasurementChannel, ParticulateReading,
+    Sensor, SensorError, SharedReadings, SharedSensorHealth, SENSOR_TIMEOUT,
+};
+use air_quality_monitor::console::{Command, ConsoleError, LineBuffer, LineStatus, CONSOLE_HELP, CONSOLE_LINE_SIZE};
 
 #[panic_handler]
 fn panic(pi: &core::panic::PanicInfo) -> ! {
@@ -45,8 +70,45 @@ extern crate alloc;
 esp_bootloader_esp_idf::esp_app_desc!();
 
 static WIFI_INIT: StaticCell<esp_wifi::EspWifiController> = StaticCell::new();
-static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+/// DHCP, DNS, mDNS, MQTT and the web server.
+const NET_STACK_SOCKETS: usize = 8;
+static RESOURCES: StaticCell<StackResources<NET_STACK_SOCKETS>> = StaticCell::new();
 static NET_STACK: StaticCell<Stack<'static>> = StaticCell::new();
+static MQTT_FACADE: StaticCell<MqttFacade> = StaticCell::new();
+static HOME_ASSISTANT: StaticCell<HomeAssistantFacade> = StaticCell::new();
+static WEB_SERVER: StaticCell<WebServerFacade> = StaticCell::new();
+static BOOT_RESET_REASON: StaticCell<String<32>> = StaticCell::new();
+static DEVICE_CONFIG: StaticCell<DeviceConfig> = StaticCell::new();
+static ACCESS_POINT_RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+/// Connection attempts at boot before falling back to the provisioning portal.
+const WIFI_CONNECT_ATTEMPTS: u32 = 3;
+const MDNS_MODEL: &str = "air-quality-monitor";
+/// How long to collect broker announcements before picking one.
+const MDNS_BROWSE_WINDOW: Duration = Duration::from_secs(3);
+const BROKER_DISCOVERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+static MEASUREMENTS: MeasurementChannel = MeasurementChannel::new();
+/// Latest readings, updated by the aggregator loop in `main`.
+static READINGS: SharedReadings = SharedReadings::new();
+static SCD41_HEALTH: SharedSensorHealth = SharedSensorHealth::new("scd41", "SCD41");
+static SGP41_HEALTH: SharedSensorHealth = SharedSensorHealth::new("sgp41", "SGP41");
+static PMS5003_HEALTH: SharedSensorHealth = SharedSensorHealth::new("pms5003", "PMS5003");
+static SENSOR_HEALTH: [&SharedSensorHealth; 3] = [&SCD41_HEALTH, &SGP41_HEALTH, &PMS5003_HEALTH];
+
+/// Number of PMS5003 reads between two state publishes. Changed through the `interval` command.
+static PUBLISH_EVERY: AtomicU32 = AtomicU32::new(5);
+/// Time between two PMS5003 reads. The state is published on a timer derived from
+/// it, so diagnostics still go out when the PMS5003 stops reporting.
+const PMS5003_READ_INTERVAL: Duration = Duration::from_secs(1);
+/// Extra age allowed for a reading on top of the publish interval, so that sensors
+/// sampling slower than the state is published are not reported as missing.
+const READING_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Reference CO2 concentration (ppm) requested through the `recalibrate` command.
+static RECALIBRATION_REQUEST: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+const DEFAULT_RECALIBRATION_PPM: u16 = 420;
+/// Raised when Home Assistant publishes its birth message and discovery must be re-sent.
+static HOME_ASSISTANT_ONLINE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
@@ -59,6 +121,20 @@ async fn main(spawner: Spawner) {
 
     esp_alloc::heap_allocator!(size: 64 * 1024);
 
+    let mut boot_reset_reason: String<32> = String::new();
+    let _ = match reset_reason(Cpu::ProCpu) {
+        Some(reason) => write!(&mut boot_reset_reason, "{:?}", reason),
+        None => write!(&mut boot_reset_reason, "Unknown"),
+    };
+    info!("Reset reason: {}", boot_reset_reason);
+    let boot_reset_reason: &'static str = BOOT_RESET_REASON.init(boot_reset_reason).as_str();
+
+    let device_config: &'static DeviceConfig = DEVICE_CONFIG.init(
+        with_config_store(|store| store.load_or_default()).unwrap_or_else(|e| {
+            error!("Failed to open configuration partition: {:?}. Using compile-time defaults", e);
+            DeviceConfig::from_env()
+        }));
+
     let timer0 = TimerGroup::new(peripherals.TIMG1);
     esp_hal_embassy::init(timer0.timer0);
 
@@ -70,29 +146,71 @@ async fn main(spawner: Spawner) {
         WIFI_INIT.init(esp_wifi::init(timer1.timer0, rng).expect("Failed to initialize WIFI/BLE controller"));
     let (mut _wifi_controller, _interfaces) = esp_wifi::wifi::new(wifi_init, peripherals.WIFI)
         .expect("Failed to initialize WIFI controller");
-    let stack_resources= RESOURCES.init(StackResources::<5>::new());
+    let stack_resources= RESOURCES.init(StackResources::<NET_STACK_SOCKETS>::new());
     let (mut wifi_facade, stack_tmp, _runner) = WiFiFacade::new(
-        WiFiFacadeConfig::from_env(),
+        device_config.wifi_config(),
         _wifi_controller, 
         _interfaces,
         stack_resources);
     let stack = NET_STACK.init(stack_tmp);
 
-    let mdns = MdnsFacade::new();
+    let broker_lookup = NetworkBrokerLookup::new(stack, UnicastDnsFacade::new(rng), MDNS_BROWSE_WINDOW);
 
     info!("Wifi and MQTT facades initialized. Connecting to Wifi..");
-    wifi_facade.connect().await.expect("Failed to connect to WiFi");
+    if !connect_wifi(&mut wifi_facade, device_config).await {
+        provision(spawner, wifi_facade, device_config).await;
+    }
     spawner.spawn(net_task(_runner)).unwrap();
-    
-    info!("Wifi connected! Fetching broker using mDNS...");
-    let (ip, port) = mdns.query_service(env!("MQTT_SERVICE"), stack).await;
-    info!("Got IP: {} and Port: {}", ip, port);
-
-    let mut mqtt_facade: MqttFacade = MqttFacade::new(MqttFacadeConfig::new(ip, port, "MyDevice"));
-    let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(HomeAssistantFacadeConfig::new_from_env());
+    let wifi_link = wifi_facade.link_monitor();
+    spawner.spawn(wifi_task(wifi_facade)).unwrap();
+    info!("Wifi connected!");
+
+    info!("Starting web server");
+    let web_server = WEB_SERVER.init(WebServerFacade::new(
+        WebServerConfig::new(
+            device_config.device_id.as_str(),
+            device_config.device_name.as_str(),
+            boot_reset_reason),
+        &READINGS,
+        &SENSOR_HEALTH,
+        wifi_link,
+        MqttPublisher));
+    spawner.spawn(web_task(web_server, *stack)).unwrap();
+
+    info!("Starting mDNS responder");
+    let device_id = device_config.device_id.as_str();
+    let mdns_config = MdnsResponderConfig::new(device_id, device_id)
+        .with_service(MdnsService::new("_http._tcp", WEB_SERVER_PORT)
+            .with_txt("path", "/"))
+        .with_service(MdnsService::new("_airquality._tcp", WEB_SERVER_PORT)
+            .with_txt("model", MDNS_MODEL)
+            .with_txt("fw", env!("CARGO_PKG_VERSION"))
+            .with_txt("id", device_id));
+    spawner.spawn(mdns_responder_task(stack, mdns_config)).unwrap();
+
+    info!("Looking for brokers..");
+    let brokers = discover_brokers(device_config, broker_lookup).await;
+
+    let home_assistant: &'static HomeAssistantFacade =
+        HOME_ASSISTANT.init(HomeAssistantFacade::new(device_config.home_assistant_config()));
+    let mut mqtt_config = device_config.mqtt_config(brokers[0].clone(), home_assistant.config().discovery_prefix())
+        .with_availability(home_assistant.get_availability().expect("Failed to build availability topic"));
+    for fallback_broker in brokers.iter().skip(1) {
+        mqtt_config = mqtt_config.with_fallback_broker(fallback_broker.clone());
+    }
+    let mqtt_facade = MQTT_FACADE.init(MqttFacade::new(mqtt_config));
+    mqtt_facade.subscribe(&home_assistant.get_command_topic("interval").unwrap(), on_interval_command)
+        .expect("Failed to subscribe to interval command");
+    mqtt_facade.subscribe(&home_assistant.get_command_topic("recalibrate").unwrap(), on_recalibrate_command)
+        .expect("Failed to subscribe to recalibrate command");
+    mqtt_facade.subscribe(&home_assistant.get_status_topic().unwrap(), on_home_assistant_status)
+        .expect("Failed to subscribe to Home Assistant status");
+    let mqtt_publisher = mqtt_facade.publisher();
+    spawner.spawn(mqtt_task(mqtt_facade, stack)).unwrap();
+    spawner.spawn(broker_monitor_task(device_config, stack, broker_lookup, mqtt_publisher, brokers)).unwrap();
 
-    info!("IP Fetched! Sending MQTT Message..");
-    mqtt_facade.send_message(stack, home_assistant.get_device_discovery_mqtt_message()).await;
+    info!("IP Fetched! Sending discovery..");
+    spawner.spawn(discovery_task(home_assistant, mqtt_publisher)).unwrap();
 
 
     info!("Configuring Sensors");
@@ -104,111 +222,602 @@ async fn main(spawner: Spawner) {
     let scd_41_i2c = I2c::new(peripherals.I2C0,i2c_config).unwrap();
     let scd_41_i2c_with_pins = scd_41_i2c
         .with_scl(peripherals.GPIO18)
-        .with_sda(peripherals.GPIO19);
-
-    let delay: Delay = Delay::new();
-    let mut scd41_sensor = Scd4x::new(scd_41_i2c_with_pins, delay);
+        .with_sda(peripherals.GPIO19)
+        .into_async();
 
-    scd41_sensor.wake_up();
-    scd41_sensor.stop_periodic_measurement().unwrap();
-    scd41_sensor.reinit().unwrap();
-    scd41_sensor.start_periodic_measurement().unwrap();
+    let scd41_sensor = Scd41Sensor(Scd41::new(scd_41_i2c_with_pins));
+    spawner.spawn(scd41_task(scd41_sensor)).unwrap();
 
 
     info!("Configuring SGP41 Sensor");
     let sgp41_i2c = I2c::new(peripherals.I2C1,i2c_config).unwrap();
     let sgp41_i2c_with_pins = sgp41_i2c
         .with_scl(peripherals.GPIO22)
-        .with_sda(peripherals.GPIO23);
-    let mut sgp41_sensor = Sgp41::new(sgp41_i2c_with_pins, 0x59, delay);
-    info!("SGP41: Starting conditioning...");
-    for i in 0..10 {
-        if let Ok(voc_raw) = sgp41_sensor.execute_conditioning() {
-            info!("SGP41: Conditioning step {}: VOC raw = {}", i + 1, voc_raw);
-        } else {
-            info!("SGP41: Conditioning failed at step {}", i + 1);
-        }
-        Timer::after(Duration::from_secs(1)).await;
-    }
+        .with_sda(peripherals.GPIO23)
+        .into_async();
+    let sgp41_sensor = Sgp41Sensor(Sgp41::new(sgp41_i2c_with_pins));
+    spawner.spawn(sgp41_task(sgp41_sensor)).unwrap();
 
 
     info!("Configuring PMS5003 Sensor");
     let config = esp_hal::uart::Config::default().with_baudrate(9600);
-    let uart = esp_hal::uart::Uart::new(peripherals.UART2, config).unwrap()
+    let uart = Uart::new(peripherals.UART2, config).unwrap()
         .with_rx(peripherals.GPIO17)
-        .with_tx(peripherals.GPIO16);
-    let mut pms5003_sensor = PmsX003Sensor::new(uart);
-    pms5003_sensor.wake().unwrap();
+        .with_tx(peripherals.GPIO16)
+        .into_async();
+    let pms5003_sensor = Pms5003Sensor(Pms5003::new(uart));
+    spawner.spawn(pms5003_task(pms5003_sensor)).unwrap();
+
+
+    info!("Starting serial console");
+    let console_config = esp_hal::uart::Config::default().with_baudrate(115200);
+    let console_uart = Uart::new(peripherals.UART0, console_config).unwrap()
+        .with_rx(peripherals.GPIO3)
+        .with_tx(peripherals.GPIO1)
+        .into_async();
+    spawner.spawn(console_task(console_uart, ConsoleContext {
+        device_config,
+        stack,
+        wifi_link,
+        mqtt_publisher,
+    })).unwrap();
+
+    let mut next_publish = Instant::now() + publish_interval();
+    let mut next_telemetry = Instant::now();
+
+    loop {
+        match select3(MEASUREMENTS.receive(), Timer::at(next_publish), Timer::at(next_telemetry)).await {
+            Either3::First(measurement) => READINGS.update(measurement, Instant::now()),
+            Either3::Second(_) => {
+                next_publish = Instant::now() + publish_interval();
+
+                READINGS.expire(Instant::now(), publish_interval() + READING_GRACE_PERIOD);
+                let readings = READINGS.get();
+                if readings.is_empty() {
+                    info!("No sensor has reported recently, skipping state publish");
+                } else {
+                    match home_assistant.get_state_mqtt_message(&readings) {
+                        Ok(state_message) => mqtt_publisher.send_message(state_message).await,
+                        Err(e) => error!("Failed to build state message: {:?}", e),
+                    }
+                }
+                match home_assistant.get_diagnostics_mqtt_message(&SENSOR_HEALTH, Instant::now()) {
+                    Ok(diagnostics_message) => mqtt_publisher.send_message(diagnostics_message).await,
+                    Err(e) => error!("Failed to build diagnostics message: {:?}", e),
+                }
+            }
+            Either3::Third(_) => {
+                next_telemetry = Instant::now() + TELEMETRY_INTERVAL;
+
+                let telemetry = DeviceTelemetry::collect(wifi_link.rssi(), boot_reset_reason);
+                match home_assistant.get_telemetry_mqtt_message(&telemetry) {
+                    Ok(telemetry_message) => mqtt_publisher.send_message(telemetry_message).await,
+                    Err(e) => error!("Failed to build telemetry message: {:?}", e),
+                }
+            }
+        }
+    }
+
+    // for inspiration have a look at the examples at https://github.com/esp-rs/esp-hal/tree/esp-hal-v1.0.0-rc.0/examples/src/bin
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn net_task(mut runner: embassy_net::Runner<'static, esp_wifi::wifi::WifiDevice<'static>>) -> ! {
+    runner.run().await
+}
+
+/// Brokers from `lookup_brokers`, retrying until some broker is known.
+async fn discover_brokers(
+    device_config: &'static DeviceConfig,
+    mut broker_lookup: NetworkBrokerLookup,
+) -> Vec<MqttBroker, MQTT_MAX_BROKERS> {
+    loop {
+        let brokers = lookup_brokers(device_config, &mut broker_lookup).await;
+        if !brokers.is_empty() {
+            return brokers;
+        }
+
+        info!("No broker available. Retrying..");
+        Timer::after(BROKER_DISCOVERY_RETRY_DELAY).await;
+    }
+}
 
-    let mut loop_counter = 0;
+/// Brokers found as `DeviceConfig::broker_discovery` describes, each with the
+/// options it advertises.
+async fn lookup_brokers(
+    device_config: &'static DeviceConfig,
+    broker_lookup: &mut NetworkBrokerLookup,
+) -> Vec<MqttBroker, MQTT_MAX_BROKERS> {
+    let endpoints = device_config.broker_discovery().discover(broker_lookup).await;
+    endpoints.iter().filter_map(MqttBroker::advertised).take(MQTT_MAX_BROKERS).collect()
+}
 
+/// Follows brokers that change address, e.g. after a new DHCP lease: looks
+/// them up again when MQTT keeps failing to connect, or when a broker not in
+/// use announces itself, and hands the new list over to the MQTT task.
+#[embassy_executor::task]
+async fn broker_monitor_task(
+    device_config: &'static DeviceConfig,
+    stack: &'static Stack<'static>,
+    mut broker_lookup: NetworkBrokerLookup,
+    mqtt_publisher: MqttPublisher,
+    mut brokers: Vec<MqttBroker, MQTT_MAX_BROKERS>,
+) -> ! {
+    let mdns = MdnsFacade::new();
+    let service = device_config.mqtt_service.as_str();
     loop {
-        info!("Reading from PMS5003");
-        let pms5003_data = match pms5003_sensor.read() {
-            Ok(frame) => {
-                info!("✓ Successfully read sensor data:");
-                info!("  PM1.0:  {} μg/m³", frame.pm1_0);
-                info!("  PM2.5:  {} μg/m³", frame.pm2_5);
-                info!("  PM10:   {} μg/m³", frame.pm10);
-                info!("  PM1.0 (atmospheric): {} μg/m³", frame.pm1_0_atm);
-                info!("  PM2.5 (atmospheric): {} μg/m³", frame.pm2_5_atm);
-                info!("  PM10  (atmospheric): {} μg/m³", frame.pm10_atm);
-                info!("  Particles > 0.3μm: {} per 0.1L", frame.beyond_0_3);
-                info!("  Particles > 0.5μm: {} per 0.1L", frame.beyond_0_5);
-                info!("  Particles > 1.0μm: {} per 0.1L", frame.beyond_1_0);
-                info!("  Particles > 2.5μm: {} per 0.1L", frame.beyond_2_5);
-                info!("  Particles > 5.0μm: {} per 0.1L", frame.beyond_5_0);
-                info!("  Particles > 10μm:  {} per 0.1L", frame.beyond_10_0);
-
-                frame
+        match select(mqtt_publisher.wait_for_rediscovery(), mdns.wait_for_announcement(service, stack)).await {
+            Either::First(()) => info!("Brokers unreachable, looking them up again.."),
+            Either::Second(announced) => {
+                if announced.iter().all(|endpoint| brokers.iter().any(|broker| broker.address == endpoint.socket_addr())) {
+                    continue;
+                }
+                info!("New broker announced, looking them up again..");
             }
+        }
+
+        let found = lookup_brokers(device_config, &mut broker_lookup).await;
+        if found.is_empty() {
+            error!("No broker found while looking them up again");
+            continue;
+        }
+        brokers = found;
+        mqtt_publisher.update_brokers(brokers.clone());
+    }
+}
+
+/// Answers `<device_id>.local` and the advertised services.
+#[embassy_executor::task]
+async fn mdns_responder_task(stack: &'static Stack<'static>, config: MdnsResponderConfig) -> ! {
+    MdnsFacade::new().respond(stack, &config).await
+}
+
+#[embassy_executor::task]
+async fn web_task(web_server: &'static mut WebServerFacade, stack: Stack<'static>) -> ! {
+    web_server.run(stack).await
+}
+
+#[embassy_executor::task]
+async fn wifi_task(mut wifi_facade: WiFiFacade<'static>) -> ! {
+    wifi_facade.supervise().await
+}
+
+async fn connect_wifi(wifi_facade: &mut WiFiFacade<'static>, device_config: &DeviceConfig) -> bool {
+    if device_config.wifi_networks.is_empty() {
+        info!("No Wi-Fi network configured");
+        return false;
+    }
+
+    for attempt in 1..=WIFI_CONNECT_ATTEMPTS {
+        match wifi_facade.connect().await {
+            Ok(_) => return true,
+            Err(e) => error!("Wi-Fi connection attempt {}/{} failed: {:?}", attempt, WIFI_CONNECT_ATTEMPTS, e),
+        }
+    }
+    false
+}
+
+/// Starts the access point and captive portal, then saves the submitted
+/// configuration and reboots into it.
+async fn provision(spawner: Spawner, mut wifi_facade: WiFiFacade<'static>, device_config: &'static DeviceConfig) -> ! {
+    let mut ssid: String<32> = String::new();
+    let _ = write!(&mut ssid, "{}{}", PROVISIONING_SSID_PREFIX, device_config.device_id);
+    info!("Starting provisioning access point {:?}", ssid);
+
+    let (access_point_stack, access_point_runner) = wifi_facade
+        .start_access_point(&ssid, ACCESS_POINT_RESOURCES.init(StackResources::<5>::new()))
+        .expect("Failed to start provisioning access point");
+    spawner.spawn(net_task(access_point_runner)).unwrap();
+
+    let new_config = ProvisioningPortal::new(device_config).run(access_point_stack).await;
+    match with_config_store(|store| store.save(&new_config)).and_then(|saved| saved) {
+        Ok(_) => info!("Configuration saved. Rebooting.."),
+        Err(e) => error!("Failed to save configuration: {:?}. Rebooting..", e),
+    }
+    // Let the confirmation page reach the browser.
+    Timer::after_secs(1).await;
+    software_reset()
+}
+
+/// Runs `f` with the configuration record stored in the `config` data partition (see `partitions.csv`).
+fn with_config_store<R>(
+    f: impl FnOnce(&mut ConfigStore<FlashRegion<'_, FlashStorage>>) -> R,
+) -> Result<R, ConfigError> {
+    let mut flash = FlashStorage::new();
+    let mut partition_table_buffer = [0_u8; PARTITION_TABLE_MAX_LEN];
+    let partition_table = partitions::read_partition_table(&mut flash, &mut partition_table_buffer)
+        .map_err(|e| {
+            error!("Failed to read partition table: {:?}", e);
+            ConfigError::Storage
+        })?;
+    let partition = partition_table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Undefined))
+        .map_err(|e| {
+            error!("Failed to look up configuration partition: {:?}", e);
+            ConfigError::Storage
+        })?
+        .ok_or(ConfigError::NotFound)?;
+
+    let mut store = ConfigStore::new(partition.as_embedded_storage(&mut flash));
+    Ok(f(&mut store))
+}
+
+const CONSOLE_OUTPUT_SIZE: usize = 1024;
+const CONSOLE_PROMPT: &[u8] = b"> ";
+
+/// Everything the console commands report on.
+struct ConsoleContext {
+    device_config: &'static DeviceConfig,
+    stack: &'static Stack<'static>,
+    wifi_link: WiFiLinkMonitor,
+    mqtt_publisher: MqttPublisher,
+}
+
+/// Line-based shell on the serial console. `config set` edits a copy of the
+/// configuration, which `config save` persists for the next boot.
+#[embassy_executor::task]
+async fn console_task(uart: Uart<'static, Async>, context: ConsoleContext) -> ! {
+    let (mut rx, mut tx) = uart.split();
+    let mut line: LineBuffer<CONSOLE_LINE_SIZE> = LineBuffer::new();
+    let mut pending_config = context.device_config.clone();
+    let mut output: String<CONSOLE_OUTPUT_SIZE> = String::new();
+    let mut byte = [0_u8; 1];
+    let mut previous_byte = 0_u8;
+
+    let _ = tx.write_all(CONSOLE_PROMPT).await;
+    loop {
+        match rx.read_async(&mut byte).await {
+            Ok(1) => {}
+            Ok(_) => continue,
             Err(e) => {
-                error!("✗ Failed to read sensor: {:?}", e);
+                error!("Console: Read failed: {:?}", e);
                 continue;
             }
-        };
+        }
+        // Treat CRLF as a single line ending.
+        let is_crlf = previous_byte == b'\r' && byte[0] == b'\n';
+        previous_byte = byte[0];
+        if is_crlf {
+            continue;
+        }
 
-        if loop_counter > 4 {
-            info!("Reading from SCD41");
-            let scd41_data: scd4x::types::SensorData = match scd41_sensor.measurement() {
-                Ok(data) => data,
-                Err(e) => {
-                    info!("Error reading SCP41 sensor: {:?}", e);
-                    Timer::after(Duration::from_secs(1)).await;
-                    continue;
+        match line.push(byte[0]) {
+            LineStatus::Ignored => {}
+            LineStatus::Appended => {
+                let _ = tx.write_all(&byte).await;
+            }
+            LineStatus::Erased => {
+                let _ = tx.write_all(b"\x08 \x08").await;
+            }
+            LineStatus::Complete => {
+                output.clear();
+                let reboot = match line.line().and_then(Command::parse) {
+                    Ok(command) => run_console_command(command, &mut pending_config, &context, &mut output).await,
+                    Err(ConsoleError::EmptyLine) => false,
+                    Err(e) => {
+                        let _ = write!(&mut output, "Error: {:?}. Type `help` for the commands\r\n", e);
+                        false
+                    }
+                };
+                line.clear();
+
+                let _ = tx.write_all(b"\r\n").await;
+                let _ = tx.write_all(output.as_bytes()).await;
+                if reboot {
+                    let _ = tx.flush_async().await;
+                    Timer::after_millis(100).await;
+                    software_reset();
                 }
-            };
+                let _ = tx.write_all(CONSOLE_PROMPT).await;
+            }
+        }
+    }
+}
 
-            info!("Reading from SGP41");
-            let (sgp_41_voc, sgp_41_nox) = match sgp41_sensor.measure_indices() {
-                Ok((voc, nox)) => (voc, nox),
+/// Runs `command`, writing its output to `output`. Returns whether the device
+/// must reboot once the output is sent.
+async fn run_console_command(
+    command: Command<'_>,
+    pending_config: &mut DeviceConfig,
+    context: &ConsoleContext,
+    output: &mut String<CONSOLE_OUTPUT_SIZE>,
+) -> bool {
+    let _ = match command {
+        Command::Help => write!(output, "{}", CONSOLE_HELP),
+        Command::ConfigGet(Some(key)) => match pending_config.get(key) {
+            Ok(value) => write_config_value(output, key, value),
+            Err(e) => write!(output, "Error: {:?}\r\n", e),
+        },
+        Command::ConfigGet(None) => CONFIG_KEYS.iter().try_for_each(|key| {
+            write_config_value(output, key, pending_config.get(key).unwrap_or(""))
+        }),
+        Command::ConfigSet { key, value } => match pending_config.set(key, value) {
+            Ok(_) => write!(output, "OK. Run `config save` and `reboot` to apply\r\n"),
+            Err(e) => write!(output, "Error: {:?}\r\n", e),
+        },
+        Command::ConfigSave => match with_config_store(|store| store.save(pending_config)).and_then(|saved| saved) {
+            Ok(_) => write!(output, "Saved. Run `reboot` to apply\r\n"),
+            Err(e) => write!(output, "Error: {:?}\r\n", e),
+        },
+        Command::WifiScan => {
+            let access_points = context.wifi_link.scan().await;
+            access_points.iter().try_for_each(|access_point| write!(output,
+                "{:<32} {:>4} dBm  channel {}\r\n", access_point.ssid, access_point.rssi, access_point.channel))
+        }
+        Command::WifiStatus => write!(output, "State: {:?}\r\nRSSI: {:?} dBm\r\nAddress: {:?}\r\nIPv6 address: {:?}\r\n",
+            context.wifi_link.state(),
+            context.wifi_link.rssi(),
+            context.stack.config_v4().map(|config| config.address),
+            context.stack.config_v6().map(|config| config.address)),
+        Command::MqttStatus => write!(output, "Broker: {:?}\r\nConnected: {}\r\n",
+            context.mqtt_publisher.broker(), context.mqtt_publisher.is_connected()),
+        Command::SensorRead(id) => write_sensor(output, id),
+        Command::Reboot => {
+            let _ = write!(output, "Rebooting..\r\n");
+            return true;
+        }
+        Command::FactoryReset => {
+            return match with_config_store(|store| store.erase()).and_then(|erased| erased) {
+                Ok(_) => {
+                    let _ = write!(output, "Configuration erased. Rebooting..\r\n");
+                    true
+                }
                 Err(e) => {
-                    info!("Error reading SGP41 sensor: {:?}", e);
-                    Timer::after(Duration::from_secs(1)).await;
-                    continue;
+                    let _ = write!(output, "Error: {:?}\r\n", e);
+                    false
                 }
             };
+        }
+    };
+    false
+}
+
+fn write_config_value<W: Write>(output: &mut W, key: &str, value: &str) -> core::fmt::Result {
+    let value = if key == "wifi.password" && !value.is_empty() { "********" } else { value };
+    write!(output, "{} = {:?}\r\n", key, value)
+}
+
+fn write_sensor<W: Write>(output: &mut W, id: &str) -> core::fmt::Result {
+    let Some(health) = SENSOR_HEALTH.iter().find(|health| health.id() == id) else {
+        return write!(output, "Unknown sensor {:?}. Known sensors: scd41, sgp41, pms5003\r\n", id);
+    };
+
+    let readings = READINGS.get();
+    match id {
+        "scd41" => write!(output, "Reading: {:?}\r\n", readings.climate)?,
+        "sgp41" => write!(output, "Reading: {:?}\r\n", readings.gas_index)?,
+        _ => write!(output, "Reading: {:?}\r\n", readings.particulate)?,
+    }
+
+    let health = health.get();
+    write!(output, "Warmed up: {}\r\nConsecutive failures: {}\r\nTotal failures: {}\r\nLast error: {:?}\r\n",
+        health.warmed_up, health.consecutive_failures, health.total_failures, health.last_error)?;
+    match health.last_success_at {
+        Some(last_success_at) => write!(output, "Last success: {} s ago\r\n", last_success_at.elapsed().as_secs()),
+        None => write!(output, "Last success: never\r\n"),
+    }
+}
+
+fn publish_interval() -> Duration {
+    PMS5003_READ_INTERVAL * PUBLISH_EVERY.load(Ordering::Relaxed)
+}
 
-            mqtt_facade.send_message(stack, home_assistant.get_state_mqtt_message(
-                scd41_data.co2,
-                scd41_data.humidity,
-                scd41_data.temperature,
-                sgp_41_voc,
-                sgp_41_nox,
-                pms5003_data.pm1_0_atm,
-                pms5003_data.pm2_5_atm,
-                pms5003_data.pm10_atm
-            )).await;
-            loop_counter = 0;
+fn on_interval_command(_topic: &str, payload: &[u8]) {
+    match core::str::from_utf8(payload).ok().and_then(|value| value.trim().parse::<u32>().ok()) {
+        Some(publish_every) if publish_every > 0 => {
+            info!("Publishing state every {} reads", publish_every);
+            PUBLISH_EVERY.store(publish_every, Ordering::Relaxed);
         }
+        _ => error!("Invalid interval command payload: {:?}", payload),
+    }
+}
 
-        loop_counter += 1;
+fn on_recalibrate_command(_topic: &str, payload: &[u8]) {
+    let target_ppm = if payload.is_empty() {
+        Some(DEFAULT_RECALIBRATION_PPM)
+    } else {
+        core::str::from_utf8(payload).ok().and_then(|value| value.trim().parse::<u16>().ok())
+    };
+    match target_ppm {
+        Some(target_ppm) => RECALIBRATION_REQUEST.signal(target_ppm),
+        None => error!("Invalid recalibrate command payload: {:?}", payload),
     }
+}
 
-    // for inspiration have a look at the examples at https://github.com/esp-rs/esp-hal/tree/esp-hal-v1.0.0-rc.0/examples/src/bin
+struct Scd41Sensor(Scd41<I2c<'static, Async>>);
+
+impl Sensor for Scd41Sensor {
+    fn name(&self) -> &'static str {
+        "SCD41"
+    }
+
+    fn read_interval(&self) -> Duration {
+        // Periodic measurement mode produces a new sample every 5 seconds
+        Duration::from_secs(5)
+    }
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        self.0.wake_up().await;
+        let result = match self.0.stop_periodic_measurement().await {
+            Ok(_) => match self.0.reinit().await {
+                Ok(_) => self.0.start_periodic_measurement().await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        result.map_err(|e| {
+            error!("SCD41: Failed to start periodic measurement: {:?}", e);
+            SensorError::InitFailed
+        })
+    }
+
+    async fn read(&mut self) -> Result<Measurement, SensorError> {
+        if let Some(target_ppm) = RECALIBRATION_REQUEST.try_take() {
+            self.recalibrate(target_ppm).await;
+        }
+
+        let data = self.0.measurement().await.map_err(|e| {
+            error!("SCD41: Error reading sensor: {:?}", e);
+            SensorError::ReadFailed
+        })?;
+        info!("SCD41: CO2 {} ppm, {} °C, {} %", data.co2, data.temperature, data.humidity);
+
+        Ok(Measurement::Climate(ClimateReading {
+            co2: data.co2,
+            temperature: data.temperature,
+            humidity: data.humidity,
+        }))
+    }
+}
+
+impl Scd41Sensor {
+    async fn recalibrate(&mut self, target_ppm: u16) {
+        info!("SCD41: Recalibrating to {} ppm", target_ppm);
+        let result = match self.0.stop_periodic_measurement().await {
+            Ok(_) => self.0.forced_recalibration(target_ppm).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(correction) => info!("SCD41: Recalibrated, correction: {}", correction),
+            Err(e) => error!("SCD41: Recalibration failed: {:?}", e),
+        }
+        if let Err(e) = self.0.start_periodic_measurement().await {
+            error!("SCD41: Failed to restart periodic measurement: {:?}", e);
+        }
+    }
+}
+
+struct Sgp41Sensor(Sgp41<I2c<'static, Async>>);
+
+impl Sensor for Sgp41Sensor {
+    fn name(&self) -> &'static str {
+        "SGP41"
+    }
+
+    fn read_interval(&self) -> Duration {
+        // The VOC/NOx index algorithm expects one sample per second
+        Duration::from_secs(1)
+    }
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    async fn warm_up(&mut self) -> Result<(), SensorError> {
+        let mut conditioned = false;
+        for i in 0..10 {
+            match with_timeout(SENSOR_TIMEOUT, self.0.execute_conditioning()).await {
+                Ok(Ok(voc_raw)) => {
+                    info!("SGP41: Conditioning step {}: VOC raw = {}", i + 1, voc_raw);
+                    conditioned = true;
+                }
+                Ok(Err(e)) => info!("SGP41: Conditioning failed at step {}: {:?}", i + 1, e),
+                Err(_) => info!("SGP41: Conditioning timed out at step {}", i + 1),
+            }
+            Timer::after(Duration::from_secs(1)).await;
+        }
+
+        if conditioned {
+            Ok(())
+        } else {
+            Err(SensorError::WarmUpFailed)
+        }
+    }
+
+    async fn read(&mut self) -> Result<Measurement, SensorError> {
+        let (voc_index, nox_index) = self.0.measure_indices().await.map_err(|e| {
+            error!("SGP41: Error reading sensor: {:?}", e);
+            SensorError::ReadFailed
+        })?;
+
+        Ok(Measurement::GasIndex(GasIndexReading { voc_index, nox_index }))
+    }
+}
+
+struct Pms5003Sensor(Pms5003<Uart<'static, Async>>);
+
+impl Sensor for Pms5003Sensor {
+    fn name(&self) -> &'static str {
+        "PMS5003"
+    }
+
+    fn read_interval(&self) -> Duration {
+        PMS5003_READ_INTERVAL
+    }
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        self.0.wake().await.map_err(|e| {
+            error!("PMS5003: Failed to wake up sensor: {:?}", e);
+            SensorError::InitFailed
+        })
+    }
+
+    async fn read(&mut self) -> Result<Measurement, SensorError> {
+        let frame = self.0.read().await.map_err(|e| {
+            error!("PMS5003: Failed to read sensor: {:?}", e);
+            SensorError::ReadFailed
+        })?;
+        info!("PMS5003: PM1.0 {} μg/m³, PM2.5 {} μg/m³, PM10 {} μg/m³ (atmospheric)",
+            frame.pm1_0_atm, frame.pm2_5_atm, frame.pm10_atm);
+
+        Ok(Measurement::Particulate(ParticulateReading {
+            pm1_0_atm: frame.pm1_0_atm,
+            pm2_5_atm: frame.pm2_5_atm,
+            pm10_0_atm: frame.pm10_atm,
+            beyond_0_3: frame.beyond_0_3,
+            beyond_0_5: frame.beyond_0_5,
+            beyond_1_0: frame.beyond_1_0,
+            beyond_2_5: frame.beyond_2_5,
+            beyond_5_0: frame.beyond_5_0,
+            beyond_10_0: frame.beyond_10_0,
+        }))
+    }
 }
 
 #[embassy_executor::task]
-async fn net_task(mut runner: embassy_net::Runner<'static, esp_wifi::wifi::WifiDevice<'static>>) -> ! {
-    runner.run().await
+async fn scd41_task(sensor: Scd41Sensor) -> ! {
+    run_sensor(sensor, &MEASUREMENTS, &SCD41_HEALTH).await
+}
+
+#[embassy_executor::task]
+async fn sgp41_task(sensor: Sgp41Sensor) -> ! {
+    run_sensor(sensor, &MEASUREMENTS, &SGP41_HEALTH).await
+}
+
+#[embassy_executor::task]
+async fn pms5003_task(sensor: Pms5003Sensor) -> ! {
+    run_sensor(sensor, &MEASUREMENTS, &PMS5003_HEALTH).await
+}
+
+fn on_home_assistant_status(_topic: &str, payload: &[u8]) {
+    if HomeAssistantFacade::is_birth_message(payload) {
+        info!("Home Assistant is online, discovery will be re-sent");
+        HOME_ASSISTANT_ONLINE.signal(());
+    }
+}
+
+/// Sends discovery whenever a session is established with a new broker, boot
+/// included, and again with some jitter every time Home Assistant restarts.
+#[embassy_executor::task]
+async fn discovery_task(home_assistant: &'static HomeAssistantFacade, mqtt_publisher: MqttPublisher) -> ! {
+    loop {
+        match select(mqtt_publisher.wait_for_new_broker(), HOME_ASSISTANT_ONLINE.wait()).await {
+            Either::First(()) => info!("Connected to a new broker, sending discovery"),
+            Either::Second(()) => Timer::after(home_assistant.get_discovery_jitter()).await,
+        }
+
+        let topic_prefix = mqtt_publisher.topic_prefix();
+        for index in 0.. {
+            match home_assistant.get_component_discovery_mqtt_message(&SENSOR_HEALTH, &topic_prefix, index) {
+                Ok(Some(discovery_message)) => mqtt_publisher.send_message(discovery_message).await,
+                Ok(None) => break,
+                Err(e) => error!("Failed to build discovery message {}: {:?}", index, e),
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn mqtt_task(mqtt_facade: &'static mut MqttFacade, stack: &'static Stack<'static>) -> ! {
+    mqtt_facade.run(stack).await
 }
\ No newline at end of file
@@ -0,0 +1,49 @@
+use core::net::IpAddr;
+use embassy_net::Stack;
+use embassy_time::Duration;
+use heapless::Vec;
+use log::error;
+
+use crate::discovery::{BrokerLookup, ServiceEndpoint, DISCOVERY_MAX_INSTANCES};
+use crate::dns_sd::UnicastDnsFacade;
+use crate::mdns::MdnsFacade;
+
+/// `BrokerLookup` over the network stack: mDNS browsing for `window`, and
+/// unicast DNS through the servers provided by DHCP.
+#[derive(Clone, Copy)]
+pub struct NetworkBrokerLookup {
+    _stack: &'static Stack<'static>,
+    _unicast_dns: UnicastDnsFacade,
+    _window: Duration,
+}
+
+impl NetworkBrokerLookup {
+    pub const fn new(stack: &'static Stack<'static>, unicast_dns: UnicastDnsFacade, window: Duration) -> Self {
+        Self {
+            _stack: stack,
+            _unicast_dns: unicast_dns,
+            _window: window,
+        }
+    }
+}
+
+impl BrokerLookup for NetworkBrokerLookup {
+    async fn browse_mdns(&mut self, service: &'static str) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+        MdnsFacade::new().browse_service(service, self._stack, self._window).await
+            .inspect_err(|e| error!("NetworkBrokerLookup: mDNS browsing of {:?} failed: {:?}", service, e))
+            .unwrap_or_default()
+    }
+
+    async fn browse_dns_sd(&mut self, service: &str) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+        self._unicast_dns.browse_service(service, self._stack).await
+            .inspect_err(|e| error!("NetworkBrokerLookup: Unicast DNS-SD of {:?} failed: {:?}", service, e))
+            .unwrap_or_default()
+    }
+
+    async fn resolve_host(&mut self, host: &str, port: u16) -> Option<IpAddr> {
+        self._unicast_dns.resolve_host(host, port, self._stack).await
+            .map(|endpoint| endpoint.address)
+            .inspect_err(|e| error!("NetworkBrokerLookup: Resolving {:?} failed: {:?}", host, e))
+            .ok()
+    }
+}
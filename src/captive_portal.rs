@@ -0,0 +1,383 @@
+//! DNS and DHCP answers of the provisioning captive portal, built in place
+//! from the packets of the clients of the access point. Only depends on `core`
+//! and `heapless`, so it can be exercised on the host without a network stack.
+
+use core::net::Ipv4Addr;
+use heapless::Vec;
+
+const DNS_HEADER_SIZE: usize = 12;
+const DNS_ANSWER_TTL_SECS: u32 = 60;
+
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const DHCP_LEASE_SECS: u32 = 3600;
+const DHCP_MAX_LEASES: usize = 8;
+/// Last byte of the first address handed out, the following leases get the next ones.
+const DHCP_FIRST_HOST: u8 = 100;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+
+const DHCP_OPTION_SUBNET_MASK: u8 = 1;
+const DHCP_OPTION_ROUTER: u8 = 3;
+const DHCP_OPTION_DNS_SERVER: u8 = 6;
+const DHCP_OPTION_LEASE_TIME: u8 = 51;
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+const DHCP_OPTION_SERVER_ID: u8 = 54;
+const DHCP_OPTION_PAD: u8 = 0;
+const DHCP_OPTION_END: u8 = 255;
+
+/// Turns the query in `packet[..length]` into a response in place, returning
+/// its length. Only the first question is answered, A queries with `address`.
+pub fn build_dns_response(packet: &mut [u8], length: usize, address: Ipv4Addr) -> Option<usize> {
+    if length < DNS_HEADER_SIZE || packet[2] & 0x80 != 0 {
+        return None;
+    }
+    let question_count = u16::from_be_bytes([packet[4], packet[5]]);
+    if question_count == 0 {
+        return None;
+    }
+
+    let mut position = DNS_HEADER_SIZE;
+    loop {
+        let label_length = *packet[..length].get(position)? as usize;
+        position += 1;
+        if label_length == 0 {
+            break;
+        }
+        if label_length & 0xC0 != 0 {
+            return None;
+        }
+        position += label_length;
+    }
+    let question_end = position + 4;
+    if question_end > length {
+        return None;
+    }
+    let is_a_query = packet[position..position + 4] == [0, 1, 0, 1];
+
+    // Response, recursion desired copied from the query, recursion available.
+    packet[2] = 0x80 | (packet[2] & 0x01);
+    packet[3] = 0x80;
+    packet[4..6].copy_from_slice(&1_u16.to_be_bytes());
+    packet[6..8].copy_from_slice(&(is_a_query as u16).to_be_bytes());
+    packet[8..12].fill(0);
+    if !is_a_query {
+        return Some(question_end);
+    }
+
+    let answer_end = question_end + 16;
+    let answer = packet.get_mut(question_end..answer_end)?;
+    // Name as a pointer to the question, type A, class IN, TTL, address.
+    answer[0..2].copy_from_slice(&[0xC0, DNS_HEADER_SIZE as u8]);
+    answer[2..6].copy_from_slice(&[0, 1, 0, 1]);
+    answer[6..10].copy_from_slice(&DNS_ANSWER_TTL_SECS.to_be_bytes());
+    answer[10..12].copy_from_slice(&4_u16.to_be_bytes());
+    answer[12..16].copy_from_slice(&address.octets());
+    Some(answer_end)
+}
+
+/// Client MAC addresses by lease, the index of a lease deciding its address.
+pub struct DhcpLeases {
+    clients: Vec<[u8; 6], DHCP_MAX_LEASES>,
+    /// Slot handed out the longest ago, reused once every slot is taken.
+    oldest: usize,
+}
+
+impl DhcpLeases {
+    pub fn new() -> Self {
+        Self {
+            clients: Vec::new(),
+            oldest: 0,
+        }
+    }
+
+    /// Index of the lease of `client_mac`, evicting the oldest lease in place
+    /// when the table is full so the other clients keep their addresses.
+    fn lease_index(&mut self, client_mac: [u8; 6]) -> usize {
+        if let Some(index) = self.clients.iter().position(|mac| *mac == client_mac) {
+            return index;
+        }
+        if self.clients.push(client_mac).is_ok() {
+            return self.clients.len() - 1;
+        }
+        let index = self.oldest;
+        self.clients[index] = client_mac;
+        self.oldest = (index + 1) % DHCP_MAX_LEASES;
+        index
+    }
+}
+
+impl Default for DhcpLeases {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turns the DHCPDISCOVER or DHCPREQUEST in `packet[..length]` into an offer
+/// or acknowledgement in place, returning its length. `server_address` is
+/// also given as the router and DNS server.
+pub fn build_dhcp_response(
+    packet: &mut [u8],
+    length: usize,
+    leases: &mut DhcpLeases,
+    server_address: Ipv4Addr,
+) -> Option<usize> {
+    if length < DHCP_OPTIONS_OFFSET || packet[0] != 1 || packet[236..240] != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut position = DHCP_OPTIONS_OFFSET;
+    while position < length {
+        match packet[position] {
+            DHCP_OPTION_PAD => position += 1,
+            DHCP_OPTION_END => break,
+            option => {
+                let option_length = *packet[..length].get(position + 1)? as usize;
+                if option == DHCP_OPTION_MESSAGE_TYPE && option_length == 1 {
+                    message_type = packet[..length].get(position + 2).copied();
+                }
+                position += 2 + option_length;
+            }
+        }
+    }
+    let response_type = match message_type? {
+        DHCP_DISCOVER => DHCP_OFFER,
+        DHCP_REQUEST => DHCP_ACK,
+        _ => return None,
+    };
+
+    let mut client_mac = [0_u8; 6];
+    client_mac.copy_from_slice(&packet[28..34]);
+    let lease_index = leases.lease_index(client_mac);
+    let mut client_address = server_address.octets();
+    client_address[3] = DHCP_FIRST_HOST + lease_index as u8;
+    let server_address = server_address.octets();
+
+    // BOOTREPLY, keeping htype, hlen, xid, flags and chaddr from the request.
+    packet[0] = 2;
+    packet[3] = 0;
+    packet[8..10].fill(0);
+    packet[12..16].fill(0);
+    packet[16..20].copy_from_slice(&client_address);
+    packet[20..24].copy_from_slice(&server_address);
+    packet[24..28].fill(0);
+    packet[44..236].fill(0);
+
+    let mut options: Vec<u8, 64> = Vec::new();
+    let _ = options.extend_from_slice(&[DHCP_OPTION_MESSAGE_TYPE, 1, response_type]);
+    let _ = options.extend_from_slice(&[DHCP_OPTION_SERVER_ID, 4]);
+    let _ = options.extend_from_slice(&server_address);
+    let _ = options.extend_from_slice(&[DHCP_OPTION_LEASE_TIME, 4]);
+    let _ = options.extend_from_slice(&DHCP_LEASE_SECS.to_be_bytes());
+    let _ = options.extend_from_slice(&[DHCP_OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+    let _ = options.extend_from_slice(&[DHCP_OPTION_ROUTER, 4]);
+    let _ = options.extend_from_slice(&server_address);
+    let _ = options.extend_from_slice(&[DHCP_OPTION_DNS_SERVER, 4]);
+    let _ = options.extend_from_slice(&server_address);
+    let _ = options.push(DHCP_OPTION_END);
+
+    let response_end = DHCP_OPTIONS_OFFSET + options.len();
+    packet.get_mut(DHCP_OPTIONS_OFFSET..response_end)?.copy_from_slice(&options);
+    // Some clients drop replies shorter than a minimal BOOTP message.
+    let padded_end = response_end.max(300);
+    packet.get_mut(response_end..padded_end)?.fill(0);
+    Some(padded_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORTAL: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+
+    /// Query for the A record of `connectivitycheck.gstatic.com`, as sent by
+    /// Android when joining a network, with recursion desired.
+    const A_QUERY: &[u8] = &[
+        // header: id 0x5ad1, recursion desired, 1 question
+        0x5a, 0xd1, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // connectivitycheck.gstatic.com
+        0x11, 0x63, 0x6f, 0x6e, 0x6e, 0x65, 0x63, 0x74, 0x69, 0x76, 0x69, 0x74, 0x79, 0x63, 0x68, 0x65,
+        0x63, 0x6b, 0x07, 0x67, 0x73, 0x74, 0x61, 0x74, 0x69, 0x63, 0x03, 0x63, 0x6f, 0x6d, 0x00,
+        // A IN
+        0x00, 0x01, 0x00, 0x01,
+    ];
+
+    fn dns_response(query: &[u8]) -> Option<std::vec::Vec<u8>> {
+        let mut packet = [0u8; 512];
+        packet[..query.len()].copy_from_slice(query);
+        let length = build_dns_response(&mut packet, query.len(), PORTAL)?;
+        Some(packet[..length].to_vec())
+    }
+
+    /// DHCP message of `message_type` from the client with the MAC address
+    /// ending in `mac_suffix`, options padded as some clients do.
+    fn dhcp_request(message_type: u8, mac_suffix: u8) -> std::vec::Vec<u8> {
+        let mut packet = std::vec![0u8; DHCP_OPTIONS_OFFSET];
+        packet[0..4].copy_from_slice(&[1, 1, 6, 0]);
+        packet[4..8].copy_from_slice(&[0x3d, 0x1e, 0x8a, 0x42]);
+        packet[28..34].copy_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, mac_suffix]);
+        packet[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+        packet.extend_from_slice(&[DHCP_OPTION_PAD, DHCP_OPTION_MESSAGE_TYPE, 1, message_type]);
+        packet.extend_from_slice(&[55, 3, DHCP_OPTION_SUBNET_MASK, DHCP_OPTION_ROUTER, DHCP_OPTION_DNS_SERVER]);
+        packet.push(DHCP_OPTION_END);
+        packet
+    }
+
+    fn dhcp_response(request: &[u8], leases: &mut DhcpLeases) -> Option<std::vec::Vec<u8>> {
+        let mut packet = [0u8; 1024];
+        packet[..request.len()].copy_from_slice(request);
+        let length = build_dhcp_response(&mut packet, request.len(), leases, PORTAL)?;
+        Some(packet[..length].to_vec())
+    }
+
+    fn offered_address(response: &[u8]) -> Ipv4Addr {
+        Ipv4Addr::new(response[16], response[17], response[18], response[19])
+    }
+
+    #[test]
+    fn a_queries_resolve_to_the_portal() {
+        let response = dns_response(A_QUERY).unwrap();
+
+        assert_eq!(&response[..12], [0x5a, 0xd1, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(&response[12..A_QUERY.len()], &A_QUERY[12..]);
+        assert_eq!(
+            &response[A_QUERY.len()..],
+            [0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 192, 168, 4, 1],
+        );
+    }
+
+    #[test]
+    fn other_queries_get_no_answer() {
+        let mut query = A_QUERY.to_vec();
+        let type_offset = query.len() - 4;
+        query[type_offset + 1] = 0x1c; // AAAA
+
+        let response = dns_response(&query).unwrap();
+
+        assert_eq!(&response[6..8], [0x00, 0x00]);
+        assert_eq!(response.len(), query.len());
+    }
+
+    #[test]
+    fn malformed_dns_packets_are_ignored() {
+        // Responses, no question and truncated headers
+        let mut response = A_QUERY.to_vec();
+        response[2] |= 0x80;
+        assert_eq!(dns_response(&response), None);
+        let mut no_question = A_QUERY.to_vec();
+        no_question[5] = 0;
+        assert_eq!(dns_response(&no_question), None);
+        assert_eq!(dns_response(&A_QUERY[..11]), None);
+
+        // Name running past the end, missing type and class, compression pointer
+        assert_eq!(dns_response(&A_QUERY[..20]), None);
+        assert_eq!(dns_response(&A_QUERY[..A_QUERY.len() - 2]), None);
+        let mut pointer = A_QUERY[..12].to_vec();
+        pointer.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        assert_eq!(dns_response(&pointer), None);
+    }
+
+    #[test]
+    fn dns_answer_must_fit_in_the_buffer() {
+        let mut packet = A_QUERY.to_vec();
+
+        assert_eq!(build_dns_response(&mut packet, A_QUERY.len(), PORTAL), None);
+    }
+
+    #[test]
+    fn discover_gets_an_offer_and_request_an_ack() {
+        let mut leases = DhcpLeases::new();
+
+        let offer = dhcp_response(&dhcp_request(DHCP_DISCOVER, 1), &mut leases).unwrap();
+        let ack = dhcp_response(&dhcp_request(DHCP_REQUEST, 1), &mut leases).unwrap();
+
+        for (response, message_type) in [(&offer, DHCP_OFFER), (&ack, DHCP_ACK)] {
+            assert_eq!(response.len(), 300);
+            assert_eq!(response[0], 2);
+            assert_eq!(&response[4..8], [0x3d, 0x1e, 0x8a, 0x42]);
+            assert_eq!(offered_address(response), Ipv4Addr::new(192, 168, 4, 100));
+            assert_eq!(&response[20..24], [192, 168, 4, 1]);
+            assert_eq!(&response[28..34], [0x02, 0x00, 0x00, 0x00, 0x00, 1]);
+            assert_eq!(&response[236..240], DHCP_MAGIC_COOKIE);
+            assert_eq!(&response[240..243], [DHCP_OPTION_MESSAGE_TYPE, 1, message_type]);
+            assert_eq!(&response[243..249], [DHCP_OPTION_SERVER_ID, 4, 192, 168, 4, 1]);
+        }
+    }
+
+    #[test]
+    fn malformed_dhcp_packets_are_ignored() {
+        let mut leases = DhcpLeases::new();
+        let request = dhcp_request(DHCP_DISCOVER, 1);
+
+        // Truncated before the options, and in the message type option
+        assert_eq!(dhcp_response(&request[..DHCP_OPTIONS_OFFSET - 1], &mut leases), None);
+        assert_eq!(dhcp_response(&request[..DHCP_OPTIONS_OFFSET + 2], &mut leases), None);
+        assert_eq!(dhcp_response(&request[..DHCP_OPTIONS_OFFSET + 3], &mut leases), None);
+
+        let mut reply = request.clone();
+        reply[0] = 2;
+        assert_eq!(dhcp_response(&reply, &mut leases), None);
+        let mut no_cookie = request.clone();
+        no_cookie[236] = 0;
+        assert_eq!(dhcp_response(&no_cookie, &mut leases), None);
+        // DHCPRELEASE is not answered
+        assert_eq!(dhcp_response(&dhcp_request(7, 1), &mut leases), None);
+        // An option length running past the end
+        let mut overlong = request[..DHCP_OPTIONS_OFFSET].to_vec();
+        overlong.extend_from_slice(&[12, 200, b'a']);
+        assert_eq!(dhcp_response(&overlong, &mut leases), None);
+
+        assert!(leases.clients.is_empty());
+    }
+
+    #[test]
+    fn each_client_keeps_its_address() {
+        let mut leases = DhcpLeases::new();
+
+        let first = dhcp_response(&dhcp_request(DHCP_DISCOVER, 1), &mut leases).unwrap();
+        let second = dhcp_response(&dhcp_request(DHCP_DISCOVER, 2), &mut leases).unwrap();
+        let first_again = dhcp_response(&dhcp_request(DHCP_REQUEST, 1), &mut leases).unwrap();
+
+        assert_eq!(offered_address(&first), Ipv4Addr::new(192, 168, 4, 100));
+        assert_eq!(offered_address(&second), Ipv4Addr::new(192, 168, 4, 101));
+        assert_eq!(offered_address(&first_again), Ipv4Addr::new(192, 168, 4, 100));
+    }
+
+    #[test]
+    fn full_lease_table_evicts_the_oldest_lease_in_place() {
+        let mut leases = DhcpLeases::new();
+        for client in 0..DHCP_MAX_LEASES as u8 {
+            assert_eq!(leases.lease_index([2, 0, 0, 0, 0, client]), client as usize);
+        }
+
+        // The first two clients lose their lease, the others keep their address
+        assert_eq!(leases.lease_index([2, 0, 0, 0, 1, 0]), 0);
+        assert_eq!(leases.lease_index([2, 0, 0, 0, 1, 1]), 1);
+        for client in 2..DHCP_MAX_LEASES as u8 {
+            assert_eq!(leases.lease_index([2, 0, 0, 0, 0, client]), client as usize);
+        }
+        assert_eq!(leases.lease_index([2, 0, 0, 0, 1, 0]), 0);
+
+        // Eviction wraps around once every lease was reused
+        for client in 2..DHCP_MAX_LEASES as u8 {
+            assert_eq!(leases.lease_index([2, 0, 0, 0, 2, client]), client as usize);
+        }
+        assert_eq!(leases.lease_index([2, 0, 0, 0, 3, 0]), 0);
+        assert_eq!(leases.clients.len(), DHCP_MAX_LEASES);
+    }
+
+    #[test]
+    fn addresses_stay_in_the_subnet_when_leases_run_out() {
+        let mut leases = DhcpLeases::new();
+        for client in 0..3 * DHCP_MAX_LEASES as u8 {
+            let response = dhcp_response(&dhcp_request(DHCP_DISCOVER, client), &mut leases).unwrap();
+            let address = offered_address(&response).octets();
+            assert_eq!(address[..3], [192, 168, 4]);
+            assert!((DHCP_FIRST_HOST..DHCP_FIRST_HOST + DHCP_MAX_LEASES as u8).contains(&address[3]));
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use embedded_storage::{ReadStorage, Storage};
+use log::{error, info};
+
+use crate::config_record::{
+    ConfigError, DeviceConfig, CONFIG_HEADER_SIZE, CONFIG_RECORD_SIZE, DEVICE_ID_SIZE, DEVICE_NAME_SIZE,
+    MQTT_BROKER_SIZE, MQTT_DOMAIN_SIZE, MQTT_SERVICE_SIZE, PASSWORD_SIZE, SSID_SIZE,
+};
+use crate::home_assistant::HomeAssistantFacadeConfig;
+use crate::mqtt::{MqttBroker, MqttFacadeConfig};
+use crate::wifi::WiFiFacadeConfig;
+use crate::wifi_networks::WiFiNetwork;
+
+/// Whether every value that is set fits in `capacity` bytes.
+const fn env_fits<const N: usize>(values: [Option<&str>; N], capacity: usize) -> bool {
+    let mut index = 0;
+    while index < N {
+        if let Some(value) = values[index] {
+            if value.len() > capacity {
+                return false;
+            }
+        }
+        index += 1;
+    }
+    true
+}
+
+// The compile-time defaults are checked here rather than truncated or dropped by `from_env`.
+const _: () = {
+    assert!(env_fits([Some(env!("WIFI_SSID")), option_env!("WIFI_SSID_2"), option_env!("WIFI_SSID_3"), option_env!("WIFI_SSID_4")], SSID_SIZE),
+        "WIFI_SSID* must be at most 32 bytes");
+    assert!(env_fits([Some(env!("WIFI_PASSWORD")), option_env!("WIFI_PASSWORD_2"), option_env!("WIFI_PASSWORD_3"), option_env!("WIFI_PASSWORD_4")], PASSWORD_SIZE),
+        "WIFI_PASSWORD* must be at most 64 bytes");
+    assert!(env!("DEVICE_ID").len() <= DEVICE_ID_SIZE, "DEVICE_ID must be at most 32 bytes");
+    assert!(env!("DEVICE_NAME").len() <= DEVICE_NAME_SIZE, "DEVICE_NAME must be at most 64 bytes");
+    assert!(env!("MQTT_SERVICE").len() <= MQTT_SERVICE_SIZE, "MQTT_SERVICE must be at most 64 bytes");
+    assert!(env_fits([option_env!("MQTT_BROKER")], MQTT_BROKER_SIZE), "MQTT_BROKER must be at most 64 bytes");
+    assert!(env_fits([option_env!("MQTT_DOMAIN")], MQTT_DOMAIN_SIZE), "MQTT_DOMAIN must be at most 64 bytes");
+};
+
+impl DeviceConfig {
+    /// Configuration compiled into the firmware, used when flash holds no valid record.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        let wifi_config = WiFiFacadeConfig::from_env();
+        for network in wifi_config.networks.iter() {
+            config.add_wifi_network(network.ssid, network.password).expect("Wi-Fi network length checked at compile time");
+        }
+        config.device_id.push_str(env!("DEVICE_ID")).expect("DEVICE_ID length checked at compile time");
+        config.device_name.push_str(env!("DEVICE_NAME")).expect("DEVICE_NAME length checked at compile time");
+        config.mqtt_service.push_str(env!("MQTT_SERVICE")).expect("MQTT_SERVICE length checked at compile time");
+        let _ = config.mqtt_client_id.push_str("MyDevice");
+        config.mqtt_broker.push_str(option_env!("MQTT_BROKER").unwrap_or("")).expect("MQTT_BROKER length checked at compile time");
+        config.mqtt_domain.push_str(option_env!("MQTT_DOMAIN").unwrap_or("")).expect("MQTT_DOMAIN length checked at compile time");
+        config
+    }
+
+    pub fn wifi_config(&'static self) -> WiFiFacadeConfig {
+        WiFiFacadeConfig {
+            networks: self.wifi_networks.iter()
+                .map(|network| WiFiNetwork { ssid: network.ssid.as_str(), password: network.password.as_str() })
+                .collect(),
+        }
+    }
+
+    pub fn home_assistant_config(&'static self) -> HomeAssistantFacadeConfig {
+        HomeAssistantFacadeConfig::new(self.device_id.as_str(), self.device_name.as_str())
+    }
+
+    /// MQTT settings with `broker` first and topics under `topic_prefix`,
+    /// unless the broker advertises its own.
+    pub fn mqtt_config(&'static self, broker: MqttBroker, topic_prefix: &'static str) -> MqttFacadeConfig {
+        MqttFacadeConfig::new(broker, self.mqtt_client_id.as_str(), topic_prefix)
+    }
+}
+
+/// Loads and saves the `DeviceConfig` record at the start of a flash region.
+pub struct ConfigStore<S> {
+    _storage: S,
+}
+
+impl<S: ReadStorage + Storage> ConfigStore<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            _storage: storage,
+        }
+    }
+
+    pub fn load(&mut self) -> Result<DeviceConfig, ConfigError> {
+        let mut buffer = [0_u8; CONFIG_RECORD_SIZE];
+        self._storage.read(0, &mut buffer).map_err(|_| ConfigError::Storage)?;
+        DeviceConfig::decode(&buffer)
+    }
+
+    /// Loads the stored record, falling back to `DeviceConfig::from_env` when
+    /// it is absent, corrupted or from another version.
+    pub fn load_or_default(&mut self) -> DeviceConfig {
+        match self.load() {
+            Ok(config) => {
+                info!("ConfigStore: Loaded configuration for device {:?}", config.device_id);
+                config
+            }
+            Err(ConfigError::NotFound) => {
+                info!("ConfigStore: No stored configuration, using compile-time defaults");
+                DeviceConfig::from_env()
+            }
+            Err(e) => {
+                error!("ConfigStore: Stored configuration unusable ({:?}), using compile-time defaults", e);
+                DeviceConfig::from_env()
+            }
+        }
+    }
+
+    pub fn save(&mut self, config: &DeviceConfig) -> Result<(), ConfigError> {
+        let mut buffer = [0_u8; CONFIG_RECORD_SIZE];
+        let length = config.encode(&mut buffer)?;
+        self._storage.write(0, &buffer[..length]).map_err(|_| ConfigError::Storage)?;
+        info!("ConfigStore: Saved configuration ({} bytes)", length);
+        Ok(())
+    }
+
+    /// Invalidates the stored record, so the next boot uses the compile-time defaults.
+    pub fn erase(&mut self) -> Result<(), ConfigError> {
+        self._storage.write(0, &[0xFF; CONFIG_HEADER_SIZE]).map_err(|_| ConfigError::Storage)
+    }
+}
+
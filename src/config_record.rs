@@ -0,0 +1,550 @@
+//! The persisted `DeviceConfig` and its flash record: layout, versions,
+//! migration and the `get`/`set` keys of the console and the portal. Only
+//! depends on `core` and `heapless`, so it can be exercised on the host
+//! without flash.
+
+use heapless::{String, Vec};
+use log::error;
+
+use crate::discovery::{BrokerDiscovery, ConfiguredBroker, UNICAST_SERVICE_SIZE};
+use crate::wifi_networks::MAX_KNOWN_NETWORKS;
+
+/// Bumped whenever the record layout changes. Records of an older version
+/// down to `CONFIG_MIN_VERSION` are migrated when decoded, others are ignored
+/// and the compile-time defaults are used instead.
+pub const CONFIG_VERSION: u16 = 2;
+/// Oldest record version that can be migrated. Version 1 ends with
+/// `mqtt_broker`, `mqtt_domain` was added in version 2.
+const CONFIG_MIN_VERSION: u16 = 1;
+
+const CONFIG_MAGIC: [u8; 4] = *b"AQMC";
+pub const CONFIG_HEADER_SIZE: usize = 8;
+const CONFIG_CRC_SIZE: usize = 4;
+/// Space reserved for the record at the start of the configuration partition.
+pub const CONFIG_RECORD_SIZE: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    NotFound,
+    UnsupportedVersion(u16),
+    Corrupted,
+    TooLarge,
+    Storage,
+    UnknownKey,
+    InvalidValue,
+}
+
+/// Keys accepted by `DeviceConfig::get` and `DeviceConfig::set`. The `wifi.*`
+/// keys refer to the preferred (first) network.
+pub const CONFIG_KEYS: [&str; 8] = [
+    "wifi.ssid",
+    "wifi.password",
+    "device.id",
+    "device.name",
+    "mqtt.service",
+    "mqtt.client_id",
+    "mqtt.broker",
+    "mqtt.domain",
+];
+
+pub const SSID_SIZE: usize = 32;
+pub const PASSWORD_SIZE: usize = 64;
+pub const DEVICE_ID_SIZE: usize = 32;
+pub const DEVICE_NAME_SIZE: usize = 64;
+pub const MQTT_SERVICE_SIZE: usize = 64;
+pub const MQTT_BROKER_SIZE: usize = 64;
+pub const MQTT_DOMAIN_SIZE: usize = 64;
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredWiFiNetwork {
+    pub ssid: String<SSID_SIZE>,
+    pub password: String<PASSWORD_SIZE>,
+}
+
+/// Settings that used to be compiled in, persisted so a single firmware build
+/// can be deployed to every monitor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub wifi_networks: Vec<StoredWiFiNetwork, MAX_KNOWN_NETWORKS>,
+    pub device_id: String<DEVICE_ID_SIZE>,
+    pub device_name: String<DEVICE_NAME_SIZE>,
+    pub mqtt_service: String<MQTT_SERVICE_SIZE>,
+    pub mqtt_client_id: String<32>,
+    /// Static broker (`ip:port`, `[ipv6]:port` or `host:port`), used when
+    /// discovery of `mqtt_service` is disabled (empty service) or finds no broker.
+    /// Host names are resolved with the DNS servers provided by DHCP.
+    pub mqtt_broker: String<MQTT_BROKER_SIZE>,
+    /// Unicast DNS domain browsed for `mqtt_service` when mDNS finds no broker,
+    /// e.g. on networks filtering multicast. Empty disables it.
+    pub mqtt_domain: String<MQTT_DOMAIN_SIZE>,
+}
+
+
+impl DeviceConfig {
+    pub fn add_wifi_network(&mut self, ssid: &str, password: &str) -> Result<(), ConfigError> {
+        let mut network = StoredWiFiNetwork::default();
+        network.ssid.push_str(ssid).map_err(|_| ConfigError::TooLarge)?;
+        network.password.push_str(password).map_err(|_| ConfigError::TooLarge)?;
+        self.wifi_networks.push(network).map_err(|_| ConfigError::TooLarge)
+    }
+
+    pub fn get(&self, key: &str) -> Result<&str, ConfigError> {
+        let preferred_network = self.wifi_networks.first();
+        match key {
+            "wifi.ssid" => Ok(preferred_network.map_or("", |network| network.ssid.as_str())),
+            "wifi.password" => Ok(preferred_network.map_or("", |network| network.password.as_str())),
+            "device.id" => Ok(&self.device_id),
+            "device.name" => Ok(&self.device_name),
+            "mqtt.service" => Ok(&self.mqtt_service),
+            "mqtt.client_id" => Ok(&self.mqtt_client_id),
+            "mqtt.broker" => Ok(&self.mqtt_broker),
+            "mqtt.domain" => Ok(&self.mqtt_domain),
+            _ => Err(ConfigError::UnknownKey),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        fn assign<const N: usize>(field: &mut String<N>, value: &str) -> Result<(), ConfigError> {
+            let mut new_value = String::new();
+            new_value.push_str(value).map_err(|_| ConfigError::TooLarge)?;
+            *field = new_value;
+            Ok(())
+        }
+
+        match key {
+            "wifi.ssid" | "device.id" if value.is_empty() => Err(ConfigError::InvalidValue),
+            "wifi.ssid" => assign(&mut self.preferred_network()?.ssid, value),
+            "wifi.password" => assign(&mut self.preferred_network()?.password, value),
+            "device.id" => assign(&mut self.device_id, value),
+            "device.name" => assign(&mut self.device_name, value),
+            "mqtt.service" => assign(&mut self.mqtt_service, value),
+            "mqtt.client_id" => assign(&mut self.mqtt_client_id, value),
+            "mqtt.broker" if !value.is_empty() && parse_broker(value).is_none() => Err(ConfigError::InvalidValue),
+            "mqtt.broker" => assign(&mut self.mqtt_broker, value),
+            "mqtt.domain" => assign(&mut self.mqtt_domain, value.trim_matches('.')),
+            _ => Err(ConfigError::UnknownKey),
+        }
+    }
+
+    fn preferred_network(&mut self) -> Result<&mut StoredWiFiNetwork, ConfigError> {
+        if self.wifi_networks.is_empty() {
+            let _ = self.wifi_networks.push(StoredWiFiNetwork::default());
+        }
+        self.wifi_networks.first_mut().ok_or(ConfigError::TooLarge)
+    }
+
+    /// Broker configured with `mqtt_broker`, if any. It is the fallback of
+    /// discovery using `mqtt_service`.
+    pub fn static_broker(&self) -> Option<ConfiguredBroker<'_>> {
+        if self.mqtt_broker.is_empty() {
+            return None;
+        }
+        let broker = parse_broker(&self.mqtt_broker);
+        if broker.is_none() {
+            error!("DeviceConfig: Ignoring invalid broker address {:?}", self.mqtt_broker);
+        }
+        broker
+    }
+
+    /// Name browsed with unicast DNS-SD: `mqtt_service` moved from `.local`
+    /// to `mqtt_domain`, e.g. `_mqtt._tcp.example.com`. `None` when either is empty.
+    pub fn unicast_service(&self) -> Option<String<UNICAST_SERVICE_SIZE>> {
+        let service_type = self.mqtt_service.strip_suffix(".local").unwrap_or(&self.mqtt_service);
+        if service_type.is_empty() || self.mqtt_domain.is_empty() {
+            return None;
+        }
+        let mut name = String::new();
+        name.push_str(service_type).ok()?;
+        name.push('.').ok()?;
+        name.push_str(&self.mqtt_domain).ok()?;
+        Some(name)
+    }
+
+    /// Where the MQTT brokers are looked up, from `mqtt_service`, `mqtt_domain`
+    /// and `mqtt_broker`.
+    pub fn broker_discovery(&'static self) -> BrokerDiscovery<'static> {
+        BrokerDiscovery {
+            service: self.mqtt_service.as_str(),
+            unicast_service: self.unicast_service(),
+            configured_broker: self.static_broker(),
+        }
+    }
+
+    /// Serializes the record (header, fields, CRC32) into `buffer`, returning its length.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, ConfigError> {
+        let mut writer = RecordWriter { buffer, position: CONFIG_HEADER_SIZE };
+        writer.write_u8(self.wifi_networks.len() as u8)?;
+        for network in self.wifi_networks.iter() {
+            writer.write_str(&network.ssid)?;
+            writer.write_str(&network.password)?;
+        }
+        writer.write_str(&self.device_id)?;
+        writer.write_str(&self.device_name)?;
+        writer.write_str(&self.mqtt_service)?;
+        writer.write_str(&self.mqtt_client_id)?;
+        writer.write_str(&self.mqtt_broker)?;
+        writer.write_str(&self.mqtt_domain)?;
+
+        let payload_length = writer.position - CONFIG_HEADER_SIZE;
+        let buffer = writer.buffer;
+        if buffer.len() < CONFIG_HEADER_SIZE + payload_length + CONFIG_CRC_SIZE || payload_length > u16::MAX as usize {
+            return Err(ConfigError::TooLarge);
+        }
+        buffer[0..4].copy_from_slice(&CONFIG_MAGIC);
+        buffer[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+        buffer[6..8].copy_from_slice(&(payload_length as u16).to_le_bytes());
+
+        let crc_offset = CONFIG_HEADER_SIZE + payload_length;
+        let crc = crc32(&buffer[4..crc_offset]);
+        buffer[crc_offset..crc_offset + CONFIG_CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(crc_offset + CONFIG_CRC_SIZE)
+    }
+
+    /// Parses a record produced by `encode`, or by an older firmware down to
+    /// `CONFIG_MIN_VERSION`, checking magic, version and CRC32.
+    pub fn decode(buffer: &[u8]) -> Result<Self, ConfigError> {
+        if buffer.len() < CONFIG_HEADER_SIZE || buffer[0..4] != CONFIG_MAGIC {
+            return Err(ConfigError::NotFound);
+        }
+
+        let version = u16::from_le_bytes([buffer[4], buffer[5]]);
+        if !(CONFIG_MIN_VERSION..=CONFIG_VERSION).contains(&version) {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+
+        let payload_length = u16::from_le_bytes([buffer[6], buffer[7]]) as usize;
+        let crc_offset = CONFIG_HEADER_SIZE + payload_length;
+        if buffer.len() < crc_offset + CONFIG_CRC_SIZE {
+            return Err(ConfigError::Corrupted);
+        }
+        let stored_crc = u32::from_le_bytes([
+            buffer[crc_offset],
+            buffer[crc_offset + 1],
+            buffer[crc_offset + 2],
+            buffer[crc_offset + 3],
+        ]);
+        if crc32(&buffer[4..crc_offset]) != stored_crc {
+            return Err(ConfigError::Corrupted);
+        }
+
+        let mut reader = RecordReader { buffer: &buffer[..crc_offset], position: CONFIG_HEADER_SIZE };
+        let mut config = Self::default();
+        let network_count = reader.read_u8()?;
+        for _ in 0..network_count {
+            let network = StoredWiFiNetwork {
+                ssid: reader.read_str()?,
+                password: reader.read_str()?,
+            };
+            config.wifi_networks.push(network).map_err(|_| ConfigError::Corrupted)?;
+        }
+        config.device_id = reader.read_str()?;
+        config.device_name = reader.read_str()?;
+        config.mqtt_service = reader.read_str()?;
+        config.mqtt_client_id = reader.read_str()?;
+        config.mqtt_broker = reader.read_str()?;
+        if version >= 2 {
+            config.mqtt_domain = reader.read_str()?;
+        }
+
+        Ok(config)
+    }
+}
+
+struct RecordWriter<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+}
+
+impl<'b> RecordWriter<'b> {
+    fn write_u8(&mut self, value: u8) -> Result<(), ConfigError> {
+        let slot = self.buffer.get_mut(self.position).ok_or(ConfigError::TooLarge)?;
+        *slot = value;
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Strings are stored as a length byte followed by their UTF-8 bytes.
+    fn write_str(&mut self, value: &str) -> Result<(), ConfigError> {
+        let length = u8::try_from(value.len()).map_err(|_| ConfigError::TooLarge)?;
+        self.write_u8(length)?;
+        let end = self.position + value.len();
+        self.buffer.get_mut(self.position..end)
+            .ok_or(ConfigError::TooLarge)?
+            .copy_from_slice(value.as_bytes());
+        self.position = end;
+        Ok(())
+    }
+}
+
+struct RecordReader<'b> {
+    buffer: &'b [u8],
+    position: usize,
+}
+
+impl<'b> RecordReader<'b> {
+    fn read_u8(&mut self) -> Result<u8, ConfigError> {
+        let value = *self.buffer.get(self.position).ok_or(ConfigError::Corrupted)?;
+        self.position += 1;
+        Ok(value)
+    }
+
+    fn read_str<const N: usize>(&mut self) -> Result<String<N>, ConfigError> {
+        let length = self.read_u8()? as usize;
+        let end = self.position + length;
+        let bytes = self.buffer.get(self.position..end).ok_or(ConfigError::Corrupted)?;
+        let value = core::str::from_utf8(bytes).map_err(|_| ConfigError::Corrupted)?;
+        self.position = end;
+
+        let mut string = String::new();
+        string.push_str(value).map_err(|_| ConfigError::Corrupted)?;
+        Ok(string)
+    }
+}
+
+/// Parses `ip:port`, `[ipv6]:port` or `host:port`.
+fn parse_broker(value: &str) -> Option<ConfiguredBroker<'_>> {
+    if let Ok(address) = value.parse() {
+        return Some(ConfiguredBroker::Address(address));
+    }
+    let (name, port) = value.rsplit_once(':')?;
+    let valid_name = !name.is_empty()
+        && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.');
+    let port = port.parse().ok()?;
+    valid_name.then_some(ConfiguredBroker::Host { name, port })
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to avoid a lookup table in flash.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    fn sample_config() -> DeviceConfig {
+        let mut config = DeviceConfig::default();
+        config.add_wifi_network("home", "secret").unwrap();
+        config.add_wifi_network("office", "").unwrap();
+        config.set("device.id", "aqm-01").unwrap();
+        config.set("device.name", "Living room").unwrap();
+        config.set("mqtt.service", "_mqtt._tcp.local").unwrap();
+        config.set("mqtt.client_id", "aqm-01").unwrap();
+        config.set("mqtt.broker", "broker.example.com:1883").unwrap();
+        config.set("mqtt.domain", "example.com").unwrap();
+        config
+    }
+
+    fn encoded(config: &DeviceConfig) -> std::vec::Vec<u8> {
+        let mut buffer = [0_u8; CONFIG_RECORD_SIZE];
+        let length = config.encode(&mut buffer).unwrap();
+        buffer[..length].to_vec()
+    }
+
+    /// Wraps a hand-built payload in a header and CRC, as `encode` would.
+    fn sealed(version: u16, payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut record = CONFIG_MAGIC.to_vec();
+        record.extend_from_slice(&version.to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        record.extend_from_slice(payload);
+        let crc = crc32(&record[4..]);
+        record.extend_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// Fields of a record as length-prefixed strings.
+    fn payload(network_count: u8, strings: &[&str]) -> std::vec::Vec<u8> {
+        let mut payload = std::vec![network_count];
+        for string in strings {
+            payload.push(string.len() as u8);
+            payload.extend_from_slice(string.as_bytes());
+        }
+        payload
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let config = sample_config();
+
+        let record = encoded(&config);
+
+        assert_eq!(&record[0..4], b"AQMC");
+        assert_eq!(DeviceConfig::decode(&record), Ok(config));
+    }
+
+    #[test]
+    fn record_is_read_from_the_start_of_the_partition() {
+        let config = sample_config();
+        let mut partition = [0xFF_u8; CONFIG_RECORD_SIZE];
+        config.encode(&mut partition).unwrap();
+
+        assert_eq!(DeviceConfig::decode(&partition), Ok(config));
+    }
+
+    #[test]
+    fn erased_flash_is_not_found() {
+        assert_eq!(DeviceConfig::decode(&[0xFF; CONFIG_RECORD_SIZE]), Err(ConfigError::NotFound));
+        assert_eq!(DeviceConfig::decode(&[]), Err(ConfigError::NotFound));
+        assert_eq!(DeviceConfig::decode(b"AQMC"), Err(ConfigError::NotFound));
+    }
+
+    #[test]
+    fn corrupted_crc_is_rejected() {
+        let mut record = encoded(&sample_config());
+        let last = record.len() - 1;
+        record[last] ^= 0x01;
+        assert_eq!(DeviceConfig::decode(&record), Err(ConfigError::Corrupted));
+
+        let mut record = encoded(&sample_config());
+        record[CONFIG_HEADER_SIZE + 3] ^= 0x20;
+        assert_eq!(DeviceConfig::decode(&record), Err(ConfigError::Corrupted));
+    }
+
+    #[test]
+    fn truncated_record_is_corrupted() {
+        let record = encoded(&sample_config());
+
+        assert_eq!(DeviceConfig::decode(&record[..record.len() - 1]), Err(ConfigError::Corrupted));
+        assert_eq!(DeviceConfig::decode(&record[..CONFIG_HEADER_SIZE]), Err(ConfigError::Corrupted));
+    }
+
+    #[test]
+    fn payload_shorter_than_its_fields_is_corrupted() {
+        // Valid CRC, but the record stops after the device name
+        let record = sealed(CONFIG_VERSION, &payload(0, &["aqm-01", "Living room"]));
+
+        assert_eq!(DeviceConfig::decode(&record), Err(ConfigError::Corrupted));
+    }
+
+    #[test]
+    fn oversized_fields_are_corrupted() {
+        let long_id = "x".repeat(DEVICE_ID_SIZE + 1);
+        let record = sealed(CONFIG_VERSION, &payload(0, &[&long_id, "", "", "", "", ""]));
+        assert_eq!(DeviceConfig::decode(&record), Err(ConfigError::Corrupted));
+
+        let mut networks = std::vec::Vec::new();
+        for _ in 0..=MAX_KNOWN_NETWORKS {
+            networks.extend(["home", "secret"]);
+        }
+        networks.extend(["", "", "", "", "", ""]);
+        let record = sealed(CONFIG_VERSION, &payload(MAX_KNOWN_NETWORKS as u8 + 1, &networks));
+        assert_eq!(DeviceConfig::decode(&record), Err(ConfigError::Corrupted));
+    }
+
+    #[test]
+    fn encode_into_a_small_buffer_is_too_large() {
+        let config = sample_config();
+        let length = encoded(&config).len();
+        let mut buffer = std::vec![0_u8; length - 1];
+
+        assert_eq!(config.encode(&mut buffer), Err(ConfigError::TooLarge));
+        assert_eq!(config.encode(&mut [0_u8; 4]), Err(ConfigError::TooLarge));
+    }
+
+    #[test]
+    fn version_1_record_is_migrated() {
+        // Written by a firmware without `mqtt_domain`
+        let fields = ["home", "secret", "aqm-01", "Living room", "_mqtt._tcp.local", "aqm-01", "10.0.0.1:1883"];
+        let record = sealed(1, &payload(1, &fields));
+
+        let config = DeviceConfig::decode(&record).unwrap();
+
+        assert_eq!(config.get("wifi.ssid"), Ok("home"));
+        assert_eq!(config.get("wifi.password"), Ok("secret"));
+        assert_eq!(config.get("device.id"), Ok("aqm-01"));
+        assert_eq!(config.get("device.name"), Ok("Living room"));
+        assert_eq!(config.get("mqtt.service"), Ok("_mqtt._tcp.local"));
+        assert_eq!(config.get("mqtt.client_id"), Ok("aqm-01"));
+        assert_eq!(config.get("mqtt.broker"), Ok("10.0.0.1:1883"));
+        assert_eq!(config.get("mqtt.domain"), Ok(""));
+        assert_eq!(config.unicast_service(), None);
+
+        // Saved again with the current layout
+        let migrated = encoded(&config);
+        assert_eq!(u16::from_le_bytes([migrated[4], migrated[5]]), CONFIG_VERSION);
+        assert_eq!(DeviceConfig::decode(&migrated), Ok(config));
+    }
+
+    #[test]
+    fn unknown_versions_are_unsupported() {
+        let fields = payload(0, &["aqm-01", "", "", "", "", ""]);
+
+        assert_eq!(DeviceConfig::decode(&sealed(0, &fields)), Err(ConfigError::UnsupportedVersion(0)));
+        assert_eq!(
+            DeviceConfig::decode(&sealed(CONFIG_VERSION + 1, &fields)),
+            Err(ConfigError::UnsupportedVersion(CONFIG_VERSION + 1)),
+        );
+    }
+
+    #[test]
+    fn set_validates_values() {
+        let mut config = DeviceConfig::default();
+
+        assert_eq!(config.set("wifi.ssid", ""), Err(ConfigError::InvalidValue));
+        assert_eq!(config.set("device.id", ""), Err(ConfigError::InvalidValue));
+        assert_eq!(config.set("mqtt.broker", "not a broker"), Err(ConfigError::InvalidValue));
+        assert_eq!(config.set("mqtt.port", "1883"), Err(ConfigError::UnknownKey));
+        assert_eq!(config.set("device.id", &"x".repeat(DEVICE_ID_SIZE + 1)), Err(ConfigError::TooLarge));
+        assert_eq!(config, DeviceConfig::default());
+    }
+
+    #[test]
+    fn set_then_get() {
+        let mut config = DeviceConfig::default();
+
+        config.set("wifi.ssid", "home").unwrap();
+        config.set("mqtt.domain", ".example.com.").unwrap();
+        config.set("mqtt.broker", "").unwrap();
+
+        assert_eq!(config.wifi_networks.len(), 1);
+        assert_eq!(config.get("wifi.ssid"), Ok("home"));
+        assert_eq!(config.get("wifi.password"), Ok(""));
+        assert_eq!(config.get("mqtt.domain"), Ok("example.com"));
+        assert_eq!(config.get("mqtt.broker"), Ok(""));
+        assert_eq!(config.get("mqtt.port"), Err(ConfigError::UnknownKey));
+        for key in CONFIG_KEYS {
+            assert!(config.get(key).is_ok(), "{key}");
+        }
+    }
+
+    #[test]
+    fn brokers_are_parsed() {
+        assert_eq!(
+            parse_broker("192.168.1.10:1883"),
+            Some(ConfiguredBroker::Address(SocketAddr::new(Ipv4Addr::new(192, 168, 1, 10).into(), 1883))),
+        );
+        assert_eq!(
+            parse_broker("[fd00::1]:8883"),
+            Some(ConfiguredBroker::Address(SocketAddr::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into(), 8883))),
+        );
+        assert_eq!(parse_broker("broker.lan:1883"), Some(ConfiguredBroker::Host { name: "broker.lan", port: 1883 }));
+        assert_eq!(parse_broker("broker.lan"), None);
+        assert_eq!(parse_broker(":1883"), None);
+        assert_eq!(parse_broker("broker lan:1883"), None);
+        assert_eq!(parse_broker("broker.lan:70000"), None);
+    }
+
+    #[test]
+    fn unicast_service_moves_the_service_to_the_domain() {
+        let config = sample_config();
+
+        assert_eq!(config.unicast_service().as_deref(), Some("_mqtt._tcp.example.com"));
+        assert_eq!(DeviceConfig::default().unicast_service(), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Command parsing for the serial console. Only depends on `core` and
+//! `heapless`, so it can be exercised on the host without hardware.
+
+use heapless::String;
+
+pub const CONSOLE_LINE_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    EmptyLine,
+    UnknownCommand,
+    MissingArgument(&'static str),
+    UnexpectedArgument,
+    LineTooLong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'l> {
+    Help,
+    /// `config get [key]`, every key when none is given.
+    ConfigGet(Option<&'l str>),
+    /// `config set <key> [value]`. The value is the rest of the line, so it may
+    /// contain spaces. It can be wrapped in double quotes, e.g. `""` to clear it.
+    ConfigSet { key: &'l str, value: &'l str },
+    ConfigSave,
+    WifiScan,
+    WifiStatus,
+    MqttStatus,
+    /// `sensor read <id>`, e.g. `sensor read scd41`.
+    SensorRead(&'l str),
+    Reboot,
+    FactoryReset,
+}
+
+pub const CONSOLE_HELP: &str = "\
+help                      Show this help\r\n\
+config get [key]          Show the configuration, or one key\r\n\
+config set <key> [value]  Change a key (applied after config save and reboot)\r\n\
+config save               Persist the configuration to flash\r\n\
+wifi scan                 List the access points in range\r\n\
+wifi status               Show the Wi-Fi link state\r\n\
+mqtt status               Show the MQTT connection state\r\n\
+sensor read <id>          Show the latest reading and health of a sensor\r\n\
+reboot                    Restart the device\r\n\
+factory-reset             Erase the stored configuration and restart\r\n";
+
+impl<'l> Command<'l> {
+    pub fn parse(line: &'l str) -> Result<Self, ConsoleError> {
+        let line = line.trim();
+        let (command, arguments) = split_word(line);
+        let (subcommand, rest) = split_word(arguments);
+
+        let command = match (command, subcommand) {
+            ("", _) => return Err(ConsoleError::EmptyLine),
+            ("help" | "?", _) => Command::Help,
+            ("config", "get") => {
+                let (key, rest) = split_word(rest);
+                expect_end(rest)?;
+                Command::ConfigGet((!key.is_empty()).then_some(key))
+            }
+            ("config", "set") => {
+                let (key, value) = split_word(rest);
+                if key.is_empty() {
+                    return Err(ConsoleError::MissingArgument("key"));
+                }
+                Command::ConfigSet { key, value: unquote(value) }
+            }
+            ("config", "save") => expect_end(rest).map(|_| Command::ConfigSave)?,
+            ("wifi", "scan") => expect_end(rest).map(|_| Command::WifiScan)?,
+            ("wifi", "status") => expect_end(rest).map(|_| Command::WifiStatus)?,
+            ("mqtt", "status") => expect_end(rest).map(|_| Command::MqttStatus)?,
+            ("sensor", "read") => {
+                let (sensor, rest) = split_word(rest);
+                if sensor.is_empty() {
+                    return Err(ConsoleError::MissingArgument("sensor"));
+                }
+                expect_end(rest)?;
+                Command::SensorRead(sensor)
+            }
+            ("reboot", "") => Command::Reboot,
+            ("factory-reset", "") => Command::FactoryReset,
+            ("reboot" | "factory-reset", _) => return Err(ConsoleError::UnexpectedArgument),
+            _ => return Err(ConsoleError::UnknownCommand),
+        };
+        Ok(command)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineStatus {
+    /// Control character or overflowing byte, nothing to echo.
+    Ignored,
+    /// The byte was added to the line and should be echoed.
+    Appended,
+    /// The last character was removed (backspace or delete).
+    Erased,
+    /// End of line, `LineBuffer::line` holds the command.
+    Complete,
+}
+
+/// Accumulates the bytes typed on the console until a line ending.
+pub struct LineBuffer<const N: usize> {
+    line: String<N>,
+    overflowed: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            line: String::new(),
+            overflowed: false,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> LineStatus {
+        match byte {
+            b'\r' | b'\n' => LineStatus::Complete,
+            0x08 | 0x7F => match self.line.pop() {
+                Some(_) => LineStatus::Erased,
+                None => LineStatus::Ignored,
+            },
+            b' '..=b'~' => match self.line.push(byte as char) {
+                Ok(_) => LineStatus::Appended,
+                Err(_) => {
+                    self.overflowed = true;
+                    LineStatus::Ignored
+                }
+            },
+            _ => LineStatus::Ignored,
+        }
+    }
+
+    /// The completed line. Fails if characters were dropped because it did
+    /// not fit. The buffer must be `clear`ed before typing the next line.
+    pub fn line(&self) -> Result<&str, ConsoleError> {
+        if self.overflowed {
+            return Err(ConsoleError::LineTooLong);
+        }
+        Ok(&self.line)
+    }
+
+    pub fn clear(&mut self) {
+        self.line.clear();
+        self.overflowed = false;
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits the first whitespace-separated word from the rest of `input`.
+fn split_word(input: &str) -> (&str, &str) {
+    let input = input.trim_start();
+    match input.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (input, ""),
+    }
+}
+
+fn expect_end(rest: &str) -> Result<(), ConsoleError> {
+    if rest.trim().is_empty() {
+        Ok(())
+    } else {
+        Err(ConsoleError::UnexpectedArgument)
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value.strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_line<const N: usize>(buffer: &mut LineBuffer<N>, text: &str) -> LineStatus {
+        let mut status = LineStatus::Ignored;
+        for byte in text.bytes() {
+            status = buffer.push(byte);
+        }
+        status
+    }
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+        assert_eq!(Command::parse("?"), Ok(Command::Help));
+        assert_eq!(Command::parse("config get"), Ok(Command::ConfigGet(None)));
+        assert_eq!(Command::parse("config get mqtt.broker"), Ok(Command::ConfigGet(Some("mqtt.broker"))));
+        assert_eq!(Command::parse("config set device.name Office"),
+            Ok(Command::ConfigSet { key: "device.name", value: "Office" }));
+        assert_eq!(Command::parse("config save"), Ok(Command::ConfigSave));
+        assert_eq!(Command::parse("wifi scan"), Ok(Command::WifiScan));
+        assert_eq!(Command::parse("wifi status"), Ok(Command::WifiStatus));
+        assert_eq!(Command::parse("mqtt status"), Ok(Command::MqttStatus));
+        assert_eq!(Command::parse("sensor read scd41"), Ok(Command::SensorRead("scd41")));
+        assert_eq!(Command::parse("reboot"), Ok(Command::Reboot));
+        assert_eq!(Command::parse("factory-reset"), Ok(Command::FactoryReset));
+    }
+
+    #[test]
+    fn ignores_surrounding_and_repeated_whitespace() {
+        assert_eq!(Command::parse("  wifi \t status  "), Ok(Command::WifiStatus));
+        assert_eq!(Command::parse("config  get   mqtt.broker "), Ok(Command::ConfigGet(Some("mqtt.broker"))));
+    }
+
+    #[test]
+    fn rejects_empty_and_unknown_commands() {
+        assert_eq!(Command::parse(""), Err(ConsoleError::EmptyLine));
+        assert_eq!(Command::parse("   "), Err(ConsoleError::EmptyLine));
+        assert_eq!(Command::parse("reset"), Err(ConsoleError::UnknownCommand));
+        assert_eq!(Command::parse("config"), Err(ConsoleError::UnknownCommand));
+        assert_eq!(Command::parse("config delete wifi.ssid"), Err(ConsoleError::UnknownCommand));
+        assert_eq!(Command::parse("wifi"), Err(ConsoleError::UnknownCommand));
+        assert_eq!(Command::parse("WIFI SCAN"), Err(ConsoleError::UnknownCommand));
+    }
+
+    #[test]
+    fn rejects_missing_arguments() {
+        assert_eq!(Command::parse("config set"), Err(ConsoleError::MissingArgument("key")));
+        assert_eq!(Command::parse("config set   "), Err(ConsoleError::MissingArgument("key")));
+        assert_eq!(Command::parse("sensor read"), Err(ConsoleError::MissingArgument("sensor")));
+    }
+
+    #[test]
+    fn rejects_unexpected_arguments() {
+        assert_eq!(Command::parse("config get mqtt.broker mqtt.username"), Err(ConsoleError::UnexpectedArgument));
+        assert_eq!(Command::parse("config save now"), Err(ConsoleError::UnexpectedArgument));
+        assert_eq!(Command::parse("wifi scan all"), Err(ConsoleError::UnexpectedArgument));
+        assert_eq!(Command::parse("mqtt status verbose"), Err(ConsoleError::UnexpectedArgument));
+        assert_eq!(Command::parse("sensor read scd41 sgp41"), Err(ConsoleError::UnexpectedArgument));
+        assert_eq!(Command::parse("reboot now"), Err(ConsoleError::UnexpectedArgument));
+        assert_eq!(Command::parse("factory-reset yes"), Err(ConsoleError::UnexpectedArgument));
+    }
+
+    #[test]
+    fn config_set_value_keeps_spaces() {
+        assert_eq!(Command::parse("config set device.name Living room sensor"),
+            Ok(Command::ConfigSet { key: "device.name", value: "Living room sensor" }));
+        assert_eq!(Command::parse("config set wifi.password \" pass phrase \""),
+            Ok(Command::ConfigSet { key: "wifi.password", value: " pass phrase " }));
+        assert_eq!(Command::parse("config set mqtt.username \"\""),
+            Ok(Command::ConfigSet { key: "mqtt.username", value: "" }));
+        assert_eq!(Command::parse("config set mqtt.username"),
+            Ok(Command::ConfigSet { key: "mqtt.username", value: "" }));
+        // Only a matching pair of quotes is removed.
+        assert_eq!(Command::parse("config set device.name \"Office"),
+            Ok(Command::ConfigSet { key: "device.name", value: "\"Office" }));
+    }
+
+    #[test]
+    fn line_buffer_completes_lines() {
+        let mut buffer = LineBuffer::<CONSOLE_LINE_SIZE>::new();
+        assert_eq!(type_line(&mut buffer, "wifi scan"), LineStatus::Appended);
+        assert_eq!(buffer.push(b'\r'), LineStatus::Complete);
+        assert_eq!(buffer.line(), Ok("wifi scan"));
+
+        buffer.clear();
+        assert_eq!(buffer.line(), Ok(""));
+        assert_eq!(buffer.push(b'\n'), LineStatus::Complete);
+    }
+
+    #[test]
+    fn line_buffer_erases_and_ignores_control_characters() {
+        let mut buffer = LineBuffer::<CONSOLE_LINE_SIZE>::new();
+        assert_eq!(buffer.push(0x08), LineStatus::Ignored);
+        type_line(&mut buffer, "rebooz");
+        assert_eq!(buffer.push(0x7F), LineStatus::Erased);
+        assert_eq!(buffer.push(0x1B), LineStatus::Ignored);
+        assert_eq!(buffer.push(0xC3), LineStatus::Ignored);
+        type_line(&mut buffer, "t");
+        assert_eq!(buffer.line(), Ok("reboot"));
+    }
+
+    #[test]
+    fn line_buffer_rejects_overflowing_line() {
+        let mut buffer = LineBuffer::<8>::new();
+        assert_eq!(type_line(&mut buffer, "reboot12"), LineStatus::Appended);
+        assert_eq!(buffer.push(b'3'), LineStatus::Ignored);
+        assert_eq!(buffer.push(b'\r'), LineStatus::Complete);
+        assert_eq!(buffer.line(), Err(ConsoleError::LineTooLong));
+
+        // Erasing back under the limit does not recover the dropped bytes.
+        buffer.push(0x7F);
+        assert_eq!(buffer.line(), Err(ConsoleError::LineTooLong));
+
+        buffer.clear();
+        type_line(&mut buffer, "reboot");
+        assert_eq!(buffer.line(), Ok("reboot"));
+    }
+}
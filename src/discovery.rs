@@ -0,0 +1,358 @@
+//! Service discovery types shared by mDNS and unicast DNS-SD, and the chain
+//! that looks up the MQTT brokers with them through `BrokerLookup`. Only
+//! depends on `core`, `heapless` and `log`, so it can be exercised on the host
+//! without a network.
+
+use core::net::{IpAddr, SocketAddr};
+use heapless::{String, Vec};
+use log::{error, info};
+
+use crate::dns::DnsTxt;
+
+/// Instances kept per browsed service.
+pub const DISCOVERY_MAX_INSTANCES: usize = 4;
+/// Longest service name browsed with unicast DNS-SD, e.g. `_mqtt._tcp.example.com`.
+pub const UNICAST_SERVICE_SIZE: usize = 128;
+
+pub const SERVICE_TXT_MAX_ENTRIES: usize = 8;
+pub const SERVICE_TXT_KEY_SIZE: usize = 16;
+pub const SERVICE_TXT_VALUE_SIZE: usize = 64;
+
+/// Key/value pairs of the TXT record of a discovered service (RFC 6763 section 6),
+/// e.g. `tls=1` or `user=monitor`. Keys are compared ignoring case, a key
+/// without `=` is a flag with an empty value.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceTxt {
+    entries: Vec<(String<SERVICE_TXT_KEY_SIZE>, String<SERVICE_TXT_VALUE_SIZE>), SERVICE_TXT_MAX_ENTRIES>,
+}
+
+impl ServiceTxt {
+    /// Parses the entries of a TXT record. Entries that do not fit, repeated
+    /// keys and entries with an empty key are skipped.
+    pub fn parse(record: &DnsTxt<'_>) -> Self {
+        let mut txt = Self::default();
+        for entry in record.entries() {
+            let Ok(entry) = core::str::from_utf8(entry) else {
+                continue;
+            };
+            let (key, value) = entry.split_once('=').unwrap_or((entry, ""));
+            if key.is_empty() || txt.get(key).is_some() {
+                continue;
+            }
+            let (Ok(key), Ok(value)) = (String::try_from(key), String::try_from(value)) else {
+                info!("mDNS: Skipping TXT entry {:?}, too long", entry);
+                continue;
+            };
+            if txt.entries.push((key, value)).is_err() {
+                info!("mDNS: Skipping TXT entries beyond {}", SERVICE_TXT_MAX_ENTRIES);
+                break;
+            }
+        }
+        txt
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(entry_key, _)| entry_key.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether `key` is present as a flag or set to `1`, `true` or `yes`.
+    pub fn is_enabled(&self, key: &str) -> bool {
+        matches!(self.get(key), Some(value)
+            if value.is_empty() || ["1", "true", "yes"].iter().any(|yes| value.eq_ignore_ascii_case(yes)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Address of a service instance, with its SRV priority and weight and the
+/// options it advertises in its TXT record.
+#[derive(Debug, Clone)]
+pub struct ServiceEndpoint {
+    pub address: IpAddr,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+    pub txt: ServiceTxt,
+}
+
+impl ServiceEndpoint {
+    /// Endpoint without SRV or TXT data, e.g. a broker configured by hand.
+    pub fn new(address: IpAddr, port: u16) -> Self {
+        Self {
+            address,
+            port,
+            priority: 0,
+            weight: 0,
+            txt: ServiceTxt::default(),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+/// Orders `endpoints` as RFC 2782 prescribes for SRV targets: by ascending
+/// priority, and within a priority by repeatedly picking one of the remaining
+/// endpoints at random, with a probability proportional to its weight.
+pub fn order_by_priority(endpoints: &mut [ServiceEndpoint], mut random: impl FnMut() -> u32) {
+    // Zero weights first, so they only get picked when the draw is 0
+    endpoints.sort_unstable_by_key(|endpoint| (endpoint.priority, endpoint.weight != 0));
+
+    let mut start = 0;
+    while start < endpoints.len() {
+        let priority = endpoints[start].priority;
+        let end = endpoints[start..].iter()
+            .position(|endpoint| endpoint.priority != priority)
+            .map_or(endpoints.len(), |length| start + length);
+
+        for position in start..end {
+            let total_weight: u32 = endpoints[position..end].iter().map(|endpoint| endpoint.weight as u32).sum();
+            let draw = random() % (total_weight + 1);
+            let mut running_weight = 0;
+            for candidate in position..end {
+                running_weight += endpoints[candidate].weight as u32;
+                if running_weight >= draw {
+                    endpoints.swap(position, candidate);
+                    break;
+                }
+            }
+        }
+        start = end;
+    }
+}
+
+/// Broker set by hand with `mqtt.broker`, as an address or a host name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfiguredBroker<'c> {
+    Address(SocketAddr),
+    Host { name: &'c str, port: u16 },
+}
+
+/// Lookups the discovery chain is made of. Implementations log their errors
+/// and return nothing when a lookup fails.
+#[allow(async_fn_in_trait)]
+pub trait BrokerLookup {
+    /// Instances of `service` (e.g. `_mqtt._tcp.local`) found with mDNS, in
+    /// the order they should be tried.
+    async fn browse_mdns(&mut self, service: &'static str) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>;
+
+    /// Instances of `service` (e.g. `_mqtt._tcp.example.com`) found with
+    /// unicast DNS-SD, in the order they should be tried.
+    async fn browse_dns_sd(&mut self, service: &str) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>;
+
+    async fn resolve_host(&mut self, host: &str, port: u16) -> Option<IpAddr>;
+}
+
+/// Where the MQTT brokers are looked up: mDNS first, then unicast DNS-SD when
+/// mDNS finds none (e.g. multicast is filtered), followed by the configured
+/// broker as a last resort.
+pub struct BrokerDiscovery<'c> {
+    /// Browsed with mDNS, discovery is disabled when empty.
+    pub service: &'static str,
+    pub unicast_service: Option<String<UNICAST_SERVICE_SIZE>>,
+    pub configured_broker: Option<ConfiguredBroker<'c>>,
+}
+
+impl BrokerDiscovery<'_> {
+    /// Brokers in the order they should be tried. Discovered brokers that
+    /// require TLS are skipped, it is not supported. Empty when no broker
+    /// could be found.
+    pub async fn discover(&self, lookup: &mut impl BrokerLookup) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+        let mut endpoints = Vec::new();
+        if !self.service.is_empty() {
+            endpoints = without_tls(lookup.browse_mdns(self.service).await);
+            info!("Discovery: Found {} broker(s) using mDNS", endpoints.len());
+        }
+
+        if let Some(unicast_service) = self.unicast_service.as_ref().filter(|_| endpoints.is_empty()) {
+            endpoints = without_tls(lookup.browse_dns_sd(unicast_service).await);
+            info!("Discovery: Found {} broker(s) using DNS-SD", endpoints.len());
+        }
+
+        let configured_broker = match self.configured_broker {
+            Some(ConfiguredBroker::Address(address)) => Some(address),
+            Some(ConfiguredBroker::Host { name, port }) => {
+                lookup.resolve_host(name, port).await.map(|address| SocketAddr::new(address, port))
+            }
+            None => None,
+        };
+        if let Some(address) = configured_broker {
+            if endpoints.iter().all(|endpoint| endpoint.socket_addr() != address) {
+                info!("Discovery: Using configured broker {}", address);
+                if endpoints.push(ServiceEndpoint::new(address.ip(), address.port())).is_err() {
+                    info!("Discovery: Too many brokers discovered, not falling back to the configured one");
+                }
+            }
+        }
+        endpoints
+    }
+}
+
+/// `endpoints` without those that require TLS (`tls=1`).
+fn without_tls(
+    mut endpoints: Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>,
+) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+    endpoints.retain(|endpoint| {
+        let tls = endpoint.txt.is_enabled("tls");
+        if tls {
+            error!("Discovery: Skipping {}, it requires TLS which is not supported", endpoint.socket_addr());
+        }
+        !tls
+    });
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::net::Ipv4Addr;
+    use embassy_futures::block_on;
+
+    /// Answers from fixed results and records which lookups were made.
+    #[derive(Default)]
+    struct FakeLookup {
+        mdns: Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>,
+        dns_sd: Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>,
+        host: Option<IpAddr>,
+        browsed_mdns: bool,
+        browsed_dns_sd: Option<String<UNICAST_SERVICE_SIZE>>,
+        resolved_host: Option<(String<64>, u16)>,
+    }
+
+    impl BrokerLookup for FakeLookup {
+        async fn browse_mdns(&mut self, _service: &'static str) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+            self.browsed_mdns = true;
+            self.mdns.clone()
+        }
+
+        async fn browse_dns_sd(&mut self, service: &str) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+            self.browsed_dns_sd = String::try_from(service).ok();
+            self.dns_sd.clone()
+        }
+
+        async fn resolve_host(&mut self, host: &str, port: u16) -> Option<IpAddr> {
+            self.resolved_host = Some((String::try_from(host).unwrap(), port));
+            self.host
+        }
+    }
+
+    fn endpoint(last_octet: u8, txt: &[u8]) -> ServiceEndpoint {
+        ServiceEndpoint {
+            txt: ServiceTxt::parse(&DnsTxt::new(txt)),
+            ..ServiceEndpoint::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, last_octet)), 1883)
+        }
+    }
+
+    fn addresses(endpoints: &[ServiceEndpoint]) -> std::vec::Vec<SocketAddr> {
+        endpoints.iter().map(ServiceEndpoint::socket_addr).collect()
+    }
+
+    fn discovery(configured_broker: Option<ConfiguredBroker<'static>>) -> BrokerDiscovery<'static> {
+        BrokerDiscovery {
+            service: "_mqtt._tcp.local",
+            unicast_service: Some(String::try_from("_mqtt._tcp.example.com").unwrap()),
+            configured_broker,
+        }
+    }
+
+    #[test]
+    fn mdns_brokers_come_first_and_skip_dns_sd() {
+        let configured: SocketAddr = "10.0.0.1:1883".parse().unwrap();
+        let mut lookup = FakeLookup::default();
+        lookup.mdns.push(endpoint(20, b"")).unwrap();
+        lookup.mdns.push(endpoint(21, b"")).unwrap();
+        lookup.dns_sd.push(endpoint(30, b"")).unwrap();
+
+        let endpoints = block_on(discovery(Some(ConfiguredBroker::Address(configured))).discover(&mut lookup));
+
+        assert_eq!(addresses(&endpoints), [endpoint(20, b"").socket_addr(), endpoint(21, b"").socket_addr(), configured]);
+        assert_eq!(lookup.browsed_dns_sd, None);
+    }
+
+    #[test]
+    fn dns_sd_is_browsed_when_mdns_finds_nothing() {
+        let mut lookup = FakeLookup::default();
+        lookup.dns_sd.push(endpoint(30, b"")).unwrap();
+
+        let endpoints = block_on(discovery(None).discover(&mut lookup));
+
+        assert!(lookup.browsed_mdns);
+        assert_eq!(lookup.browsed_dns_sd.as_deref(), Some("_mqtt._tcp.example.com"));
+        assert_eq!(addresses(&endpoints), [endpoint(30, b"").socket_addr()]);
+    }
+
+    #[test]
+    fn brokers_requiring_tls_are_skipped() {
+        let mut lookup = FakeLookup::default();
+        lookup.mdns.push(endpoint(20, b"\x05tls=1")).unwrap();
+        lookup.dns_sd.push(endpoint(30, b"\x05tls=0")).unwrap();
+        lookup.dns_sd.push(endpoint(31, b"\x03tls")).unwrap();
+
+        let endpoints = block_on(discovery(None).discover(&mut lookup));
+
+        assert_eq!(addresses(&endpoints), [endpoint(30, b"").socket_addr()]);
+    }
+
+    #[test]
+    fn configured_host_is_resolved_and_not_repeated() {
+        let mut lookup = FakeLookup::default();
+        lookup.mdns.push(endpoint(20, b"")).unwrap();
+        lookup.host = Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)));
+        let configured = ConfiguredBroker::Host { name: "mqtt.example.com", port: 1883 };
+
+        let endpoints = block_on(discovery(Some(configured)).discover(&mut lookup));
+
+        assert_eq!(lookup.resolved_host, Some((String::try_from("mqtt.example.com").unwrap(), 1883)));
+        assert_eq!(addresses(&endpoints), [endpoint(20, b"").socket_addr()]);
+    }
+
+    #[test]
+    fn failed_lookups_fall_back_to_the_configured_broker() {
+        // What the network lookups return when a browse or a resolution fails
+        let configured: SocketAddr = "10.0.0.1:1883".parse().unwrap();
+        let mut lookup = FakeLookup::default();
+
+        let endpoints = block_on(discovery(Some(ConfiguredBroker::Address(configured))).discover(&mut lookup));
+
+        assert!(lookup.browsed_mdns);
+        assert!(lookup.browsed_dns_sd.is_some());
+        assert_eq!(addresses(&endpoints), [configured]);
+    }
+
+    #[test]
+    fn nothing_is_found_when_the_configured_host_does_not_resolve() {
+        let mut lookup = FakeLookup::default();
+        let configured = ConfiguredBroker::Host { name: "mqtt.example.com", port: 1883 };
+
+        let endpoints = block_on(discovery(Some(configured)).discover(&mut lookup));
+
+        assert!(lookup.resolved_host.is_some());
+        assert!(endpoints.is_empty());
+    }
+
+    #[test]
+    fn disabled_discovery_only_uses_the_configured_broker() {
+        let configured: SocketAddr = "10.0.0.1:1883".parse().unwrap();
+        let mut lookup = FakeLookup::default();
+        lookup.mdns.push(endpoint(20, b"")).unwrap();
+        let discovery = BrokerDiscovery {
+            service: "",
+            unicast_service: None,
+            configured_broker: Some(ConfiguredBroker::Address(configured)),
+        };
+
+        let endpoints = block_on(discovery.discover(&mut lookup));
+
+        assert!(!lookup.browsed_mdns);
+        assert_eq!(addresses(&endpoints), [configured]);
+    }
+}
@@ -0,0 +1,810 @@
+//! Allocation-free parser and writer for DNS messages (RFC 1035), including
+//! the mDNS uses of the class top bit (RFC 6762). Only depends on `core` and
+//! `heapless`, so it can be exercised on the host without hardware.
+
+use core::fmt;
+use core::net::{Ipv4Addr, Ipv6Addr};
+use heapless::String;
+
+pub const DNS_HEADER_SIZE: usize = 12;
+/// Header flag asking a unicast server to resolve the question recursively.
+pub const DNS_FLAG_RECURSION_DESIRED: u16 = 0x0100;
+/// Longest name in wire format, length bytes included (RFC 1035 section 2.3.4).
+const DNS_MAX_NAME_SIZE: usize = 255;
+
+pub const DNS_CLASS_IN: u16 = 1;
+/// Top bit of the class of a question (unicast response wanted) or record (cache flush).
+pub const MDNS_CLASS_FLAG: u16 = 0x8000;
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_PTR: u16 = 12;
+pub const DNS_TYPE_TXT: u16 = 16;
+pub const DNS_TYPE_AAAA: u16 = 28;
+pub const DNS_TYPE_SRV: u16 = 33;
+pub const DNS_TYPE_ANY: u16 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The message ends in the middle of a field.
+    Truncated,
+    /// A label length uses one of the reserved `01` or `10` prefixes.
+    InvalidLabel,
+    /// A compression pointer does not point before the name it is part of,
+    /// which could otherwise make it loop forever.
+    InvalidPointer,
+    NameTooLong,
+    /// The RDATA does not match the layout of its record type.
+    InvalidRecordData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnsHeader {
+    pub id: u16,
+    pub flags: u16,
+    pub question_count: u16,
+    pub answer_count: u16,
+    pub authority_count: u16,
+    pub additional_count: u16,
+}
+
+impl DnsHeader {
+    pub fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsSection {
+    Answer,
+    Authority,
+    Additional,
+}
+
+/// A DNS message borrowed from a receive buffer. Questions and records are
+/// decoded on demand while iterating, nothing is copied.
+#[derive(Clone, Copy)]
+pub struct DnsMessage<'m> {
+    data: &'m [u8],
+    header: DnsHeader,
+}
+
+impl<'m> DnsMessage<'m> {
+    pub fn parse(data: &'m [u8]) -> Result<Self, DnsError> {
+        let field = |index: usize| read_u16(data, 2 * index);
+        let header = DnsHeader {
+            id: field(0)?,
+            flags: field(1)?,
+            question_count: field(2)?,
+            answer_count: field(3)?,
+            authority_count: field(4)?,
+            additional_count: field(5)?,
+        };
+        Ok(Self { data, header })
+    }
+
+    pub fn header(&self) -> &DnsHeader {
+        &self.header
+    }
+
+    pub fn questions(&self) -> DnsQuestions<'m> {
+        DnsQuestions {
+            data: self.data,
+            offset: DNS_HEADER_SIZE,
+            remaining: self.header.question_count,
+        }
+    }
+
+    /// Records of the answer, authority and additional sections, in that order.
+    /// Iteration stops after the first error.
+    pub fn records(&self) -> DnsRecords<'m> {
+        let mut questions = self.questions();
+        let mut error = None;
+        for question in questions.by_ref() {
+            if let Err(e) = question {
+                error = Some(e);
+            }
+        }
+        DnsRecords {
+            data: self.data,
+            offset: questions.offset,
+            remaining: [
+                self.header.answer_count,
+                self.header.authority_count,
+                self.header.additional_count,
+            ],
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DnsQuestion<'m> {
+    pub name: DnsName<'m>,
+    pub record_type: u16,
+    /// Class without the unicast-response bit.
+    pub class: u16,
+    pub unicast_response: bool,
+}
+
+pub struct DnsQuestions<'m> {
+    data: &'m [u8],
+    offset: usize,
+    remaining: u16,
+}
+
+impl<'m> Iterator for DnsQuestions<'m> {
+    type Item = Result<DnsQuestion<'m>, DnsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        match parse_question(self.data, self.offset) {
+            Ok((question, next_offset)) => {
+                self.offset = next_offset;
+                Some(Ok(question))
+            }
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DnsRecord<'m> {
+    pub section: DnsSection,
+    pub name: DnsName<'m>,
+    pub record_type: u16,
+    /// Class without the cache-flush bit.
+    pub class: u16,
+    pub cache_flush: bool,
+    pub ttl: u32,
+    pub data: DnsRecordData<'m>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DnsRecordData<'m> {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(DnsName<'m>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsName<'m>,
+    },
+    Txt(DnsTxt<'m>),
+    /// RDATA of any other record type.
+    Other(&'m [u8]),
+}
+
+pub struct DnsRecords<'m> {
+    data: &'m [u8],
+    offset: usize,
+    /// Records left in the answer, authority and additional sections.
+    remaining: [u16; 3],
+    /// Error met while skipping the questions, reported by the first `next`.
+    error: Option<DnsError>,
+}
+
+impl<'m> Iterator for DnsRecords<'m> {
+    type Item = Result<DnsRecord<'m>, DnsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.remaining = [0; 3];
+            return Some(Err(e));
+        }
+
+        let (index, section) = [DnsSection::Answer, DnsSection::Authority, DnsSection::Additional]
+            .into_iter()
+            .enumerate()
+            .find(|(index, _)| self.remaining[*index] > 0)?;
+        self.remaining[index] -= 1;
+
+        match parse_record(self.data, self.offset, section) {
+            Ok((record, next_offset)) => {
+                self.offset = next_offset;
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.remaining = [0; 3];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A name inside a message, possibly compressed. It was validated when its
+/// record was parsed, so walking its labels always terminates.
+#[derive(Clone, Copy)]
+pub struct DnsName<'m> {
+    data: &'m [u8],
+    offset: usize,
+}
+
+impl<'m> DnsName<'m> {
+    pub fn labels(&self) -> DnsLabels<'m> {
+        DnsLabels {
+            data: self.data,
+            position: self.offset,
+        }
+    }
+
+    /// Whether this is the dotted `name`, e.g. `_mqtt._tcp.local`, ignoring
+    /// ASCII case. A trailing dot is allowed.
+    pub fn matches(&self, name: &str) -> bool {
+        labels_match(self.labels(), name)
+    }
+
+    /// Whether this is a name strictly below the dotted `domain`, e.g. an
+    /// instance `Broker._mqtt._tcp.local` of `_mqtt._tcp.local`.
+    pub fn is_subdomain_of(&self, domain: &str) -> bool {
+        let domain_labels = dotted_labels(domain).count();
+        let name_labels = self.labels().count();
+        if name_labels <= domain_labels {
+            return false;
+        }
+
+        let mut labels = self.labels();
+        labels.by_ref().take(name_labels - domain_labels).for_each(drop);
+        labels_match(labels, domain)
+    }
+
+    /// Whether both names have the same labels, ignoring ASCII case.
+    pub fn same_as(&self, other: &DnsName<'_>) -> bool {
+        let mut labels = self.labels();
+        let mut other_labels = other.labels();
+        loop {
+            match (labels.next(), other_labels.next()) {
+                (Some(label), Some(other_label)) if label.eq_ignore_ascii_case(other_label) => continue,
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    /// The dotted name, or `None` if it does not fit in `N` bytes.
+    pub fn to_dotted_string<const N: usize>(&self) -> Option<String<N>> {
+        let mut name = String::new();
+        fmt::write(&mut name, format_args!("{}", self)).ok()?;
+        Some(name)
+    }
+}
+
+impl fmt::Display for DnsName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, label) in self.labels().enumerate() {
+            if index > 0 {
+                f.write_str(".")?;
+            }
+            for &byte in label {
+                fmt::Write::write_char(f, byte as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DnsName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Labels of a `DnsName`, following compression pointers.
+pub struct DnsLabels<'m> {
+    data: &'m [u8],
+    position: usize,
+}
+
+impl<'m> Iterator for DnsLabels<'m> {
+    type Item = &'m [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let length = *self.data.get(self.position)? as usize;
+            match length & 0xC0 {
+                0x00 if length == 0 => return None,
+                0x00 => {
+                    let label = self.data.get(self.position + 1..self.position + 1 + length)?;
+                    self.position += 1 + length;
+                    return Some(label);
+                }
+                0xC0 => self.position = (read_u16(self.data, self.position).ok()? & 0x3FFF) as usize,
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Character strings of a TXT record (RFC 6763 section 6), e.g. `tls=1`.
+#[derive(Debug, Clone, Copy)]
+pub struct DnsTxt<'m> {
+    data: &'m [u8],
+}
+
+impl<'m> DnsTxt<'m> {
+    /// TXT RDATA received earlier, e.g. kept in a cache.
+    pub fn new(data: &'m [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn as_bytes(&self) -> &'m [u8] {
+        self.data
+    }
+
+    /// The entries, stopping at one whose length overflows the RDATA.
+    pub fn entries(&self) -> impl Iterator<Item = &'m [u8]> {
+        let data = self.data;
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let length = *data.get(offset)? as usize;
+            let entry = data.get(offset + 1..offset + 1 + length)?;
+            offset += 1 + length;
+            Some(entry)
+        })
+    }
+}
+
+/// Writes DNS messages without name compression.
+pub struct DnsWriter<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+}
+
+impl<'b> DnsWriter<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self { buffer, position: 0 }
+    }
+
+    /// Length written so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> Option<()> {
+        let end = self.position + data.len();
+        self.buffer.get_mut(self.position..end)?.copy_from_slice(data);
+        self.position = end;
+        Some(())
+    }
+
+    pub fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes the name made of `parts`, each of which may hold several dotted labels.
+    pub fn name(&mut self, parts: &[&str]) -> Option<()> {
+        for label in parts.iter().flat_map(|part| part.split('.')) {
+            if label.is_empty() || label.len() > 63 {
+                return None;
+            }
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Writes the name, type, class and TTL of a record. `unique` records set the
+    /// cache-flush bit, since no other host answers for them.
+    pub fn record(&mut self, name: &[&str], record_type: u16, unique: bool, ttl: u32) -> Option<()> {
+        self.name(name)?;
+        self.u16(record_type)?;
+        self.u16(if unique { DNS_CLASS_IN | MDNS_CLASS_FLAG } else { DNS_CLASS_IN })?;
+        self.u32(ttl)
+    }
+
+    /// Writes a question for `name` in class IN.
+    pub fn question(&mut self, name: &[&str], record_type: u16) -> Option<()> {
+        self.name(name)?;
+        self.u16(record_type)?;
+        self.u16(DNS_CLASS_IN)
+    }
+
+    /// Reserves the RDLENGTH field, returning its position for `end_rdata`.
+    pub fn begin_rdata(&mut self) -> Option<usize> {
+        let position = self.position;
+        self.u16(0)?;
+        Some(position)
+    }
+
+    pub fn end_rdata(&mut self, length_position: usize) -> Option<()> {
+        let length = u16::try_from(self.position - length_position - 2).ok()?;
+        self.buffer[length_position..length_position + 2].copy_from_slice(&length.to_be_bytes());
+        Some(())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, DnsError> {
+    let bytes = data.get(offset..offset + 2).ok_or(DnsError::Truncated)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, DnsError> {
+    let bytes = data.get(offset..offset + 4).ok_or(DnsError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Validates the name at `offset` and returns the offset following it. Every
+/// compression pointer must point before the part of the name read so far,
+/// so following them always terminates.
+fn skip_name(data: &[u8], offset: usize) -> Result<usize, DnsError> {
+    let mut position = offset;
+    let mut segment_start = offset;
+    let mut end = None;
+    let mut size = 1;
+
+    loop {
+        let length = *data.get(position).ok_or(DnsError::Truncated)? as usize;
+        match length & 0xC0 {
+            0x00 if length == 0 => return Ok(end.unwrap_or(position + 1)),
+            0x00 => {
+                size += 1 + length;
+                if size > DNS_MAX_NAME_SIZE {
+                    return Err(DnsError::NameTooLong);
+                }
+                position += 1 + length;
+            }
+            0xC0 => {
+                let target = (read_u16(data, position)? & 0x3FFF) as usize;
+                if target < DNS_HEADER_SIZE || target >= segment_start {
+                    return Err(DnsError::InvalidPointer);
+                }
+                end.get_or_insert(position + 2);
+                segment_start = target;
+                position = target;
+            }
+            _ => return Err(DnsError::InvalidLabel),
+        }
+    }
+}
+
+fn parse_question(data: &[u8], offset: usize) -> Result<(DnsQuestion<'_>, usize), DnsError> {
+    let name_end = skip_name(data, offset)?;
+    let record_type = read_u16(data, name_end)?;
+    let class = read_u16(data, name_end + 2)?;
+    let question = DnsQuestion {
+        name: DnsName { data, offset },
+        record_type,
+        class: class & !MDNS_CLASS_FLAG,
+        unicast_response: class & MDNS_CLASS_FLAG != 0,
+    };
+    Ok((question, name_end + 4))
+}
+
+fn parse_record(data: &[u8], offset: usize, section: DnsSection) -> Result<(DnsRecord<'_>, usize), DnsError> {
+    let name_end = skip_name(data, offset)?;
+    let record_type = read_u16(data, name_end)?;
+    let class = read_u16(data, name_end + 2)?;
+    let ttl = read_u32(data, name_end + 4)?;
+    let rdata_offset = name_end + 10;
+    let rdata_end = rdata_offset + read_u16(data, name_end + 8)? as usize;
+    let rdata = data.get(rdata_offset..rdata_end).ok_or(DnsError::Truncated)?;
+
+    let record_data = match record_type {
+        DNS_TYPE_A => DnsRecordData::A(Ipv4Addr::from(
+            <[u8; 4]>::try_from(rdata).map_err(|_| DnsError::InvalidRecordData)?)),
+        DNS_TYPE_AAAA => DnsRecordData::Aaaa(Ipv6Addr::from(
+            <[u8; 16]>::try_from(rdata).map_err(|_| DnsError::InvalidRecordData)?)),
+        DNS_TYPE_PTR => DnsRecordData::Ptr(parse_rdata_name(data, rdata_offset, rdata_end)?),
+        DNS_TYPE_SRV => {
+            if rdata.len() < 7 {
+                return Err(DnsError::InvalidRecordData);
+            }
+            DnsRecordData::Srv {
+                priority: read_u16(rdata, 0)?,
+                weight: read_u16(rdata, 2)?,
+                port: read_u16(rdata, 4)?,
+                target: parse_rdata_name(data, rdata_offset + 6, rdata_end)?,
+            }
+        }
+        DNS_TYPE_TXT => DnsRecordData::Txt(DnsTxt { data: rdata }),
+        _ => DnsRecordData::Other(rdata),
+    };
+
+    let record = DnsRecord {
+        section,
+        name: DnsName { data, offset },
+        record_type,
+        class: class & !MDNS_CLASS_FLAG,
+        cache_flush: class & MDNS_CLASS_FLAG != 0,
+        ttl,
+        data: record_data,
+    };
+    Ok((record, rdata_end))
+}
+
+/// Name inside the RDATA ending at `rdata_end`, which it must not overflow.
+fn parse_rdata_name(data: &[u8], offset: usize, rdata_end: usize) -> Result<DnsName<'_>, DnsError> {
+    if skip_name(data, offset)? > rdata_end {
+        return Err(DnsError::InvalidRecordData);
+    }
+    Ok(DnsName { data, offset })
+}
+
+/// Labels of a dotted name, without the trailing dot. The root is `""`.
+fn dotted_labels(name: &str) -> impl Iterator<Item = &str> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    name.split('.').filter(move |_| !name.is_empty())
+}
+
+fn labels_match(mut labels: DnsLabels<'_>, name: &str) -> bool {
+    let mut expected = dotted_labels(name);
+    loop {
+        match (labels.next(), expected.next()) {
+            (Some(label), Some(expected_label)) if label.eq_ignore_ascii_case(expected_label.as_bytes()) => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response to a `_mqtt._tcp.local` PTR query, laid out as Avahi sends it for
+    /// `avahi-publish -s Broker _mqtt._tcp 1883 user=sensors prefix=home`: the
+    /// shared PTR record as the answer, the unique records as additionals, all
+    /// names compressed against the first one.
+    const AVAHI_RESPONSE: &[u8] = &[
+        // header: id 0, response + authoritative, 0 questions, 1 answer, 4 additional
+        0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04,
+        // _mqtt._tcp.local
+        0x05, 0x5f, 0x6d, 0x71, 0x74, 0x74, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x05, 0x6c, 0x6f, 0x63, 0x61,
+        0x6c, 0x00,
+        // PTR IN, TTL 4500
+        0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94,
+        // RDLENGTH 9: Broker, then a pointer to the service name
+        0x00, 0x09, 0x06, 0x42, 0x72, 0x6f, 0x6b, 0x65, 0x72, 0xc0, 0x0c,
+        // Broker._mqtt._tcp.local SRV IN flush, TTL 120
+        0xc0, 0x28, 0x00, 0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78,
+        // RDLENGTH 16: priority 0, weight 0, port 1883, target mqtt-pi.local
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x07, 0x5b, 0x07, 0x6d, 0x71, 0x74, 0x74, 0x2d, 0x70, 0x69,
+        0xc0, 0x17,
+        // Broker._mqtt._tcp.local TXT IN flush, TTL 4500
+        0xc0, 0x28, 0x00, 0x10, 0x80, 0x01, 0x00, 0x00, 0x11, 0x94,
+        // RDLENGTH 25: user=sensors, prefix=home
+        0x00, 0x19, 0x0c, 0x75, 0x73, 0x65, 0x72, 0x3d, 0x73, 0x65, 0x6e, 0x73, 0x6f, 0x72, 0x73, 0x0b,
+        0x70, 0x72, 0x65, 0x66, 0x69, 0x78, 0x3d, 0x68, 0x6f, 0x6d, 0x65,
+        // mqtt-pi.local A IN flush, TTL 120: 192.168.1.20
+        0xc0, 0x43, 0x00, 0x01, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8, 0x01, 0x14,
+        // mqtt-pi.local AAAA IN flush, TTL 120: fd00::20
+        0xc0, 0x43, 0x00, 0x1c, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x10, 0xfd, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20,
+    ];
+
+    /// Legacy unicast response of macOS mDNSResponder to an ANY query for its host
+    /// name: the question repeated, the addresses without the cache-flush bit and
+    /// with a 10 s TTL, and the NSEC record it adds for the types it has.
+    const BONJOUR_RESPONSE: &[u8] = &[
+        // header: id 0x2f1c, response + authoritative, 1 question, 2 answers, 1 additional
+        0x2f, 0x1c, 0x84, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01,
+        // Office-Mac.local
+        0x0a, 0x4f, 0x66, 0x66, 0x69, 0x63, 0x65, 0x2d, 0x4d, 0x61, 0x63, 0x05, 0x6c, 0x6f, 0x63, 0x61,
+        0x6c, 0x00,
+        // ANY IN, unicast response wanted
+        0x00, 0xff, 0x80, 0x01,
+        // Office-Mac.local A IN, TTL 10: 10.0.0.7
+        0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x04, 0x0a, 0x00, 0x00, 0x07,
+        // Office-Mac.local AAAA IN, TTL 10: fe80::1c2b:3aff:fe4d:5e6f
+        0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x10, 0xfe, 0x80, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x1c, 0x2b, 0x3a, 0xff, 0xfe, 0x4d, 0x5e, 0x6f,
+        // Office-Mac.local NSEC IN flush, TTL 120: A and AAAA exist
+        0xc0, 0x0c, 0x00, 0x2f, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x08, 0xc0, 0x0c, 0x00, 0x04,
+        0x40, 0x00, 0x00, 0x08,
+    ];
+
+    fn parse_records(data: &[u8]) -> Vec<Result<DnsRecord<'_>, DnsError>> {
+        DnsMessage::parse(data).unwrap().records().collect()
+    }
+
+    /// A query for `A` records with a single question whose name is `name`.
+    fn query(name: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        data.extend_from_slice(name);
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        data
+    }
+
+    fn first_question(data: &[u8]) -> Result<DnsQuestion<'_>, DnsError> {
+        DnsMessage::parse(data).unwrap().questions().next().unwrap()
+    }
+
+    #[test]
+    fn parses_avahi_response() {
+        let message = DnsMessage::parse(AVAHI_RESPONSE).unwrap();
+        assert!(message.header().is_response());
+        assert_eq!(message.header().answer_count, 1);
+        assert_eq!(message.header().additional_count, 4);
+        assert!(message.questions().next().is_none());
+
+        let records = parse_records(AVAHI_RESPONSE);
+        assert_eq!(records.len(), 5);
+        let records: Vec<_> = records.into_iter().map(Result::unwrap).collect();
+
+        let ptr = &records[0];
+        assert_eq!(ptr.section, DnsSection::Answer);
+        assert!(ptr.name.matches("_mqtt._tcp.local"));
+        assert_eq!(ptr.record_type, DNS_TYPE_PTR);
+        assert_eq!(ptr.class, DNS_CLASS_IN);
+        assert!(!ptr.cache_flush);
+        assert_eq!(ptr.ttl, 4500);
+        let DnsRecordData::Ptr(instance) = ptr.data else { panic!("{:?}", ptr.data) };
+        assert!(instance.matches("broker._MQTT._tcp.local."));
+        assert!(instance.is_subdomain_of("_mqtt._tcp.local"));
+        assert!(!ptr.name.is_subdomain_of("_mqtt._tcp.local"));
+
+        let srv = &records[1];
+        assert_eq!(srv.section, DnsSection::Additional);
+        assert!(srv.name.same_as(&instance));
+        assert!(srv.cache_flush);
+        assert_eq!(srv.class, DNS_CLASS_IN);
+        assert_eq!(srv.ttl, 120);
+        let DnsRecordData::Srv { priority, weight, port, target } = srv.data else { panic!("{:?}", srv.data) };
+        assert_eq!((priority, weight, port), (0, 0, 1883));
+        assert_eq!(target.to_dotted_string::<32>().unwrap().as_str(), "mqtt-pi.local");
+        assert!(target.to_dotted_string::<8>().is_none());
+
+        let txt = &records[2];
+        assert!(txt.cache_flush);
+        let DnsRecordData::Txt(entries) = txt.data else { panic!("{:?}", txt.data) };
+        let entries: Vec<_> = entries.entries().collect();
+        assert_eq!(entries, [&b"user=sensors"[..], b"prefix=home"]);
+
+        let a = &records[3];
+        assert!(a.name.same_as(&target));
+        assert!(a.cache_flush);
+        assert!(matches!(a.data, DnsRecordData::A(address) if address == Ipv4Addr::new(192, 168, 1, 20)));
+
+        let aaaa = &records[4];
+        assert!(aaaa.name.matches("mqtt-pi.local"));
+        assert_eq!(aaaa.record_type, DNS_TYPE_AAAA);
+        assert!(matches!(aaaa.data, DnsRecordData::Aaaa(address) if address == Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x20)));
+    }
+
+    #[test]
+    fn parses_bonjour_legacy_response() {
+        let message = DnsMessage::parse(BONJOUR_RESPONSE).unwrap();
+        assert_eq!(message.header().id, 0x2f1c);
+
+        let questions: Vec<_> = message.questions().map(Result::unwrap).collect();
+        assert_eq!(questions.len(), 1);
+        assert!(questions[0].name.matches("office-mac.local"));
+        assert_eq!(questions[0].record_type, DNS_TYPE_ANY);
+        assert_eq!(questions[0].class, DNS_CLASS_IN);
+        assert!(questions[0].unicast_response);
+
+        let records: Vec<_> = parse_records(BONJOUR_RESPONSE).into_iter().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|record| record.name.same_as(&questions[0].name)));
+
+        assert_eq!(records[0].section, DnsSection::Answer);
+        assert!(!records[0].cache_flush);
+        assert_eq!(records[0].ttl, 10);
+        assert!(matches!(records[0].data, DnsRecordData::A(address) if address == Ipv4Addr::new(10, 0, 0, 7)));
+        assert!(matches!(records[1].data,
+            DnsRecordData::Aaaa(address) if address == Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1c2b, 0x3aff, 0xfe4d, 0x5e6f)));
+
+        assert_eq!(records[2].section, DnsSection::Additional);
+        assert!(records[2].cache_flush);
+        assert_eq!(records[2].record_type, 47);
+        assert!(matches!(records[2].data, DnsRecordData::Other(data) if data.len() == 8));
+    }
+
+    #[test]
+    fn rejects_self_pointing_pointer() {
+        assert_eq!(first_question(&query(&[0xc0, 0x0c])).unwrap_err(), DnsError::InvalidPointer);
+    }
+
+    #[test]
+    fn rejects_forward_pointer() {
+        assert_eq!(first_question(&query(&[0xc0, 0x0e])).unwrap_err(), DnsError::InvalidPointer);
+        // A pointer back into the label it follows would loop as well.
+        assert_eq!(first_question(&query(&[0x01, b'a', 0xc0, 0x0d])).unwrap_err(), DnsError::InvalidPointer);
+    }
+
+    #[test]
+    fn rejects_pointer_into_header() {
+        assert_eq!(first_question(&query(&[0x01, b'a', 0xc0, 0x02])).unwrap_err(), DnsError::InvalidPointer);
+    }
+
+    #[test]
+    fn rejects_reserved_label_types() {
+        assert_eq!(first_question(&query(&[0x41, b'a', 0x00])).unwrap_err(), DnsError::InvalidLabel);
+        assert_eq!(first_question(&query(&[0x81, b'a', 0x00])).unwrap_err(), DnsError::InvalidLabel);
+    }
+
+    #[test]
+    fn rejects_name_longer_than_255_bytes() {
+        let name = |lengths: &[u8]| {
+            let mut name = Vec::new();
+            for &length in lengths {
+                name.push(length);
+                name.extend(core::iter::repeat_n(b'a', length as usize));
+            }
+            name.push(0);
+            name
+        };
+
+        // 255 bytes with the length bytes and the root label.
+        assert!(first_question(&query(&name(&[63, 63, 63, 61]))).is_ok());
+        assert_eq!(first_question(&query(&name(&[63, 63, 63, 62]))).unwrap_err(), DnsError::NameTooLong);
+    }
+
+    #[test]
+    fn rejects_truncated_rdata() {
+        let records = parse_records(&AVAHI_RESPONSE[..AVAHI_RESPONSE.len() - 3]);
+        assert_eq!(records.len(), 5);
+        assert!(records[..4].iter().all(Result::is_ok));
+        assert_eq!(records[4].as_ref().unwrap_err(), &DnsError::Truncated);
+
+        assert_eq!(DnsMessage::parse(&AVAHI_RESPONSE[..5]).err(), Some(DnsError::Truncated));
+    }
+
+    #[test]
+    fn rejects_rdata_not_matching_its_type() {
+        // The A record claims 3 bytes of address, which still fit in the message.
+        let mut data = AVAHI_RESPONSE.to_vec();
+        let a_rdlength = AVAHI_RESPONSE.len() - 28 - 5;
+        data[a_rdlength] = 0x03;
+        let records = parse_records(&data);
+        assert_eq!(records[3].as_ref().unwrap_err(), &DnsError::InvalidRecordData);
+        assert_eq!(records.len(), 4);
+
+        // The SRV target runs past its RDATA into the TXT record.
+        let mut data = AVAHI_RESPONSE.to_vec();
+        data[0x3c] = 0x0e;
+        assert_eq!(parse_records(&data)[1].as_ref().unwrap_err(), &DnsError::InvalidRecordData);
+    }
+
+    #[test]
+    fn stops_records_after_invalid_question() {
+        let data = query(&[0xc0, 0x0c]);
+        let records = parse_records(&data);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].as_ref().unwrap_err(), &DnsError::InvalidPointer);
+    }
+
+    #[test]
+    fn writer_output_parses_back() {
+        let mut buffer = [0u8; 128];
+        let mut writer = DnsWriter::new(&mut buffer);
+        writer.bytes(&[0x00, 0x00, 0x84, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        writer.question(&["sensor", "local"], DNS_TYPE_A).unwrap();
+        writer.record(&["sensor.local"], DNS_TYPE_A, true, 120).unwrap();
+        let length_position = writer.begin_rdata().unwrap();
+        writer.bytes(&[192, 168, 1, 50]).unwrap();
+        writer.end_rdata(length_position).unwrap();
+        let length = writer.position();
+
+        let message = DnsMessage::parse(&buffer[..length]).unwrap();
+        let question = message.questions().next().unwrap().unwrap();
+        assert!(question.name.matches("sensor.local"));
+        assert!(!question.unicast_response);
+        let record = message.records().next().unwrap().unwrap();
+        assert!(record.cache_flush);
+        assert_eq!(record.ttl, 120);
+        assert!(matches!(record.data, DnsRecordData::A(address) if address == Ipv4Addr::new(192, 168, 1, 50)));
+    }
+
+    #[test]
+    fn writer_rejects_invalid_labels_and_overflow() {
+        let mut buffer = [0u8; 128];
+        let mut writer = DnsWriter::new(&mut buffer);
+        assert!(writer.name(&["sensor..local"]).is_none());
+        assert!(writer.name(&[&"a".repeat(64)]).is_none());
+
+        let mut buffer = [0u8; 8];
+        let mut writer = DnsWriter::new(&mut buffer);
+        assert!(writer.name(&["sensor.local"]).is_none());
+    }
+}
@@ -0,0 +1,223 @@
+use core::net::IpAddr;
+use embassy_futures::select::{select, Either};
+use embassy_net::{dns::DnsQueryType, udp, IpAddress, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rng::Rng;
+use heapless::{String, Vec};
+use log::{error, info};
+
+use crate::dns::{DnsRecordData, DNS_TYPE_A, DNS_TYPE_AAAA, DNS_TYPE_PTR, DNS_TYPE_SRV, DNS_TYPE_TXT};
+use crate::dns_sd_query::{answers, is_response_to, write_query};
+use crate::discovery::{order_by_priority, ServiceEndpoint, DISCOVERY_MAX_INSTANCES};
+use crate::mdns::random_source;
+use crate::mdns_cache::MdnsCache;
+
+const DNS_PORT: u16 = 53;
+/// Plain DNS over UDP is limited to 512 bytes, without EDNS.
+const DNS_MESSAGE_SIZE: usize = 512;
+/// How long each server gets to answer a query before the next one is tried.
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const DNS_MAX_SERVERS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicastDnsError {
+    /// The stack has no IP configuration.
+    NetworkUnavailable,
+    /// DHCP provided no DNS server.
+    NoServers,
+    BindFailed,
+    /// No server answered, or the name does not exist.
+    NotFound,
+}
+
+/// Lookups through the DNS servers provided by DHCP, for networks where
+/// multicast (and so mDNS) is filtered.
+#[derive(Clone, Copy)]
+pub struct UnicastDnsFacade {
+    /// Source of the query IDs, which must not be predictable (RFC 5452).
+    _rng: Rng,
+}
+
+impl UnicastDnsFacade {
+    pub const fn new(rng: Rng) -> Self {
+        Self {
+            _rng: rng,
+        }
+    }
+
+    /// Resolves `host` to an address, IPv4 first unless the stack only has
+    /// IPv6, and returns it with `port`.
+    pub async fn resolve_host<'s>(
+        &self,
+        host: &str,
+        port: u16,
+        stack: &'static Stack<'s>,
+    ) -> Result<ServiceEndpoint, UnicastDnsError> {
+        if !stack.is_config_up() {
+            return Err(UnicastDnsError::NetworkUnavailable);
+        }
+
+        let query_types = if stack.config_v4().is_some() {
+            [DnsQueryType::A, DnsQueryType::Aaaa]
+        } else {
+            [DnsQueryType::Aaaa, DnsQueryType::A]
+        };
+        for query_type in query_types {
+            match stack.dns_query(host, query_type).await {
+                Ok(addresses) => {
+                    let address = match addresses.first() {
+                        Some(IpAddress::Ipv4(address)) => IpAddr::V4(*address),
+                        Some(IpAddress::Ipv6(address)) => IpAddr::V6(*address),
+                        None => continue,
+                    };
+                    info!("UnicastDnsFacade: Resolved {:?} to {}", host, address);
+                    return Ok(ServiceEndpoint::new(address, port));
+                }
+                Err(e) => info!("UnicastDnsFacade: {:?} query for {:?} failed: {:?}", query_type, host, e),
+            }
+        }
+        Err(UnicastDnsError::NotFound)
+    }
+
+    /// Browse `service_name` (e.g. `_mqtt._tcp.example.com`) with unicast
+    /// DNS-SD (RFC 6763): the PTR records of the service, then the SRV and TXT
+    /// records of each instance, then the addresses of their targets. Returns
+    /// the resolved instances in the order they should be tried (RFC 2782).
+    pub async fn browse_service<'s>(
+        &self,
+        service_name: &str,
+        stack: &'static Stack<'s>,
+    ) -> Result<Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>, UnicastDnsError> {
+        if !stack.is_config_up() {
+            return Err(UnicastDnsError::NetworkUnavailable);
+        }
+        let servers = dns_servers(stack);
+        if servers.is_empty() {
+            error!("UnicastDnsFacade: No DNS server configured");
+            return Err(UnicastDnsError::NoServers);
+        }
+
+        let mut rx_meta: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4];
+        let mut rx_buff: [u8; 2 * DNS_MESSAGE_SIZE] = [0; 2 * DNS_MESSAGE_SIZE];
+        let mut tx_meta: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4];
+        let mut tx_buff: [u8; DNS_MESSAGE_SIZE] = [0; DNS_MESSAGE_SIZE];
+        let mut sock = udp::UdpSocket::new(
+            *stack,
+            &mut rx_meta,
+            &mut rx_buff,
+            &mut tx_meta,
+            &mut tx_buff,
+        );
+        // Ephemeral port
+        sock.bind(0).map_err(|e| {
+            error!("UnicastDnsFacade: bind failed: {:?}", e);
+            UnicastDnsError::BindFailed
+        })?;
+
+        let mut response = [0u8; DNS_MESSAGE_SIZE];
+        let mut cache = MdnsCache::new();
+
+        let mut instances: Vec<String<64>, DISCOVERY_MAX_INSTANCES> = Vec::new();
+        if let Some(length) = self.query(&sock, &servers, service_name, DNS_TYPE_PTR, &mut response).await {
+            cache.insert_packet(&response[..length], Instant::now());
+            for record in answers(&response[..length]) {
+                if let DnsRecordData::Ptr(instance) = record {
+                    if let Some(instance) = instance.to_dotted_string() {
+                        let _ = instances.push(instance);
+                    }
+                }
+            }
+        }
+        info!("UnicastDnsFacade: {} instance(s) of {:?}", instances.len(), service_name);
+
+        let mut targets: Vec<String<64>, DISCOVERY_MAX_INSTANCES> = Vec::new();
+        for instance in instances.iter() {
+            for record_type in [DNS_TYPE_SRV, DNS_TYPE_TXT] {
+                let Some(length) = self.query(&sock, &servers, instance, record_type, &mut response).await else {
+                    continue;
+                };
+                cache.insert_packet(&response[..length], Instant::now());
+                for record in answers(&response[..length]) {
+                    if let DnsRecordData::Srv { target, .. } = record {
+                        if let Some(target) = target.to_dotted_string() {
+                            let _ = targets.push(target);
+                        }
+                    }
+                }
+            }
+        }
+
+        for target in targets.iter() {
+            for record_type in [DNS_TYPE_A, DNS_TYPE_AAAA] {
+                if let Some(length) = self.query(&sock, &servers, target, record_type, &mut response).await {
+                    cache.insert_packet(&response[..length], Instant::now());
+                }
+            }
+        }
+
+        let mut endpoints = cache.resolve(service_name, Instant::now(), stack.config_v4().is_none());
+        if endpoints.is_empty() {
+            return Err(UnicastDnsError::NotFound);
+        }
+        order_by_priority(&mut endpoints, random_source());
+        Ok(endpoints)
+    }
+
+    /// Asks each server in turn for the `record_type` records of `name`, until
+    /// one answers. Returns the length of the response written to `response`.
+    async fn query(
+        &self,
+        sock: &udp::UdpSocket<'_>,
+        servers: &[IpAddress],
+        name: &str,
+        record_type: u16,
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let mut rng = self._rng;
+        let id = rng.random() as u16;
+        let mut query = [0u8; DNS_MESSAGE_SIZE];
+        let Some(length) = write_query(&mut query, id, name, record_type) else {
+            error!("UnicastDnsFacade: Cannot query {:?}", name);
+            return None;
+        };
+
+        for &server in servers {
+            if let Err(e) = sock.send_to(&query[..length], (server, DNS_PORT)).await {
+                info!("UnicastDnsFacade: Sending query to {:?} failed: {:?}", server, e);
+                continue;
+            }
+            let deadline = Instant::now() + DNS_QUERY_TIMEOUT;
+            loop {
+                match select(sock.recv_from(response), Timer::at(deadline)).await {
+                    Either::First(Ok((n, peer))) => {
+                        if is_response_to(&response[..n], id) && peer.endpoint.addr == server {
+                            return Some(n);
+                        }
+                    }
+                    Either::First(Err(e)) => info!("UnicastDnsFacade: Receive failed: {:?}", e),
+                    Either::Second(_) => {
+                        info!("UnicastDnsFacade: No answer from {:?} for {:?}", server, name);
+                        break;
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// DNS servers of the IPv4 (DHCP) and IPv6 configurations, IPv4 first.
+fn dns_servers(stack: &Stack<'_>) -> Vec<IpAddress, DNS_MAX_SERVERS> {
+    let mut servers = Vec::new();
+    if let Some(config) = stack.config_v4() {
+        for server in config.dns_servers.iter() {
+            let _ = servers.push(IpAddress::Ipv4(*server));
+        }
+    }
+    if let Some(config) = stack.config_v6() {
+        for server in config.dns_servers.iter() {
+            let _ = servers.push(IpAddress::Ipv6(*server));
+        }
+    }
+    servers
+}
@@ -0,0 +1,150 @@
+//! Queries and responses of unicast DNS-SD (RFC 6763), sent to the recursive
+//! servers provided by DHCP. Only depends on `core` and `heapless`, so it can
+//! be exercised on the host without a network stack.
+
+use crate::dns::{DnsMessage, DnsRecordData, DnsSection, DnsWriter, DNS_FLAG_RECURSION_DESIRED};
+
+/// Writes a recursive query for the `record_type` records of `name` into
+/// `buffer`, returning its length. `None` when `name` is not a valid name or
+/// does not fit.
+pub fn write_query(buffer: &mut [u8], id: u16, name: &str, record_type: u16) -> Option<usize> {
+    let mut writer = DnsWriter::new(buffer);
+    writer.u16(id)?;
+    writer.u16(DNS_FLAG_RECURSION_DESIRED)?;
+    writer.u16(1)?; // One question
+    writer.u16(0)?;
+    writer.u16(0)?;
+    writer.u16(0)?;
+    writer.question(&[name], record_type)?;
+    Some(writer.position())
+}
+
+/// Whether `data` is a response to the query sent with `id`.
+pub fn is_response_to(data: &[u8], id: u16) -> bool {
+    DnsMessage::parse(data).is_ok_and(|message| message.header().is_response() && message.header().id == id)
+}
+
+/// Data of the answer section records of the response in `data`, up to the
+/// first record that cannot be parsed.
+pub fn answers(data: &[u8]) -> impl Iterator<Item = DnsRecordData<'_>> {
+    DnsMessage::parse(data)
+        .map(|message| message.records())
+        .into_iter()
+        .flatten()
+        .map_while(Result::ok)
+        .filter(|record| record.section == DnsSection::Answer)
+        .map(|record| record.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DNS_TYPE_PTR;
+    use heapless::String;
+
+    /// Response of a recursive server to a `_mqtt._tcp.example.com` PTR query:
+    /// the question repeated, two instances as answers and the SRV record of
+    /// the first one as an additional, names compressed against the question.
+    const PTR_RESPONSE: &[u8] = &[
+        // header: id 0x1234, response + recursion desired and available, 1 question, 2 answers, 1 additional
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01,
+        // _mqtt._tcp.example.com
+        0x05, 0x5f, 0x6d, 0x71, 0x74, 0x74, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x07, 0x65, 0x78, 0x61, 0x6d,
+        0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00,
+        // PTR IN
+        0x00, 0x0c, 0x00, 0x01,
+        // _mqtt._tcp.example.com PTR IN, TTL 3600
+        0xc0, 0x0c, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10,
+        // RDLENGTH 9: Broker, then a pointer to the service name
+        0x00, 0x09, 0x06, 0x42, 0x72, 0x6f, 0x6b, 0x65, 0x72, 0xc0, 0x0c,
+        // _mqtt._tcp.example.com PTR IN, TTL 3600
+        0xc0, 0x0c, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10,
+        // RDLENGTH 9: Backup, then a pointer to the service name
+        0x00, 0x09, 0x06, 0x42, 0x61, 0x63, 0x6b, 0x75, 0x70, 0xc0, 0x0c,
+        // Broker._mqtt._tcp.example.com SRV IN, TTL 3600
+        0xc0, 0x34, 0x00, 0x21, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10,
+        // RDLENGTH 13: priority 0, weight 0, port 1883, target mqtt.example.com
+        0x00, 0x0d, 0x00, 0x00, 0x00, 0x00, 0x07, 0x5b, 0x04, 0x6d, 0x71, 0x74, 0x74, 0xc0, 0x17,
+    ];
+
+    /// Response of a recursive server to a `Broker._mqtt._tcp.example.com` SRV query.
+    const SRV_RESPONSE: &[u8] = &[
+        // header: id 0xabcd, response + recursion desired and available, 1 question, 1 answer
+        0xab, 0xcd, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        // Broker._mqtt._tcp.example.com
+        0x06, 0x42, 0x72, 0x6f, 0x6b, 0x65, 0x72, 0x05, 0x5f, 0x6d, 0x71, 0x74, 0x74, 0x04, 0x5f, 0x74,
+        0x63, 0x70, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00,
+        // SRV IN
+        0x00, 0x21, 0x00, 0x01,
+        // Broker._mqtt._tcp.example.com SRV IN, TTL 300
+        0xc0, 0x0c, 0x00, 0x21, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c,
+        // RDLENGTH 13: priority 10, weight 5, port 1883, target mqtt.example.com
+        0x00, 0x0d, 0x00, 0x0a, 0x00, 0x05, 0x07, 0x5b, 0x04, 0x6d, 0x71, 0x74, 0x74, 0xc0, 0x1e,
+    ];
+
+    fn ptr_names(data: &[u8]) -> std::vec::Vec<String<64>> {
+        answers(data)
+            .filter_map(|record| match record {
+                DnsRecordData::Ptr(name) => name.to_dotted_string(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_asks_for_recursion() {
+        let mut buffer = [0u8; 64];
+
+        let length = write_query(&mut buffer, 0x1234, "_mqtt._tcp.example.com", DNS_TYPE_PTR).unwrap();
+
+        assert_eq!(&buffer[..12], [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        // The server repeats the question in its response
+        assert_eq!(&buffer[12..length], &PTR_RESPONSE[12..40]);
+    }
+
+    #[test]
+    fn query_rejects_invalid_names_and_overflow() {
+        let mut buffer = [0u8; 64];
+        assert_eq!(write_query(&mut buffer, 1, "_mqtt.._tcp.example.com", DNS_TYPE_PTR), None);
+        assert_eq!(write_query(&mut buffer, 1, "", DNS_TYPE_PTR), None);
+
+        let mut buffer = [0u8; 30];
+        assert_eq!(write_query(&mut buffer, 1, "_mqtt._tcp.example.com", DNS_TYPE_PTR), None);
+    }
+
+    #[test]
+    fn responses_are_matched_by_id() {
+        let mut query = [0u8; 64];
+        let length = write_query(&mut query, 0x1234, "_mqtt._tcp.example.com", DNS_TYPE_PTR).unwrap();
+
+        assert!(is_response_to(PTR_RESPONSE, 0x1234));
+        assert!(!is_response_to(PTR_RESPONSE, 0x1235));
+        assert!(!is_response_to(&query[..length], 0x1234));
+        assert!(!is_response_to(&PTR_RESPONSE[..4], 0x1234));
+    }
+
+    #[test]
+    fn answers_skip_additional_records() {
+        assert_eq!(ptr_names(PTR_RESPONSE), ["Broker._mqtt._tcp.example.com", "Backup._mqtt._tcp.example.com"]);
+    }
+
+    #[test]
+    fn answers_read_srv_records() {
+        let records: std::vec::Vec<_> = answers(SRV_RESPONSE).collect();
+
+        assert_eq!(records.len(), 1);
+        let DnsRecordData::Srv { priority, weight, port, target } = records[0] else {
+            panic!("unexpected record {:?}", records[0]);
+        };
+        assert_eq!((priority, weight, port), (10, 5, 1883));
+        assert!(target.matches("mqtt.example.com"));
+    }
+
+    #[test]
+    fn answers_stop_at_a_truncated_record() {
+        // Cut in the middle of the second answer
+        assert_eq!(ptr_names(&PTR_RESPONSE[..70]), ["Broker._mqtt._tcp.example.com"]);
+        assert_eq!(answers(&PTR_RESPONSE[..8]).count(), 0);
+        assert_eq!(answers(&[]).count(), 0);
+    }
+}
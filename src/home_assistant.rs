@@ -1,31 +1,70 @@
-use crate::mqtt::{MqttMessage};
+use crate::home_assistant_payload::{
+    write_component_discovery,
+    write_diagnostics,
+    write_state,
+    write_telemetry,
+    DiscoveryDevice,
+    AVAILABILITY_OFFLINE,
+    AVAILABILITY_ONLINE,
+};
+use crate::mqtt::{MqttAvailability, MqttError, MqttMessage, MQTT_TOPIC_SIZE};
+use crate::sensor::{Readings, SharedSensorHealth};
+use crate::telemetry::DeviceTelemetry;
+
+/// Default MQTT discovery prefix of Home Assistant, under which every topic is published.
+pub const HOME_ASSISTANT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// Upper bound of the random delay before re-sending discovery after a birth message,
+/// so a fleet of monitors does not flood Home Assistant at the same instant.
+const DISCOVERY_MAX_JITTER_MS: u64 = 5000;
 
 pub struct HomeAssistantFacadeConfig {
     device_id: &'static str,
-    device_name: &'static str
+    device_name: &'static str,
+    discovery_prefix: &'static str
 }
 
 impl HomeAssistantFacadeConfig {
     pub fn new(device_id: &'static str, device_name: &'static str) -> Self {
         Self {
-            device_id: device_id,
-            device_name: device_name
+            device_id,
+            device_name,
+            discovery_prefix: HOME_ASSISTANT_DISCOVERY_PREFIX
         }
     }
 
     pub fn new_from_env() -> Self {
-        Self {
-            device_id: env!("DEVICE_ID"),
-            device_name: env!("DEVICE_NAME")
-        }
+        Self::new(env!("DEVICE_ID"), env!("DEVICE_NAME"))
+    }
+
+    /// Replaces `HOME_ASSISTANT_DISCOVERY_PREFIX`, for Home Assistant instances
+    /// configured with another prefix.
+    pub fn with_discovery_prefix(mut self, discovery_prefix: &'static str) -> Self {
+        self.discovery_prefix = discovery_prefix;
+        self
+    }
+
+    /// Topic prefix to use with brokers that do not advertise their own.
+    pub fn discovery_prefix(&self) -> &'static str {
+        self.discovery_prefix
+    }
+
+    pub fn device_id(&self) -> &'static str {
+        self.device_id
+    }
+
+    pub fn device_name(&self) -> &'static str {
+        self.device_name
     }
 }
 
+/// Topics built by the facade are relative to the discovery prefix, which
+/// `MqttFacade` prepends, since it depends on the broker connected to.
 pub struct HomeAssistantFacade {
     _config: HomeAssistantFacadeConfig,
 }
 
 use core::fmt::Write;
+use embassy_time::{Duration, Instant};
 use heapless::String;
 
 impl HomeAssistantFacade {
@@ -35,141 +74,105 @@ impl HomeAssistantFacade {
         }
     }
 
-    pub fn get_state_mqtt_message<'m>(
-        &self, 
-        co2: u16, 
-        humidity: f32, 
-        temperature: f32,
-        voc_index: u16,
-        nox_index: u16,
-        pm1_0_atm: u16,
-        pm2_5_atm: u16,
-        pm10_0_atm: u16
-    ) -> MqttMessage<'m> {
-        unsafe {
-            static mut topic_buffer: String<128> = String::new();
-            static mut message_buffer: String<1024> = String::new();
-
-            topic_buffer.clear();
-            message_buffer.clear();
-
-            write!(&mut topic_buffer, "homeassistant/device/{}/state", self._config.device_id).unwrap();
-            write!(&mut message_buffer,
-                r#"{{"temperature":{},"co2":{},"humidity":{},"voc_index":{},"nox_index":{},"pm1_0_atm":{},"pm2_5_atm":{},"pm10_0_atm":{}}}"#,
-                temperature,
-                co2,
-                humidity,
-                voc_index,
-                nox_index,
-                pm1_0_atm,
-                pm2_5_atm,
-                pm10_0_atm
-            ).unwrap();
-
-            return MqttMessage::new(
-                topic_buffer.as_str(),
-                message_buffer.as_str()
-            );
-        }
+    pub fn config(&self) -> &HomeAssistantFacadeConfig {
+        &self._config
     }
 
-    pub fn get_device_discovery_mqtt_message<'m>(&self) -> MqttMessage<'m> {
-        unsafe {
-            static mut topic_buffer: String<128> = String::new();
-            static mut message_buffer: String<4096> = String::new();
-
-            topic_buffer.clear();
-            message_buffer.clear();
-            
-            write!(&mut topic_buffer, "homeassistant/device/{}/config", self._config.device_id).unwrap();
-            write!(&mut message_buffer, 
-                r#"{{
-                    "dev": {{
-                        "ids": "{}",
-                        "name": "{}"
-                    }},
-                    "o": {{
-                        "name":"air-quality-monitor",
-                        "sw": "1.0",
-                        "url": "https://github.com/lomagno2003/air-quality-monitor"
-                    }},
-                    "cmps": {{
-                        "temperature_component": {{
-                            "p": "sensor",
-                            "device_class":"temperature",
-                            "unit_of_measurement":"°C",
-                            "value_template":"{{{{ value_json.temperature}}}}",
-                            "unique_id":"{}_temperature"
-                        }},
-                        "carbon_dioxide_component": {{
-                            "p": "sensor",
-                            "device_class":"carbon_dioxide",
-                            "unit_of_measurement":"ppm",
-                            "value_template":"{{{{ value_json.co2}}}}",
-                            "unique_id":"{}_co2"
-                        }},
-                        "humidity_component": {{
-                            "p": "sensor",
-                            "device_class":"humidity",
-                            "unit_of_measurement":"%",
-                            "value_template":"{{{{ value_json.humidity}}}}",
-                            "unique_id":"{}_humidity"
-                        }},
-                        "voc_index_component": {{
-                            "p": "sensor",
-                            "name": "VOC index",
-                            "device_class":"aqi",
-                            "value_template":"{{{{ value_json.voc_index}}}}",
-                            "unique_id":"{}_voc_index"
-                        }},
-                        "nox_index_component": {{
-                            "p": "sensor",
-                            "name": "NOx index",
-                            "device_class":"aqi",
-                            "value_template":"{{{{ value_json.nox_index}}}}",
-                            "unique_id":"{}_nox_index"
-                        }},
-                        "pm1_0_atm_component": {{
-                            "p": "sensor",
-                            "device_class":"pm1",
-                            "unit_of_measurement":"µg/m³",
-                            "value_template":"{{{{ value_json.pm1_0_atm}}}}",
-                            "unique_id":"{}_pm1"
-                        }},
-                        "pm2_5_atm_component": {{
-                            "p": "sensor",
-                            "device_class":"pm25",
-                            "unit_of_measurement":"µg/m³",
-                            "value_template":"{{{{ value_json.pm2_5_atm}}}}",
-                            "unique_id":"{}_pm2_5"
-                        }},
-                        "pm10_0_atm_component": {{
-                            "p": "sensor",
-                            "device_class":"pm10",
-                            "unit_of_measurement":"µg/m³",
-                            "value_template":"{{{{ value_json.pm10_0_atm}}}}",
-                            "unique_id":"{}_pm10"
-                        }}
-                    }},
-                    "state_topic":"homeassistant/device/{}/state",
-                    "qos": 2
-                }}"#,
-                self._config.device_id, 
-                self._config.device_name, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id, 
-                self._config.device_id).unwrap();
-
-            return MqttMessage::new(
-                topic_buffer.as_str(), 
-                message_buffer.as_str()
-            );
-        }
+    /// Topic on which Home Assistant publishes its birth (`online`) and will (`offline`) messages.
+    pub fn get_status_topic(&self) -> Result<String<MQTT_TOPIC_SIZE>, MqttError> {
+        let mut topic: String<MQTT_TOPIC_SIZE> = String::new();
+        write!(&mut topic, "status")
+            .map_err(|_| MqttError::MessageTooLarge)?;
+        Ok(topic)
+    }
+
+    /// Whether a message received on the status topic announces that
+    /// Home Assistant (re)started and expects discovery to be sent again.
+    pub fn is_birth_message(payload: &[u8]) -> bool {
+        payload == AVAILABILITY_ONLINE.as_bytes()
+    }
+
+    /// Delay to wait before re-sending discovery after a birth message. Mixes the
+    /// device id with the current time so that monitors spread their discovery out.
+    pub fn get_discovery_jitter(&self) -> Duration {
+        let seed = self._config.device_id.bytes()
+            .fold(Instant::now().as_micros(), |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as u64));
+        Duration::from_millis(seed % DISCOVERY_MAX_JITTER_MS)
+    }
+
+    /// Availability topic declared in discovery, with the payloads Home Assistant expects.
+    pub fn get_availability(&self) -> Result<MqttAvailability, MqttError> {
+        Ok(MqttAvailability {
+            topic: self.get_device_topic("availability")?,
+            online_payload: AVAILABILITY_ONLINE,
+            offline_payload: AVAILABILITY_OFFLINE,
+        })
+    }
+
+    /// Topic on which Home Assistant (or anyone else) can send `command` to this device.
+    pub fn get_command_topic(&self, command: &str) -> Result<String<MQTT_TOPIC_SIZE>, MqttError> {
+        let mut topic: String<MQTT_TOPIC_SIZE> = String::new();
+        write!(&mut topic, "device/{}/set/{}", self._config.device_id, command)
+            .map_err(|_| MqttError::MessageTooLarge)?;
+        Ok(topic)
+    }
+
+    /// State payload with the values of every sensor present in `readings`.
+    pub fn get_state_mqtt_message(&self, readings: &Readings) -> Result<MqttMessage, MqttError> {
+        let mut message = MqttMessage::with_topic(&self.get_device_topic("state")?)?;
+        write_state(&mut message.content, readings)?;
+        Ok(message)
+    }
+
+    /// Diagnostics payload with the health counters of every sensor in `sensor_health`.
+    pub fn get_diagnostics_mqtt_message(
+        &self,
+        sensor_health: &[&SharedSensorHealth],
+        now: Instant,
+    ) -> Result<MqttMessage, MqttError> {
+        let mut message = MqttMessage::with_topic(&self.get_device_topic("diagnostics")?)?;
+        write_diagnostics(
+            &mut message.content,
+            sensor_health.iter().map(|shared_health| (shared_health.id(), shared_health.get())),
+            now)?;
+        Ok(message)
+    }
+
+    /// Discovery message of the `index`-th entity of the device, `None` past the last one.
+    /// Every entity is discovered on its own topic, so no message has to hold the whole device.
+    /// The state topics it declares are absolute, under `topic_prefix`.
+    pub fn get_component_discovery_mqtt_message(
+        &self,
+        sensor_health: &[&SharedSensorHealth],
+        topic_prefix: &str,
+        index: usize,
+    ) -> Result<Option<MqttMessage>, MqttError> {
+        let mut message = MqttMessage::with_topic("")?;
+        let device = DiscoveryDevice {
+            id: self._config.device_id,
+            name: self._config.device_name,
+        };
+        let found = write_component_discovery(
+            &mut message.topic,
+            &mut message.content,
+            &device,
+            sensor_health,
+            topic_prefix,
+            index)?;
+        Ok(found.then_some(message))
+    }
+
+    /// Telemetry payload describing the device itself rather than the air.
+    pub fn get_telemetry_mqtt_message(&self, telemetry: &DeviceTelemetry) -> Result<MqttMessage, MqttError> {
+        let mut message = MqttMessage::with_topic(&self.get_device_topic("telemetry")?)?;
+        write_telemetry(&mut message.content, telemetry)?;
+        Ok(message)
+    }
+
+    fn get_device_topic(&self, suffix: &str) -> Result<String<MQTT_TOPIC_SIZE>, MqttError> {
+        let mut topic: String<MQTT_TOPIC_SIZE> = String::new();
+        write!(&mut topic, "device/{}/{}", self._config.device_id, suffix)
+            .map_err(|_| MqttError::MessageTooLarge)?;
+        Ok(topic)
     }
 }
@@ -0,0 +1,536 @@
+//! Payloads published to Home Assistant: the state, diagnostics and telemetry
+//! JSON, and the discovery config of every entity. Only depends on `core`,
+//! `heapless` and the plain sensor and telemetry types, so it can be exercised
+//! on the host without a broker.
+
+use core::fmt::Write;
+use embassy_time::Instant;
+use heapless::String;
+
+use crate::json::{JsonError, JsonWriter};
+use crate::sensor::{Readings, SensorHealth, SharedSensorHealth};
+use crate::telemetry::DeviceTelemetry;
+
+pub const AVAILABILITY_ONLINE: &str = "online";
+pub const AVAILABILITY_OFFLINE: &str = "offline";
+
+/// A sensor entity discovered on its own topic, reading `value_key` from the state JSON.
+struct SensorComponent {
+    value_key: &'static str,
+    unique_id_suffix: &'static str,
+    name: Option<&'static str>,
+    device_class: &'static str,
+    unit_of_measurement: Option<&'static str>,
+}
+
+const SENSOR_COMPONENTS: [SensorComponent; 8] = [
+    SensorComponent {
+        value_key: "temperature",
+        unique_id_suffix: "temperature",
+        name: None,
+        device_class: "temperature",
+        unit_of_measurement: Some("°C"),
+    },
+    SensorComponent {
+        value_key: "co2",
+        unique_id_suffix: "co2",
+        name: None,
+        device_class: "carbon_dioxide",
+        unit_of_measurement: Some("ppm"),
+    },
+    SensorComponent {
+        value_key: "humidity",
+        unique_id_suffix: "humidity",
+        name: None,
+        device_class: "humidity",
+        unit_of_measurement: Some("%"),
+    },
+    SensorComponent {
+        value_key: "voc_index",
+        unique_id_suffix: "voc_index",
+        name: Some("VOC index"),
+        device_class: "aqi",
+        unit_of_measurement: None,
+    },
+    SensorComponent {
+        value_key: "nox_index",
+        unique_id_suffix: "nox_index",
+        name: Some("NOx index"),
+        device_class: "aqi",
+        unit_of_measurement: None,
+    },
+    SensorComponent {
+        value_key: "pm1_0_atm",
+        unique_id_suffix: "pm1",
+        name: None,
+        device_class: "pm1",
+        unit_of_measurement: Some("µg/m³"),
+    },
+    SensorComponent {
+        value_key: "pm2_5_atm",
+        unique_id_suffix: "pm2_5",
+        name: None,
+        device_class: "pm25",
+        unit_of_measurement: Some("µg/m³"),
+    },
+    SensorComponent {
+        value_key: "pm10_0_atm",
+        unique_id_suffix: "pm10",
+        name: None,
+        device_class: "pm10",
+        unit_of_measurement: Some("µg/m³"),
+    },
+];
+
+/// A diagnostic entity discovered on its own topic. Sensor health entities are
+/// declared for every tracked sensor and read `{sensor id}_{key}` from the diagnostics
+/// JSON; telemetry entities read `key` from the telemetry JSON.
+struct DiagnosticComponent {
+    key: &'static str,
+    name: &'static str,
+    platform: &'static str,
+    device_class: Option<&'static str>,
+    unit_of_measurement: Option<&'static str>,
+}
+
+const SENSOR_HEALTH_COMPONENTS: [DiagnosticComponent; 5] = [
+    DiagnosticComponent {
+        key: "consecutive_failures",
+        name: "consecutive failures",
+        platform: "sensor",
+        device_class: None,
+        unit_of_measurement: None,
+    },
+    DiagnosticComponent {
+        key: "total_failures",
+        name: "total failures",
+        platform: "sensor",
+        device_class: None,
+        unit_of_measurement: None,
+    },
+    DiagnosticComponent {
+        key: "last_error",
+        name: "last error",
+        platform: "sensor",
+        device_class: None,
+        unit_of_measurement: None,
+    },
+    DiagnosticComponent {
+        key: "last_success_age",
+        name: "time since last read",
+        platform: "sensor",
+        device_class: Some("duration"),
+        unit_of_measurement: Some("s"),
+    },
+    DiagnosticComponent {
+        key: "warmed_up",
+        name: "warmed up",
+        platform: "binary_sensor",
+        device_class: None,
+        unit_of_measurement: None,
+    },
+];
+
+const TELEMETRY_COMPONENTS: [DiagnosticComponent; 5] = [
+    DiagnosticComponent {
+        key: "rssi",
+        name: "Wi-Fi signal",
+        platform: "sensor",
+        device_class: Some("signal_strength"),
+        unit_of_measurement: Some("dBm"),
+    },
+    DiagnosticComponent {
+        key: "uptime",
+        name: "uptime",
+        platform: "sensor",
+        device_class: Some("duration"),
+        unit_of_measurement: Some("s"),
+    },
+    DiagnosticComponent {
+        key: "heap_free",
+        name: "free heap",
+        platform: "sensor",
+        device_class: Some("data_size"),
+        unit_of_measurement: Some("B"),
+    },
+    DiagnosticComponent {
+        key: "heap_used",
+        name: "used heap",
+        platform: "sensor",
+        device_class: Some("data_size"),
+        unit_of_measurement: Some("B"),
+    },
+    DiagnosticComponent {
+        key: "reset_reason",
+        name: "reset reason",
+        platform: "sensor",
+        device_class: None,
+        unit_of_measurement: None,
+    },
+];
+
+/// Device the discovered entities are grouped under.
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveryDevice<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+}
+
+/// State payload with the values of every sensor present in `readings`.
+/// Keys of missing sensors are omitted; discovery templates report them as unknown.
+pub fn write_state<const N: usize>(content: &mut String<N>, readings: &Readings) -> Result<(), JsonError> {
+    let mut json = JsonWriter::new(content);
+    json.begin_object()?;
+    if let Some(climate) = &readings.climate {
+        json.field_number("temperature", climate.temperature)?;
+        json.field_number("co2", climate.co2)?;
+        json.field_number("humidity", climate.humidity)?;
+    }
+    if let Some(gas_index) = &readings.gas_index {
+        json.field_number("voc_index", gas_index.voc_index)?;
+        json.field_number("nox_index", gas_index.nox_index)?;
+    }
+    if let Some(particulate) = &readings.particulate {
+        json.field_number("pm1_0_atm", particulate.pm1_0_atm)?;
+        json.field_number("pm2_5_atm", particulate.pm2_5_atm)?;
+        json.field_number("pm10_0_atm", particulate.pm10_0_atm)?;
+    }
+    json.end_object()
+}
+
+/// Diagnostics payload with the health counters of every sensor, given as
+/// pairs of sensor id and health.
+pub fn write_diagnostics<'h, const N: usize>(
+    content: &mut String<N>,
+    sensor_health: impl Iterator<Item = (&'h str, SensorHealth)>,
+    now: Instant,
+) -> Result<(), JsonError> {
+    let mut json = JsonWriter::new(content);
+    json.begin_object()?;
+    for (id, health) in sensor_health {
+        json.key_fmt(format_args!("{}_consecutive_failures", id))?;
+        json.number(health.consecutive_failures)?;
+        json.key_fmt(format_args!("{}_total_failures", id))?;
+        json.number(health.total_failures)?;
+        if let Some(last_error) = health.last_error {
+            json.key_fmt(format_args!("{}_last_error", id))?;
+            json.string_fmt(format_args!("{:?}", last_error))?;
+        }
+        if let Some(last_success_at) = health.last_success_at {
+            json.key_fmt(format_args!("{}_last_success_age", id))?;
+            json.number(now.saturating_duration_since(last_success_at).as_secs())?;
+        }
+        json.key_fmt(format_args!("{}_warmed_up", id))?;
+        json.boolean(health.warmed_up)?;
+    }
+    json.end_object()
+}
+
+/// Telemetry payload describing the device itself rather than the air.
+pub fn write_telemetry<const N: usize>(content: &mut String<N>, telemetry: &DeviceTelemetry) -> Result<(), JsonError> {
+    let mut json = JsonWriter::new(content);
+    json.begin_object()?;
+    if let Some(rssi) = telemetry.rssi {
+        json.field_number("rssi", rssi)?;
+    }
+    json.field_number("uptime", telemetry.uptime_secs)?;
+    json.field_number("heap_free", telemetry.heap_free)?;
+    json.field_number("heap_used", telemetry.heap_used)?;
+    json.field_str("reset_reason", telemetry.reset_reason)?;
+    json.end_object()
+}
+
+/// Discovery topic, relative to the discovery prefix, and config of the
+/// `index`-th entity of `device`. Returns `false` past the last entity. The
+/// state and availability topics the config declares are absolute, under
+/// `topic_prefix`.
+pub fn write_component_discovery<const T: usize, const N: usize>(
+    topic: &mut String<T>,
+    content: &mut String<N>,
+    device: &DiscoveryDevice,
+    sensor_health: &[&SharedSensorHealth],
+    topic_prefix: &str,
+    index: usize,
+) -> Result<bool, JsonError> {
+    if let Some(component) = SENSOR_COMPONENTS.get(index) {
+        write_discovery_topic(topic, device, "sensor", component.unique_id_suffix)?;
+        write_sensor_discovery(content, device, component, topic_prefix)?;
+        return Ok(true);
+    }
+
+    let index = index - SENSOR_COMPONENTS.len();
+    let health_component_count = sensor_health.len() * SENSOR_HEALTH_COMPONENTS.len();
+    if index < health_component_count {
+        let shared_health = sensor_health[index / SENSOR_HEALTH_COMPONENTS.len()];
+        let component = &SENSOR_HEALTH_COMPONENTS[index % SENSOR_HEALTH_COMPONENTS.len()];
+        let mut value_key: String<48> = String::new();
+        write!(&mut value_key, "{}_{}", shared_health.id(), component.key).map_err(|_| JsonError::BufferFull)?;
+        write_discovery_topic(topic, device, component.platform, &value_key)?;
+        write_diagnostic_discovery(
+            content,
+            device,
+            component,
+            topic_prefix,
+            &value_key,
+            format_args!("{} {}", shared_health.name(), component.name),
+            "diagnostics")?;
+        return Ok(true);
+    }
+
+    match TELEMETRY_COMPONENTS.get(index - health_component_count) {
+        Some(component) => {
+            write_discovery_topic(topic, device, component.platform, component.key)?;
+            write_diagnostic_discovery(
+                content,
+                device,
+                component,
+                topic_prefix,
+                component.key,
+                format_args!("{}", component.name),
+                "telemetry")?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn write_sensor_discovery<const N: usize>(
+    content: &mut String<N>,
+    device: &DiscoveryDevice,
+    component: &SensorComponent,
+    topic_prefix: &str,
+) -> Result<(), JsonError> {
+    let mut json = JsonWriter::new(content);
+    json.begin_object()?;
+    write_discovery_device(&mut json, device)?;
+    if let Some(name) = component.name {
+        json.field_str("name", name)?;
+    }
+    json.field_str("device_class", component.device_class)?;
+    if let Some(unit_of_measurement) = component.unit_of_measurement {
+        json.field_str("unit_of_measurement", unit_of_measurement)?;
+    }
+    json.field_fmt("state_topic", format_args!("{}/device/{}/state", topic_prefix, device.id))?;
+    // Renders to "None" (unknown in Home Assistant) when the key is missing from the state
+    json.field_fmt("value_template", format_args!("{{{{ value_json.{} | default('None') }}}}", component.value_key))?;
+    json.field_fmt("unique_id", format_args!("{}_{}", device.id, component.unique_id_suffix))?;
+    write_discovery_availability(&mut json, device, topic_prefix)?;
+    json.end_object()
+}
+
+fn write_diagnostic_discovery<const N: usize>(
+    content: &mut String<N>,
+    device: &DiscoveryDevice,
+    component: &DiagnosticComponent,
+    topic_prefix: &str,
+    value_key: &str,
+    name: core::fmt::Arguments,
+    topic_suffix: &str,
+) -> Result<(), JsonError> {
+    let mut json = JsonWriter::new(content);
+    json.begin_object()?;
+    write_discovery_device(&mut json, device)?;
+    json.field_fmt("name", name)?;
+    json.field_str("entity_category", "diagnostic")?;
+    if let Some(device_class) = component.device_class {
+        json.field_str("device_class", device_class)?;
+    }
+    if let Some(unit_of_measurement) = component.unit_of_measurement {
+        json.field_str("unit_of_measurement", unit_of_measurement)?;
+    }
+    if component.platform == "binary_sensor" {
+        // Jinja renders JSON booleans as Python ones
+        json.field_str("payload_on", "True")?;
+        json.field_str("payload_off", "False")?;
+    }
+    json.field_fmt("state_topic", format_args!("{}/device/{}/{}", topic_prefix, device.id, topic_suffix))?;
+    json.field_fmt("value_template", format_args!("{{{{ value_json.{} | default('None') }}}}", value_key))?;
+    json.field_fmt("unique_id", format_args!("{}_{}", device.id, value_key))?;
+    write_discovery_availability(&mut json, device, topic_prefix)?;
+    json.end_object()
+}
+
+/// Device and origin blocks, repeated in every component so that Home Assistant
+/// groups the entities under one device.
+fn write_discovery_device<const N: usize>(json: &mut JsonWriter<'_, N>, device: &DiscoveryDevice) -> Result<(), JsonError> {
+    json.key("dev")?;
+    json.begin_object()?;
+    json.field_str("ids", device.id)?;
+    json.field_str("name", device.name)?;
+    json.end_object()?;
+
+    json.key("o")?;
+    json.begin_object()?;
+    json.field_str("name", "air-quality-monitor")?;
+    json.field_str("sw", env!("CARGO_PKG_VERSION"))?;
+    json.field_str("url", "https://github.com/lomagno2003/air-quality-monitor")?;
+    json.end_object()
+}
+
+fn write_discovery_availability<const N: usize>(
+    json: &mut JsonWriter<'_, N>,
+    device: &DiscoveryDevice,
+    topic_prefix: &str,
+) -> Result<(), JsonError> {
+    json.field_fmt("availability_topic", format_args!("{}/device/{}/availability", topic_prefix, device.id))?;
+    json.field_str("payload_available", AVAILABILITY_ONLINE)?;
+    json.field_str("payload_not_available", AVAILABILITY_OFFLINE)?;
+    json.field_number("qos", 2)
+}
+
+fn write_discovery_topic<const T: usize>(
+    topic: &mut String<T>,
+    device: &DiscoveryDevice,
+    platform: &str,
+    object_id: &str,
+) -> Result<(), JsonError> {
+    topic.clear();
+    write!(topic, "{}/{}/{}/config", platform, device.id, object_id).map_err(|_| JsonError::BufferFull)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::{ClimateReading, ParticulateReading, SensorError};
+    use serde_json::Value;
+
+    const DEVICE: DiscoveryDevice = DiscoveryDevice { id: "aqm1", name: "Office" };
+
+    fn parse<const N: usize>(content: &String<N>) -> Value {
+        serde_json::from_str(content.as_str()).unwrap()
+    }
+
+    /// Topic and parsed config of every discovered entity, in discovery order.
+    fn discover_all(sensor_health: &[&SharedSensorHealth]) -> std::vec::Vec<(std::string::String, Value)> {
+        let mut components = std::vec::Vec::new();
+        for index in 0.. {
+            let mut topic: String<128> = String::new();
+            let mut content: String<1024> = String::new();
+            if !write_component_discovery(&mut topic, &mut content, &DEVICE, sensor_health, "homeassistant", index).unwrap() {
+                break;
+            }
+            components.push((std::string::String::from(topic.as_str()), parse(&content)));
+        }
+        components
+    }
+
+    #[test]
+    fn state_omits_missing_sensors() {
+        let mut readings = Readings::default();
+        readings.climate = Some(ClimateReading { co2: 612, temperature: 21.5, humidity: 40.25 });
+        readings.particulate = Some(ParticulateReading {
+            pm1_0_atm: 3,
+            pm2_5_atm: 5,
+            pm10_0_atm: 8,
+            beyond_0_3: 0,
+            beyond_0_5: 0,
+            beyond_1_0: 0,
+            beyond_2_5: 0,
+            beyond_5_0: 0,
+            beyond_10_0: 0,
+        });
+        let mut content: String<256> = String::new();
+
+        write_state(&mut content, &readings).unwrap();
+
+        let state = parse(&content);
+        assert_eq!(state["temperature"], 21.5);
+        assert_eq!(state["co2"], 612);
+        assert_eq!(state["humidity"], 40.25);
+        assert_eq!(state["pm2_5_atm"], 5);
+        assert!(state.get("voc_index").is_none());
+        assert!(state.get("nox_index").is_none());
+
+        write_state(&mut content, &Readings::default()).unwrap();
+        assert_eq!(parse(&content), serde_json::json!({}));
+    }
+
+    #[test]
+    fn diagnostics_report_health_attributes() {
+        let failing = SensorHealth {
+            consecutive_failures: 2,
+            total_failures: 7,
+            last_error: Some(SensorError::Timeout),
+            last_success_at: Some(Instant::from_secs(100)),
+            warmed_up: true,
+        };
+        let mut content: String<512> = String::new();
+
+        write_diagnostics(
+            &mut content,
+            [("scd41", failing), ("sgp41", SensorHealth::default())].into_iter(),
+            Instant::from_secs(130)).unwrap();
+
+        let diagnostics = parse(&content);
+        assert_eq!(diagnostics["scd41_consecutive_failures"], 2);
+        assert_eq!(diagnostics["scd41_total_failures"], 7);
+        assert_eq!(diagnostics["scd41_last_error"], "Timeout");
+        assert_eq!(diagnostics["scd41_last_success_age"], 30);
+        assert_eq!(diagnostics["scd41_warmed_up"], true);
+        assert_eq!(diagnostics["sgp41_total_failures"], 0);
+        assert_eq!(diagnostics["sgp41_warmed_up"], false);
+        assert!(diagnostics.get("sgp41_last_error").is_none());
+        assert!(diagnostics.get("sgp41_last_success_age").is_none());
+    }
+
+    #[test]
+    fn health_entities_are_declared_for_every_sensor() {
+        static SCD41: SharedSensorHealth = SharedSensorHealth::new("scd41", "SCD41");
+        static SGP41: SharedSensorHealth = SharedSensorHealth::new("sgp41", "SGP41");
+
+        let components = discover_all(&[&SCD41, &SGP41]);
+
+        assert_eq!(components.len(), SENSOR_COMPONENTS.len() + 2 * SENSOR_HEALTH_COMPONENTS.len() + TELEMETRY_COMPONENTS.len());
+        let (topic, config) = components.iter()
+            .find(|(topic, _)| topic == "binary_sensor/aqm1/sgp41_warmed_up/config")
+            .unwrap();
+        assert_eq!(config["name"], "SGP41 warmed up", "{}", topic);
+        assert_eq!(config["entity_category"], "diagnostic");
+        assert_eq!(config["payload_on"], "True");
+        assert_eq!(config["state_topic"], "homeassistant/device/aqm1/diagnostics");
+        assert_eq!(config["value_template"], "{{ value_json.sgp41_warmed_up | default('None') }}");
+        assert_eq!(config["unique_id"], "aqm1_sgp41_warmed_up");
+    }
+
+    #[test]
+    fn telemetry_entities_read_the_telemetry_payload() {
+        let telemetry = DeviceTelemetry {
+            rssi: None,
+            uptime_secs: 3600,
+            heap_free: 1024,
+            heap_used: 2048,
+            reset_reason: "PowerOn",
+        };
+        let mut content: String<256> = String::new();
+        write_telemetry(&mut content, &telemetry).unwrap();
+        let payload = parse(&content);
+        assert!(payload.get("rssi").is_none());
+
+        let components = discover_all(&[]);
+
+        let telemetry_components = &components[SENSOR_COMPONENTS.len()..];
+        assert_eq!(telemetry_components.len(), TELEMETRY_COMPONENTS.len());
+        for ((topic, config), component) in telemetry_components.iter().zip(TELEMETRY_COMPONENTS.iter()) {
+            assert_eq!(*topic, format!("sensor/aqm1/{}/config", component.key));
+            assert_eq!(config["state_topic"], "homeassistant/device/aqm1/telemetry");
+            assert_eq!(config["availability_topic"], "homeassistant/device/aqm1/availability");
+            if component.key != "rssi" {
+                assert!(payload.get(component.key).is_some(), "{} missing from the telemetry payload", component.key);
+            }
+        }
+        assert_eq!(payload["uptime"], 3600);
+        assert_eq!(payload["reset_reason"], "PowerOn");
+    }
+
+    #[test]
+    fn discovery_declares_the_device_and_firmware_version() {
+        let components = discover_all(&[]);
+
+        let (topic, config) = &components[0];
+        assert_eq!(topic, "sensor/aqm1/temperature/config");
+        assert_eq!(config["dev"], serde_json::json!({ "ids": "aqm1", "name": "Office" }));
+        assert_eq!(config["o"]["sw"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(config["unit_of_measurement"], "°C");
+        assert_eq!(config["state_topic"], "homeassistant/device/aqm1/state");
+    }
+}
@@ -0,0 +1,64 @@
+use core::fmt::Write as _;
+use embassy_net::tcp::TcpSocket;
+use embedded_io_async::Write;
+use heapless::String;
+use log::error;
+
+use crate::http_codec::{request_length, HttpError, HttpRequest};
+
+/// Reads from `socket` until a full request (headers and `Content-Length`
+/// bytes of body) is in `buffer`, then parses it.
+pub async fn read_request<'b>(socket: &mut TcpSocket<'_>, buffer: &'b mut [u8]) -> Result<HttpRequest<'b>, HttpError> {
+    let mut length = 0;
+    loop {
+        if let Some(request_length) = request_length(&buffer[..length], buffer.len())? {
+            return HttpRequest::parse(&buffer[..request_length]);
+        }
+        if length == buffer.len() {
+            return Err(HttpError::RequestTooLarge);
+        }
+
+        match socket.read(&mut buffer[length..]).await {
+            Ok(0) => return Err(HttpError::ConnectionClosed),
+            Ok(read) => length += read,
+            Err(e) => {
+                error!("HTTP: Failed to read request: {:?}", e);
+                return Err(HttpError::NetworkError);
+            }
+        }
+    }
+}
+
+/// Writes the status line and headers of a response whose body (of
+/// `content_length` bytes) the caller writes next.
+pub async fn write_response_head(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    content_length: usize,
+) -> Result<(), HttpError> {
+    let mut head: String<192> = String::new();
+    write!(
+        &mut head,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, content_type, content_length
+    ).map_err(|_| HttpError::RequestTooLarge)?;
+    write_all(socket, head.as_bytes()).await
+}
+
+pub async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), HttpError> {
+    write_response_head(socket, status, content_type, body.len()).await?;
+    write_all(socket, body).await
+}
+
+pub async fn write_all(socket: &mut TcpSocket<'_>, data: &[u8]) -> Result<(), HttpError> {
+    socket.write_all(data).await.map_err(|e| {
+        error!("HTTP: Failed to write response: {:?}", e);
+        HttpError::NetworkError
+    })
+}
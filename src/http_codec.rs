@@ -0,0 +1,254 @@
+//! Parsing of the HTTP requests received by the web servers, with the form
+//! and query string decoding, and escaping of the values put in pages. Only
+//! depends on `core` and `heapless`, so it can be exercised on the host without
+//! a network stack.
+
+use heapless::String;
+
+#[derive(Debug)]
+pub enum HttpError {
+    RequestTooLarge,
+    MalformedRequest,
+    ConnectionClosed,
+    NetworkError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Other,
+}
+
+/// Request parsed from a buffer filled by `http::read_request`. Headers other than
+/// `Content-Length` are ignored.
+pub struct HttpRequest<'b> {
+    pub method: HttpMethod,
+    pub path: &'b str,
+    pub query: &'b str,
+    pub body: &'b [u8],
+}
+
+impl<'b> HttpRequest<'b> {
+    pub fn parse(buffer: &'b [u8]) -> Result<Self, HttpError> {
+        let headers_end = find(buffer, b"\r\n\r\n").ok_or(HttpError::MalformedRequest)?;
+        let headers = core::str::from_utf8(&buffer[..headers_end]).map_err(|_| HttpError::MalformedRequest)?;
+
+        let request_line = headers.lines().next().ok_or(HttpError::MalformedRequest)?;
+        let mut parts = request_line.split(' ');
+        let method = match parts.next() {
+            Some("GET") => HttpMethod::Get,
+            Some("POST") => HttpMethod::Post,
+            Some(_) => HttpMethod::Other,
+            None => return Err(HttpError::MalformedRequest),
+        };
+        let target = parts.next().ok_or(HttpError::MalformedRequest)?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let body_start = headers_end + 4;
+        let body_end = body_start.checked_add(content_length(headers)?).ok_or(HttpError::RequestTooLarge)?;
+        if body_end > buffer.len() {
+            return Err(HttpError::RequestTooLarge);
+        }
+        let body = &buffer[body_start..body_end];
+
+        Ok(Self { method, path, query, body })
+    }
+
+    /// Value of `name` in the `application/x-www-form-urlencoded` body.
+    pub fn form_field<const N: usize>(&self, name: &str) -> Option<String<N>> {
+        url_encoded_field(self.body, name)
+    }
+
+    /// Value of `name` in the query string.
+    pub fn query_field<const N: usize>(&self, name: &str) -> Option<String<N>> {
+        url_encoded_field(self.query.as_bytes(), name)
+    }
+}
+
+/// Writes `value` with the characters that are special in HTML escaped.
+pub fn write_html_escaped<W: core::fmt::Write>(writer: &mut W, value: &str) -> core::fmt::Result {
+    for c in value.chars() {
+        match c {
+            '&' => writer.write_str("&amp;")?,
+            '<' => writer.write_str("&lt;")?,
+            '>' => writer.write_str("&gt;")?,
+            '"' => writer.write_str("&quot;")?,
+            '\'' => writer.write_str("&#39;")?,
+            c => writer.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Length of the request at the start of `buffer`, or `None` if it is not
+/// complete yet. Fails with `RequestTooLarge` as soon as the announced body
+/// cannot fit in `capacity` bytes, instead of waiting for it.
+pub fn request_length(buffer: &[u8], capacity: usize) -> Result<Option<usize>, HttpError> {
+    let Some(headers_end) = find(buffer, b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let headers = core::str::from_utf8(&buffer[..headers_end]).map_err(|_| HttpError::MalformedRequest)?;
+    let request_length = (headers_end + 4)
+        .checked_add(content_length(headers)?)
+        .filter(|&request_length| request_length <= capacity)
+        .ok_or(HttpError::RequestTooLarge)?;
+
+    Ok((buffer.len() >= request_length).then_some(request_length))
+}
+
+fn content_length(headers: &str) -> Result<usize, HttpError> {
+    for line in headers.lines().skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                return value.trim().parse().map_err(|_| HttpError::MalformedRequest);
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Decodes `name` from `key=value&...` pairs (`+` and `%XX` escapes).
+/// Returns `None` if the field is missing, not valid UTF-8 or longer than `N`.
+fn url_encoded_field<const N: usize>(encoded: &[u8], name: &str) -> Option<String<N>> {
+    let pair = encoded.split(|&b| b == b'&')
+        .find(|pair| pair.split(|&b| b == b'=').next() == Some(name.as_bytes()))?;
+    let value = pair.get(name.len() + 1..).unwrap_or(&[]);
+
+    let mut decoded: heapless::Vec<u8, N> = heapless::Vec::new();
+    let mut bytes = value.iter();
+    while let Some(&byte) = bytes.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = hex_value(*bytes.next()?)?;
+                let low = hex_value(*bytes.next()?)?;
+                high << 4 | low
+            }
+            byte => byte,
+        };
+        decoded.push(byte).ok()?;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPACITY: usize = 2048;
+
+    /// Form submitted by the provisioning page, as browsers encode it.
+    const FORM_POST: &[u8] = b"POST /save HTTP/1.1\r\n\
+        Host: 192.168.4.1\r\n\
+        Content-Type: application/x-www-form-urlencoded\r\n\
+        content-length: 59\r\n\
+        \r\n\
+        ssid=Caf%C3%A9+Wi-Fi&password=p%26ss%3Dw0rd%2B&device_name=";
+
+    fn escaped(value: &str) -> std::string::String {
+        let mut escaped = std::string::String::new();
+        write_html_escaped(&mut escaped, value).unwrap();
+        escaped
+    }
+
+    #[test]
+    fn parses_get_with_query() {
+        let request = HttpRequest::parse(b"GET /metrics?format=text&x HTTP/1.1\r\nHost: aqm.local\r\n\r\n").unwrap();
+
+        assert_eq!(request.method, HttpMethod::Get);
+        assert_eq!(request.path, "/metrics");
+        assert_eq!(request.query, "format=text&x");
+        assert!(request.body.is_empty());
+        assert_eq!(request.query_field::<8>("format").as_deref(), Some("text"));
+        assert_eq!(request.query_field::<8>("x").as_deref(), Some(""));
+        assert_eq!(request.query_field::<8>("y"), None);
+    }
+
+    #[test]
+    fn decodes_form_fields() {
+        let request = HttpRequest::parse(FORM_POST).unwrap();
+
+        assert_eq!(request.method, HttpMethod::Post);
+        assert_eq!(request.path, "/save");
+        assert_eq!(request.form_field::<32>("ssid").as_deref(), Some("Café Wi-Fi"));
+        assert_eq!(request.form_field::<64>("password").as_deref(), Some("p&ss=w0rd+"));
+        assert_eq!(request.form_field::<64>("device_name").as_deref(), Some(""));
+        // Names are matched whole
+        assert_eq!(request.form_field::<64>("device"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_form_values() {
+        assert_eq!(url_encoded_field::<8>(b"a=%4", "a"), None);
+        assert_eq!(url_encoded_field::<8>(b"a=%G1", "a"), None);
+        // Not UTF-8 once decoded
+        assert_eq!(url_encoded_field::<8>(b"a=%FF", "a"), None);
+        assert_eq!(url_encoded_field::<4>(b"a=12345", "a"), None);
+        assert_eq!(url_encoded_field::<4>(b"a=%2a%2A", "a").as_deref(), Some("**"));
+        assert_eq!(url_encoded_field::<4>(b"ab=1&a=2", "a").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn waits_for_the_whole_request() {
+        let headers_end = find(FORM_POST, b"\r\n\r\n").unwrap() + 4;
+
+        assert_eq!(request_length(&FORM_POST[..20], CAPACITY).ok(), Some(None));
+        assert_eq!(request_length(&FORM_POST[..headers_end], CAPACITY).ok(), Some(None));
+        assert_eq!(request_length(&FORM_POST[..FORM_POST.len() - 1], CAPACITY).ok(), Some(None));
+        assert_eq!(request_length(FORM_POST, CAPACITY).ok(), Some(Some(FORM_POST.len())));
+    }
+
+    #[test]
+    fn rejects_oversized_content_length() {
+        // Rejected as soon as the headers are in, without waiting for the body
+        let request = b"POST /save HTTP/1.1\r\nContent-Length: 4096\r\n\r\nssid=";
+        assert!(matches!(request_length(request, CAPACITY), Err(HttpError::RequestTooLarge)));
+
+        let request = b"POST /save HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n";
+        assert!(matches!(request_length(request, usize::MAX), Err(HttpError::RequestTooLarge)));
+        assert!(matches!(HttpRequest::parse(request), Err(HttpError::RequestTooLarge)));
+
+        let request = b"POST /save HTTP/1.1\r\nContent-Length: 18446744073709551616\r\n\r\n";
+        assert!(matches!(request_length(request, CAPACITY), Err(HttpError::MalformedRequest)));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(matches!(HttpRequest::parse(b"GET / HTTP/1.1\r\n"), Err(HttpError::MalformedRequest)));
+        assert!(matches!(HttpRequest::parse(b"GET\r\n\r\n"), Err(HttpError::MalformedRequest)));
+        assert!(matches!(
+            HttpRequest::parse(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            Err(HttpError::MalformedRequest),
+        ));
+        assert!(matches!(HttpRequest::parse(b"GET /\xff HTTP/1.1\r\n\r\n"), Err(HttpError::MalformedRequest)));
+        // Body shorter than announced
+        assert!(matches!(
+            HttpRequest::parse(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nssid"),
+            Err(HttpError::RequestTooLarge),
+        ));
+
+        let request = HttpRequest::parse(b"DELETE / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.method, HttpMethod::Other);
+    }
+
+    #[test]
+    fn escapes_html_special_characters() {
+        assert_eq!(escaped("<script>alert('x')</script>"), "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;");
+        assert_eq!(escaped("\" onfocus=\"x"), "&quot; onfocus=&quot;x");
+        assert_eq!(escaped("Tom & Jerry's Café"), "Tom &amp; Jerry&#39;s Café");
+    }
+}
@@ -0,0 +1,261 @@
+use core::fmt::{self, Display, Write};
+use heapless::String;
+
+#[derive(Debug)]
+pub enum JsonError {
+    BufferFull,
+}
+
+/// Minimal streaming JSON serializer writing into a fixed-capacity string.
+/// Separators between members are inserted automatically, so callers only
+/// describe the structure: `begin_object`, `key`, values, `end_object`.
+pub struct JsonWriter<'b, const N: usize> {
+    buffer: &'b mut String<N>,
+    first_in_container: bool,
+    after_key: bool,
+}
+
+impl<'b, const N: usize> JsonWriter<'b, N> {
+    pub fn new(buffer: &'b mut String<N>) -> Self {
+        buffer.clear();
+        Self {
+            buffer,
+            first_in_container: true,
+            after_key: false,
+        }
+    }
+
+    pub fn begin_object(&mut self) -> Result<(), JsonError> {
+        self.separate()?;
+        self.push('{')?;
+        self.first_in_container = true;
+        Ok(())
+    }
+
+    pub fn end_object(&mut self) -> Result<(), JsonError> {
+        self.push('}')?;
+        self.first_in_container = false;
+        Ok(())
+    }
+
+    pub fn begin_array(&mut self) -> Result<(), JsonError> {
+        self.separate()?;
+        self.push('[')?;
+        self.first_in_container = true;
+        Ok(())
+    }
+
+    pub fn end_array(&mut self) -> Result<(), JsonError> {
+        self.push(']')?;
+        self.first_in_container = false;
+        Ok(())
+    }
+
+    pub fn key(&mut self, key: &str) -> Result<(), JsonError> {
+        self.key_fmt(format_args!("{}", key))
+    }
+
+    /// Writes an object key built from format arguments.
+    pub fn key_fmt(&mut self, key: fmt::Arguments) -> Result<(), JsonError> {
+        self.separate()?;
+        self.push_escaped(key)?;
+        self.push(':')?;
+        self.after_key = true;
+        Ok(())
+    }
+
+    pub fn string(&mut self, value: &str) -> Result<(), JsonError> {
+        self.string_fmt(format_args!("{}", value))
+    }
+
+    /// Writes a string value built from format arguments, escaping it on the fly.
+    pub fn string_fmt(&mut self, value: fmt::Arguments) -> Result<(), JsonError> {
+        self.separate()?;
+        self.push_escaped(value)
+    }
+
+    /// Writes an integer or float. Non-finite floats are written as `null`.
+    pub fn number<T: Display>(&mut self, value: T) -> Result<(), JsonError> {
+        self.separate()?;
+        let start = self.buffer.len();
+        write!(self.buffer, "{}", value).map_err(|_| JsonError::BufferFull)?;
+        if matches!(&self.buffer[start..], "NaN" | "inf" | "-inf") {
+            self.buffer.truncate(start);
+            self.push_str("null")?;
+        }
+        Ok(())
+    }
+
+    pub fn boolean(&mut self, value: bool) -> Result<(), JsonError> {
+        self.separate()?;
+        self.push_str(if value { "true" } else { "false" })
+    }
+
+    pub fn null(&mut self) -> Result<(), JsonError> {
+        self.separate()?;
+        self.push_str("null")
+    }
+
+    pub fn field_str(&mut self, key: &str, value: &str) -> Result<(), JsonError> {
+        self.key(key)?;
+        self.string(value)
+    }
+
+    pub fn field_fmt(&mut self, key: &str, value: fmt::Arguments) -> Result<(), JsonError> {
+        self.key(key)?;
+        self.string_fmt(value)
+    }
+
+    pub fn field_number<T: Display>(&mut self, key: &str, value: T) -> Result<(), JsonError> {
+        self.key(key)?;
+        self.number(value)
+    }
+
+    pub fn field_bool(&mut self, key: &str, value: bool) -> Result<(), JsonError> {
+        self.key(key)?;
+        self.boolean(value)
+    }
+
+    fn separate(&mut self) -> Result<(), JsonError> {
+        if self.after_key {
+            self.after_key = false;
+            return Ok(());
+        }
+        if !self.first_in_container {
+            self.push(',')?;
+        }
+        self.first_in_container = false;
+        Ok(())
+    }
+
+    fn push(&mut self, ch: char) -> Result<(), JsonError> {
+        self.buffer.push(ch).map_err(|_| JsonError::BufferFull)
+    }
+
+    fn push_str(&mut self, value: &str) -> Result<(), JsonError> {
+        self.buffer.push_str(value).map_err(|_| JsonError::BufferFull)
+    }
+
+    fn push_escaped(&mut self, value: fmt::Arguments) -> Result<(), JsonError> {
+        self.push('"')?;
+        EscapingWriter { buffer: self.buffer }.write_fmt(value).map_err(|_| JsonError::BufferFull)?;
+        self.push('"')
+    }
+}
+
+/// Escapes quotes, backslashes and control characters while writing a string value.
+struct EscapingWriter<'b, const N: usize> {
+    buffer: &'b mut String<N>,
+}
+
+impl<'b, const N: usize> Write for EscapingWriter<'b, N> {
+    fn write_str(&mut self, value: &str) -> fmt::Result {
+        for ch in value.chars() {
+            match ch {
+                '"' => self.buffer.push_str("\\\"").map_err(|_| fmt::Error)?,
+                '\\' => self.buffer.push_str("\\\\").map_err(|_| fmt::Error)?,
+                '\n' => self.buffer.push_str("\\n").map_err(|_| fmt::Error)?,
+                '\r' => self.buffer.push_str("\\r").map_err(|_| fmt::Error)?,
+                '\t' => self.buffer.push_str("\\t").map_err(|_| fmt::Error)?,
+                ch if (ch as u32) < 0x20 => write!(self.buffer, "\\u{:04x}", ch as u32)?,
+                ch => self.buffer.push(ch).map_err(|_| fmt::Error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_quotes_backslashes_and_control_characters() {
+        let mut buffer = String::<128>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        json.begin_object().unwrap();
+        json.field_str("name", "Office \"north\" C:\\sensors").unwrap();
+        json.field_str("lines", "a\nb\r\tc\u{1}\u{1f}").unwrap();
+        json.field_fmt("say \"hi\"", format_args!("{}°", 21)).unwrap();
+        json.end_object().unwrap();
+        assert_eq!(buffer.as_str(),
+            r#"{"name":"Office \"north\" C:\\sensors","lines":"a\nb\r\tc\u0001\u001f","say \"hi\"":"21°"}"#);
+    }
+
+    #[test]
+    fn writes_non_finite_numbers_as_null() {
+        let mut buffer = String::<128>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        json.begin_array().unwrap();
+        json.number(f32::NAN).unwrap();
+        json.number(f32::INFINITY).unwrap();
+        json.number(f64::NEG_INFINITY).unwrap();
+        json.number(-1.5f32).unwrap();
+        json.number(42u16).unwrap();
+        json.end_array().unwrap();
+        assert_eq!(buffer.as_str(), "[null,null,null,-1.5,42]");
+    }
+
+    #[test]
+    fn separates_members_of_nested_containers() {
+        let mut buffer = String::<256>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        json.begin_object().unwrap();
+        json.key("device").unwrap();
+        json.begin_object().unwrap();
+        json.field_str("id", "aqm-1").unwrap();
+        json.key("sensors").unwrap();
+        json.begin_array().unwrap();
+        json.string("scd41").unwrap();
+        json.begin_object().unwrap();
+        json.field_bool("healthy", true).unwrap();
+        json.key("error").unwrap();
+        json.null().unwrap();
+        json.end_object().unwrap();
+        json.begin_array().unwrap();
+        json.end_array().unwrap();
+        json.end_array().unwrap();
+        json.end_object().unwrap();
+        json.key("empty").unwrap();
+        json.begin_object().unwrap();
+        json.end_object().unwrap();
+        json.field_number("co2", 612).unwrap();
+        json.end_object().unwrap();
+        assert_eq!(buffer.as_str(),
+            r#"{"device":{"id":"aqm-1","sensors":["scd41",{"healthy":true,"error":null},[]]},"empty":{},"co2":612}"#);
+    }
+
+    #[test]
+    fn new_clears_the_buffer() {
+        let mut buffer = String::<16>::try_from("stale").unwrap();
+        let mut json = JsonWriter::new(&mut buffer);
+        json.boolean(false).unwrap();
+        assert_eq!(buffer.as_str(), "false");
+    }
+
+    #[test]
+    fn reports_full_buffer() {
+        let mut buffer = String::<8>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        json.begin_object().unwrap();
+        assert!(matches!(json.field_str("key", "value"), Err(JsonError::BufferFull)));
+
+        // Escaping makes the value longer than it looks.
+        let mut buffer = String::<7>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        assert!(matches!(json.string("\"\"\""), Err(JsonError::BufferFull)));
+
+        let mut buffer = String::<4>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        assert!(matches!(json.number(123456), Err(JsonError::BufferFull)));
+
+        let mut buffer = String::<3>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        assert!(matches!(json.number(f32::NAN), Err(JsonError::BufferFull)));
+
+        let mut buffer = String::<1>::new();
+        let mut json = JsonWriter::new(&mut buffer);
+        json.begin_array().unwrap();
+        assert!(matches!(json.end_array(), Err(JsonError::BufferFull)));
+    }
+}
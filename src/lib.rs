@@ -1,6 +1,49 @@
-#![no_std]
+//! Firmware library for the air quality monitor.
+//!
+//! The protocol modules (`dns`, `dns_sd_query`, `mdns_cache`, `discovery`,
+//! `json`, `console`, `metrics`, `home_assistant_payload`, `http_codec`,
+//! `captive_portal`), the Wi-Fi network ranking (`wifi_networks`), the
+//! configuration record (`config_record`), the sensor types (`sensor`,
+//! `telemetry`) and drivers (`sensirion`, `pms5003`) do not depend on the
+//! hardware and are built for the host as well, so their unit tests run with
+//! `cargo +stable test --lib --target <host triple>`.
+//! Everything touching the radio, the network stack or the peripherals is only
+//! built for the ESP32.
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(target_arch = "xtensa")]
 pub mod wifi;
+pub mod wifi_networks;
+#[cfg(target_arch = "xtensa")]
 pub mod mqtt;
+pub mod dns;
+pub mod discovery;
+#[cfg(target_arch = "xtensa")]
 pub mod mdns;
-pub mod home_assistant;
\ No newline at end of file
+pub mod mdns_cache;
+#[cfg(target_arch = "xtensa")]
+pub mod dns_sd;
+pub mod dns_sd_query;
+#[cfg(target_arch = "xtensa")]
+pub mod broker_lookup;
+#[cfg(target_arch = "xtensa")]
+pub mod home_assistant;
+pub mod home_assistant_payload;
+pub mod json;
+pub mod sensor;
+pub mod telemetry;
+#[cfg(target_arch = "xtensa")]
+pub mod config;
+pub mod config_record;
+#[cfg(target_arch = "xtensa")]
+pub mod http;
+pub mod http_codec;
+#[cfg(target_arch = "xtensa")]
+pub mod provisioning;
+pub mod captive_portal;
+pub mod console;
+#[cfg(target_arch = "xtensa")]
+pub mod web;
+pub mod metrics;
+pub mod sensirion;
+pub mod pms5003;
@@ -1,16 +1,144 @@
 // src/mdns.rs
-#![allow(dead_code)]
 
-use core::net::{IpAddr, Ipv4Addr};
-use embassy_net::{udp, IpAddress, Ipv4Address, Stack};
+use core::cell::RefCell;
+use embassy_net::{udp, IpAddress, Ipv4Address, Ipv6Address, Stack};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Instant, Timer};
 use esp_hal_mdns::MdnsQuery;
-use log::info;
+use log::{error, info};
+
+use heapless::{String, Vec};
+
+use crate::discovery::{order_by_priority, ServiceEndpoint, DISCOVERY_MAX_INSTANCES};
+use crate::dns::{
+    DnsMessage, DnsName, DnsWriter, DNS_CLASS_IN, DNS_TYPE_A, DNS_TYPE_AAAA, DNS_TYPE_ANY, DNS_TYPE_PTR,
+    DNS_TYPE_SRV, DNS_TYPE_TXT,
+};
+use crate::mdns_cache::MdnsCache;
+
+/// Largest datagram received, an Ethernet MTU. Larger responses are truncated
+/// by the sender (RFC 6762 section 17).
+const MDNS_PACKET_SIZE: usize = 1500;
+/// Queries sent by `browse_service` hold a single question.
+const MDNS_QUERY_SIZE: usize = 512;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_IPV4_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_IPV6_GROUP: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_MAX_SERVICES: usize = 4;
+const MDNS_MAX_TXT_ENTRIES: usize = 4;
+const MDNS_MAX_QUESTIONS: usize = 8;
+const MDNS_RESPONSE_SIZE: usize = 1024;
+/// TTLs recommended by RFC 6762: host name records (A, SRV) expire faster than
+/// the others.
+const MDNS_HOST_TTL_SECS: u32 = 120;
+const MDNS_OTHER_TTL_SECS: u32 = 4500;
+/// Longest TTL given to legacy resolvers, which cache without following
+/// announcements (RFC 6762 section 6.7).
+const MDNS_LEGACY_TTL_SECS: u32 = 10;
+const MDNS_ANNOUNCEMENTS: usize = 2;
+const MDNS_ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(1);
+const DNS_SERVICES_ENUMERATION: &str = "_services._dns-sd._udp";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdnsError {
+    /// The link was down or had no IP configuration before the timeout.
+    NetworkUnavailable,
+    MulticastJoinFailed,
+    /// No UDP socket could be bound, e.g. none is left in the stack.
+    BindFailed,
+    /// No complete answer was received before the timeout.
+    Timeout,
+}
+
+const MDNS_QUERY_INTERVAL_MS: u64 = 1000;
+
+/// How often `respond` drops the records of `CACHE` whose TTL ran out.
+const MDNS_CACHE_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Records of the responses `respond` overhears, e.g. announcements of a
+/// broker that got a new address, and of those `browse_service` receives.
+static CACHE: Mutex<CriticalSectionRawMutex, RefCell<MdnsCache>> = Mutex::new(RefCell::new(MdnsCache::new()));
+static ANNOUNCEMENT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// A DNS-SD service advertised by `MdnsFacade::respond`, e.g. `_http._tcp`.
+pub struct MdnsService {
+    pub service_type: &'static str,
+    pub port: u16,
+    pub txt: Vec<(&'static str, &'static str), MDNS_MAX_TXT_ENTRIES>,
+}
+
+impl MdnsService {
+    pub fn new(service_type: &'static str, port: u16) -> Self {
+        Self {
+            service_type,
+            port,
+            txt: Vec::new(),
+        }
+    }
+
+    /// Adds a `key=value` entry to the TXT record. Entries beyond
+    /// `MDNS_MAX_TXT_ENTRIES` are ignored.
+    pub fn with_txt(mut self, key: &'static str, value: &'static str) -> Self {
+        if self.txt.push((key, value)).is_err() {
+            error!("MdnsService: Ignoring TXT entry {:?}, at most {} are supported", key, MDNS_MAX_TXT_ENTRIES);
+        }
+        self
+    }
+}
+
+/// Names answered by `MdnsFacade::respond`: `<hostname>.local` and, for every
+/// service, `<instance_name>.<service_type>.local`.
+pub struct MdnsResponderConfig {
+    pub hostname: &'static str,
+    pub instance_name: &'static str,
+    pub services: Vec<MdnsService, MDNS_MAX_SERVICES>,
+}
+
+impl MdnsResponderConfig {
+    pub fn new(hostname: &'static str, instance_name: &'static str) -> Self {
+        Self {
+            hostname,
+            instance_name,
+            services: Vec::new(),
+        }
+    }
+
+    pub fn with_service(mut self, service: MdnsService) -> Self {
+        if self.services.push(service).is_err() {
+            error!("MdnsResponderConfig: Ignoring service, at most {} are supported", MDNS_MAX_SERVICES);
+        }
+        self
+    }
+}
 
-use heapless::String;
+/// Question of a query, as repeated in legacy unicast responses.
+type MdnsQuestion = (String<64>, u16);
 
-const BUFF_SIZE: usize = 4096;
+/// Records answering a query, with the questions they answer.
+struct MatchedQuery {
+    answers: Vec<MdnsRecord, { 2 + 4 * MDNS_MAX_SERVICES }>,
+    questions: Vec<MdnsQuestion, MDNS_MAX_QUESTIONS>,
+    /// Whether a question asked for a unicast response.
+    unicast: bool,
+}
+
+/// Record of the responder, referring to services by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MdnsRecord {
+    /// A record of the host.
+    HostAddress,
+    /// AAAA record of the host.
+    HostAddressV6,
+    ServiceType(usize),
+    ServiceInstance(usize),
+    ServiceLocation(usize),
+    ServiceText(usize),
+}
 
+#[derive(Default)]
 pub struct MdnsFacade;
 
 impl MdnsFacade {
@@ -18,36 +146,37 @@ impl MdnsFacade {
         Self
     }
 
-    /// Browse `service_name` and return the first IPv4 address found.
-    /// Retries for ~5s total; loop/extend to taste.
-    pub async fn query_service<'s>(
+    /// Browse `service_name` for `window` and return every instance whose
+    /// address was received, in the order they should be tried (RFC 2782):
+    /// by ascending SRV priority, then weighted at random within a priority.
+    /// The window includes waiting for the network. Fails with `Timeout` when
+    /// no instance was resolved.
+    ///
+    /// Instances already resolved by records still in the cache are returned
+    /// without querying. Queries are sent from an ephemeral port, so that responders answer by
+    /// unicast (RFC 6762 section 6.7) and browsing works while `respond` holds
+    /// the mDNS port.
+    pub async fn browse_service<'s>(
         &self,
         service_name: &'static str, // e.g. "_mqtt._tcp.local"
         stack: &'static Stack<'s>,
-    ) -> (IpAddr, u16) {
-        loop {
-            if stack.is_link_up() {
-                info!("Network is up.");
-                if stack.config_v4().is_some() {
-                    info!("DHCP configured!");
-                    break;
-                }
-                info!("DHCP not configured yet. Waiting..");
-            } else {
-                info!("Network is down. Waiting..");
-            }
-            Timer::after_millis(400).await;
+        window: Duration,
+    ) -> Result<Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES>, MdnsError> {
+        let deadline = Instant::now() + window;
+        self.wait_for_network(stack, deadline).await?;
+        let prefer_ipv6 = stack.config_v4().is_none();
+        let mut endpoints = CACHE.lock(|cache| cache.borrow().resolve(service_name, Instant::now(), prefer_ipv6));
+        if !endpoints.is_empty() {
+            info!("mDNS: {} instance(s) of {:?} in the cache", endpoints.len(), service_name);
+            order_by_priority(&mut endpoints, random_source());
+            return Ok(endpoints);
         }
-
-        let _ = stack
-            .join_multicast_group(IpAddress::v4(224, 0, 0, 251))
-            .expect("mDNS: join multicast 224.0.0.251 failed");
+        self.join_multicast_group(stack)?;
 
         let mut rx_meta: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4];
-        let mut rx_buff: [u8; BUFF_SIZE] = [0; BUFF_SIZE];
+        let mut rx_buff: [u8; MDNS_PACKET_SIZE] = [0; MDNS_PACKET_SIZE];
         let mut tx_meta: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4];
-        let mut tx_buff: [u8; BUFF_SIZE] = [0; BUFF_SIZE];
-
+        let mut tx_buff: [u8; MDNS_QUERY_SIZE] = [0; MDNS_QUERY_SIZE];
         let mut sock = udp::UdpSocket::new(
             *stack,
             &mut rx_meta,
@@ -55,381 +184,430 @@ impl MdnsFacade {
             &mut tx_meta,
             &mut tx_buff,
         );
-        sock.bind(5353)
-            .expect("mDNS: bind(5353) failed — is another mDNS/responder running?");
+        // Ephemeral port
+        sock.bind(0).map_err(|e| {
+            error!("mDNS: bind failed: {:?}", e);
+            MdnsError::BindFailed
+        })?;
         sock.set_hop_limit(Some(255));
 
         let mut q = MdnsQuery::new(
             service_name,
-            1000, // resend interval ms
-            || Instant::now().as_millis() as u64,
+            MDNS_QUERY_INTERVAL_MS,
+            || Instant::now().as_millis(),
         );
-        let mdns_peer = (Ipv4Address::new(224, 0, 0, 251), 5353);
-        let deadline = Instant::now() + Duration::from_millis(5000);
-        let mut rx = [0u8; 1024];
+        let mut rx = [0u8; MDNS_PACKET_SIZE];
 
-        // Local state variables for caching partial mDNS records
-        let mut cached_hostname: Option<heapless::String<64>> = None;
-        let mut cached_port: Option<u16> = None;
-        let mut cached_ip: Option<[u8; 4]> = None;
-        let mut cache_time: Option<u64> = None;
-
-        loop {
+        while Instant::now() < deadline {
             if let Some(pkt) = q.should_send_mdns_packet() {
-                info!("mDNS: Querying service {:?}", service_name);
-                let _query = sock.send_to(pkt, mdns_peer).await;
-                info!("mDNS: Sent query {:?}", _query);
-            }
-            if let Ok((n, _peer)) = sock.recv_from(&mut rx).await {
-                info!("mDNS: Got response: {:?} {:?}", n, _peer);
-
-                let mut ascii: String<1024> = String::new();
-                for &b in &rx[..n] {
-                    let ch = if b.is_ascii_graphic() || b == b' ' {
-                        b as char
-                    } else {
-                        '.'
-                    };
-                    let _ = ascii.push(ch);
-                }
-                info!("mDNS: {} bytes: {}", n, ascii);
-
-                let current_time = Instant::now().as_millis() as u64;
-                let (ip_v4, port, _instance) = self.parse_with_state(
-                    &mut q,
-                    &rx[..n],
-                    current_time,
-                    service_name,
-                    &mut cached_hostname,
-                    &mut cached_port,
-                    &mut cached_ip,
-                    &mut cache_time,
-                );
-                info!("mDNS: Got response: {:?} {:?} {:?}", ip_v4, port, _instance);
-
-                if port != 0 && ip_v4 != [0, 0, 0, 0] {
-                    info!("mDNS: Got result: {:?} {:?}", ip_v4, port);
-                    return (
-                        IpAddr::V4(Ipv4Addr::new(ip_v4[0], ip_v4[1], ip_v4[2], ip_v4[3])),
-                        port,
-                    );
+                info!("mDNS: Browsing service {:?}", service_name);
+                for group in mdns_groups(stack) {
+                    if let Err(e) = sock.send_to(pkt, (group, MDNS_PORT)).await {
+                        info!("mDNS: Sending query to {:?} failed: {:?}", group, e);
+                    }
                 }
             }
-            if Instant::now() >= deadline {
-                // no result yet: back off a bit, then extend the window
-                Timer::after(Duration::from_millis(250)).await;
+            let next_query = Instant::now() + Duration::from_millis(MDNS_QUERY_INTERVAL_MS);
+            if let Either::First(Ok((n, _peer))) =
+                select(sock.recv_from(&mut rx), Timer::at(next_query.min(deadline))).await
+            {
+                CACHE.lock(|cache| cache.borrow_mut().insert_packet(&rx[..n], Instant::now()));
             }
-            Timer::after(Duration::from_millis(40)).await;
         }
-    }
 
-    /// Private helper method to handle stateful parsing logic
-    fn parse_with_state(
-        &self,
-        q: &mut MdnsQuery,
-        data: &[u8],
-        current_time: u64,
-        service_name: &'static str,
-        cached_hostname: &mut Option<heapless::String<64>>,
-        cached_port: &mut Option<u16>,
-        cached_ip: &mut Option<[u8; 4]>,
-        cache_time: &mut Option<u64>,
-    ) -> ([u8; 4], u16, Option<&str>) {
-        // Check for cache timeout and cleanup (30 seconds = 30000 milliseconds)
-        if let Some(cache_timestamp) = *cache_time {
-            if current_time.saturating_sub(cache_timestamp) > 30000 {
-                info!("mDNS: Cache cleanup - timeout reached after {}ms, clearing cached data (hostname: {:?}, port: {:?}, ip: {:?})", 
-                      current_time.saturating_sub(cache_timestamp), cached_hostname, cached_port, cached_ip);
-                *cached_hostname = None;
-                *cached_port = None;
-                *cached_ip = None;
-                *cache_time = None;
-            }
+        let mut endpoints = CACHE.lock(|cache| cache.borrow().resolve(service_name, Instant::now(), prefer_ipv6));
+        order_by_priority(&mut endpoints, random_source());
+        for endpoint in endpoints.iter() {
+            info!("mDNS: Found {}:{} (priority {}, weight {})",
+                endpoint.address, endpoint.port, endpoint.priority, endpoint.weight);
         }
-
-        // First try the existing bundled parsing approach (for Bonjour compatibility)
-        let (ip_v4, port, _instance) = q.parse_mdns_query(data, None);
-
-        // If we got a complete result, return it immediately
-        if port != 0 && ip_v4 != [0, 0, 0, 0] {
-            info!(
-                "mDNS: Complete service resolution via bundled records - IP: {:?}, port: {}",
-                ip_v4, port
-            );
-            return (ip_v4, port, None);
+        if endpoints.is_empty() {
+            info!("mDNS: No instance of {:?} resolved within the window", service_name);
+            return Err(MdnsError::Timeout);
         }
+        Ok(endpoints)
+    }
 
-        // If no complete result, try to parse individual SRV records for caching
-        if let Some((srv_hostname, srv_port)) = self.parse_srv_record(data) {
-            info!("mDNS: SRV record cached - service: {}, target hostname: {}, port: {}, timestamp: {}", 
-                  service_name, srv_hostname, srv_port, current_time);
-
-            // Check if we already have a matching A record cached
-            if let Some(cached_ip_addr) = cached_ip {
-                info!("mDNS: Complete service resolution via cached A record match - hostname: {}, IP: {:?}, port: {}", 
-                      srv_hostname, cached_ip_addr, srv_port);
-                return (*cached_ip_addr, srv_port, None);
+    /// Waits until `respond` overhears a response resolving instances of
+    /// `service_name`, and returns every instance announced so far.
+    pub async fn wait_for_announcement<'s>(
+        &self,
+        service_name: &str,
+        stack: &'static Stack<'s>,
+    ) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+        loop {
+            ANNOUNCEMENT.wait().await;
+            let prefer_ipv6 = stack.config_v4().is_none();
+            let endpoints = CACHE.lock(|cache| cache.borrow().resolve(service_name, Instant::now(), prefer_ipv6));
+            if !endpoints.is_empty() {
+                return endpoints;
             }
-
-            // Cache the SRV record data
-            *cached_hostname = Some(srv_hostname);
-            *cached_port = Some(srv_port);
-            *cache_time = Some(current_time);
         }
+    }
 
-        // Try to parse A records and match with cached SRV data
-        if let Some((a_hostname, a_ip)) = self.parse_a_record(data) {
-            info!(
-                "mDNS: A record received - hostname: {}, IP address: {}.{}.{}.{}",
-                a_hostname, a_ip[0], a_ip[1], a_ip[2], a_ip[3]
-            );
-
-            // Check if this A record matches a cached SRV record
-            if let (Some(cached_srv_hostname), Some(cached_srv_port)) =
-                (cached_hostname.as_ref(), cached_port)
-            {
-                if a_hostname == *cached_srv_hostname {
-                    info!("mDNS: Complete service resolution via A record match - hostname: {}, IP: {}.{}.{}.{}, port: {}", 
-                          a_hostname, a_ip[0], a_ip[1], a_ip[2], a_ip[3], cached_srv_port);
-                    return (a_ip, *cached_srv_port, None);
-                } else {
-                    info!(
-                        "mDNS: A record hostname '{}' does not match cached SRV hostname '{}'",
-                        a_hostname, cached_srv_hostname
-                    );
+    /// Waits until the stack has an IPv4 or IPv6 configuration, or fails at `deadline`.
+    async fn wait_for_network<'s>(&self, stack: &'static Stack<'s>, deadline: Instant) -> Result<(), MdnsError> {
+        loop {
+            if stack.is_link_up() {
+                if stack.is_config_up() {
+                    return Ok(());
                 }
+                info!("mDNS: IP not configured yet. Waiting..");
+            } else {
+                info!("mDNS: Network is down. Waiting..");
             }
-
-            // Cache the A record data for potential future SRV matching
-            *cached_ip = Some(a_ip);
-            if cache_time.is_none() {
-                *cache_time = Some(current_time);
-                info!("mDNS: A record cached for future SRV matching - hostname: {}, IP: {}.{}.{}.{}, timestamp: {}", 
-                      a_hostname, a_ip[0], a_ip[1], a_ip[2], a_ip[3], current_time);
+            if Instant::now() >= deadline {
+                return Err(MdnsError::NetworkUnavailable);
             }
+            Timer::after_millis(400).await;
         }
-
-        // Return the original result (likely empty if we got here)
-        (ip_v4, port, None)
     }
 
-    /// Parse SRV record from DNS packet data
-    /// Returns (hostname, port) if SRV record is found, None otherwise
-    fn parse_srv_record(&self, data: &[u8]) -> Option<(heapless::String<64>, u16)> {
-        if data.len() < 12 {
-            return None; // Too short to be a valid DNS packet
+    /// Joins the mDNS groups of both address families, so that an IPv4 address
+    /// leased after the link-local IPv6 one is served too. Fails only when
+    /// none could be joined.
+    fn join_multicast_group<'s>(&self, stack: &'static Stack<'s>) -> Result<(), MdnsError> {
+        let mut joined = false;
+        for group in [IpAddress::Ipv4(MDNS_IPV4_GROUP), IpAddress::Ipv6(MDNS_IPV6_GROUP)] {
+            match stack.join_multicast_group(group) {
+                Ok(_) => joined = true,
+                Err(e) => error!("mDNS: join multicast {:?} failed: {:?}", group, e),
+            }
         }
+        if joined { Ok(()) } else { Err(MdnsError::MulticastJoinFailed) }
+    }
 
-        // Skip DNS header (12 bytes)
-        let mut offset = 12;
-
-        // Skip question section - we need to find the answer section
-        let question_count = u16::from_be_bytes([data[4], data[5]]);
-        for _ in 0..question_count {
-            // Skip question name
-            offset = self.skip_dns_name(data, offset)?;
-            // Skip QTYPE (2 bytes) and QCLASS (2 bytes)
-            offset += 4;
+    /// Answers mDNS queries for the names in `config` forever, after announcing
+    /// them. Conflicting names on the network are not detected. Responses from
+    /// other hosts are cached for `wait_for_announcement` and `browse_service`.
+    pub async fn respond<'s>(&self, stack: &'static Stack<'s>, config: &MdnsResponderConfig) -> ! {
+        while !stack.is_config_up() {
+            Timer::after_millis(400).await;
         }
+        let _ = self.join_multicast_group(stack);
 
-        // Parse answer section
-        let answer_count = u16::from_be_bytes([data[6], data[7]]);
-        for _ in 0..answer_count {
-            let _record_start = offset;
-
-            // Skip name
-            offset = self.skip_dns_name(data, offset)?;
-
-            if offset + 10 > data.len() {
-                return None;
-            }
-
-            // Read TYPE (2 bytes)
-            let record_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
-            offset += 2;
-
-            // Skip CLASS (2 bytes) and TTL (4 bytes)
-            offset += 6;
-
-            // Read RDLENGTH (2 bytes)
-            let rdlength = u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
-            offset += 2;
-
-            if record_type == 33 {
-                // SRV record type
-                return self.parse_srv_rdata(data, offset, rdlength);
-            }
-
-            // Skip to next record
-            offset += rdlength;
+        let mut rx_meta: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4];
+        let mut rx_buff: [u8; MDNS_PACKET_SIZE] = [0; MDNS_PACKET_SIZE];
+        let mut tx_meta: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4];
+        let mut tx_buff: [u8; MDNS_RESPONSE_SIZE] = [0; MDNS_RESPONSE_SIZE];
+        let mut sock = udp::UdpSocket::new(
+            *stack,
+            &mut rx_meta,
+            &mut rx_buff,
+            &mut tx_meta,
+            &mut tx_buff,
+        );
+        if let Err(e) = sock.bind(MDNS_PORT) {
+            error!("mDNS: bind({}) failed: {:?}", MDNS_PORT, e);
         }
+        sock.set_hop_limit(Some(255));
 
-        None
-    }
-
-    /// Parse SRV record RDATA section
-    fn parse_srv_rdata(
-        &self,
-        data: &[u8],
-        offset: usize,
-        rdlength: usize,
-    ) -> Option<(heapless::String<64>, u16)> {
-        if rdlength < 6 || offset + rdlength > data.len() {
-            return None;
+        let mut response = [0u8; MDNS_RESPONSE_SIZE];
+        let mut every_record: Vec<MdnsRecord, { 2 + 4 * MDNS_MAX_SERVICES }> = Vec::new();
+        let _ = every_record.push(MdnsRecord::HostAddress);
+        let _ = every_record.push(MdnsRecord::HostAddressV6);
+        for index in 0..config.services.len() {
+            let _ = every_record.push(MdnsRecord::ServiceType(index));
+            let _ = every_record.push(MdnsRecord::ServiceInstance(index));
+            let _ = every_record.push(MdnsRecord::ServiceLocation(index));
+            let _ = every_record.push(MdnsRecord::ServiceText(index));
         }
-
-        // SRV RDATA format: Priority (2) + Weight (2) + Port (2) + Target (variable)
-        // Skip Priority (2 bytes) and Weight (2 bytes)
-        let port_offset = offset + 4;
-        let port = u16::from_be_bytes([data[port_offset], data[port_offset + 1]]);
-
-        // Parse target hostname starting at offset + 6
-        let hostname_offset = offset + 6;
-        if let Some(hostname) = self.parse_dns_name(data, hostname_offset) {
-            Some((hostname, port))
-        } else {
-            None
+        for _ in 0..MDNS_ANNOUNCEMENTS {
+            info!("mDNS: Announcing {}.local", config.hostname);
+            let addresses = HostAddresses::of(stack);
+            if let Some(length) = self.write_response(&mut response, 0, &[], &every_record, &[], config, &addresses) {
+                for group in mdns_groups(stack) {
+                    let _ = sock.send_to(&response[..length], (group, MDNS_PORT)).await;
+                }
+            }
+            Timer::after(MDNS_ANNOUNCEMENT_INTERVAL).await;
         }
-    }
 
-    /// Skip over a DNS name in the packet, handling compression
-    fn skip_dns_name(&self, data: &[u8], mut offset: usize) -> Option<usize> {
+        let mut rx = [0u8; MDNS_PACKET_SIZE];
+        let mut next_expiry = Instant::now() + MDNS_CACHE_EXPIRY_INTERVAL;
         loop {
-            if offset >= data.len() {
-                return None;
+            let (n, peer) = match select(sock.recv_from(&mut rx), Timer::at(next_expiry)).await {
+                Either::First(Ok(received)) => received,
+                Either::First(Err(e)) => {
+                    info!("mDNS: Receive failed: {:?}", e);
+                    continue;
+                }
+                Either::Second(_) => {
+                    CACHE.lock(|cache| cache.borrow_mut().expire(Instant::now()));
+                    next_expiry = Instant::now() + MDNS_CACHE_EXPIRY_INTERVAL;
+                    continue;
+                }
+            };
+            if CACHE.lock(|cache| cache.borrow_mut().insert_packet(&rx[..n], Instant::now())) {
+                ANNOUNCEMENT.signal(());
+                continue;
+            }
+            let Some(query) = self.match_questions(&rx[..n], config) else {
+                continue;
+            };
+
+            let mut additionals: Vec<MdnsRecord, { 2 + 4 * MDNS_MAX_SERVICES }> = Vec::new();
+            for record in query.answers.iter() {
+                let implied: &[MdnsRecord] = match *record {
+                    MdnsRecord::ServiceInstance(index) => &[
+                        MdnsRecord::ServiceLocation(index),
+                        MdnsRecord::ServiceText(index),
+                        MdnsRecord::HostAddress,
+                        MdnsRecord::HostAddressV6,
+                    ],
+                    MdnsRecord::ServiceLocation(_) => &[MdnsRecord::HostAddress, MdnsRecord::HostAddressV6],
+                    // RFC 6762 section 6.2
+                    MdnsRecord::HostAddress => &[MdnsRecord::HostAddressV6],
+                    MdnsRecord::HostAddressV6 => &[MdnsRecord::HostAddress],
+                    _ => &[],
+                };
+                for implied_record in implied {
+                    if !query.answers.contains(implied_record) && !additionals.contains(implied_record) {
+                        let _ = additionals.push(*implied_record);
+                    }
+                }
             }
 
-            let len = data[offset];
-
-            if len == 0 {
-                // End of name
-                return Some(offset + 1);
-            } else if len & 0xC0 == 0xC0 {
-                // Compression pointer - skip 2 bytes and we're done
-                return Some(offset + 2);
+            // Legacy resolvers (not sending from port 5353) and questions with the
+            // unicast-response bit get a direct reply. Legacy replies also echo the
+            // query id and questions, like a unicast DNS server would.
+            let legacy = peer.endpoint.port != MDNS_PORT;
+            let (id, questions) = if legacy {
+                (u16::from_be_bytes([rx[0], rx[1]]), query.questions.as_slice())
             } else {
-                // Regular label - skip length byte + label bytes
-                offset += 1 + len as usize;
+                (0, [].as_slice())
+            };
+            let addresses = HostAddresses::of(stack);
+            let Some(length) = self.write_response(
+                &mut response, id, questions, &query.answers, &additionals, config, &addresses) else {
+                continue;
+            };
+            // Multicast responses go to the group of the family the query came over
+            let group = match peer.endpoint.addr {
+                IpAddress::Ipv4(_) => IpAddress::Ipv4(MDNS_IPV4_GROUP),
+                IpAddress::Ipv6(_) => IpAddress::Ipv6(MDNS_IPV6_GROUP),
+            };
+            let sent = if legacy || query.unicast {
+                sock.send_to(&response[..length], peer.endpoint).await
+            } else {
+                sock.send_to(&response[..length], (group, MDNS_PORT)).await
+            };
+            if let Err(e) = sent {
+                info!("mDNS: Sending response failed: {:?}", e);
             }
         }
     }
 
-    /// Parse A record from DNS packet data
-    /// Returns (hostname, ip_address) if A record is found, None otherwise
-    fn parse_a_record(&self, data: &[u8]) -> Option<(heapless::String<64>, [u8; 4])> {
-        if data.len() < 12 {
-            return None; // Too short to be a valid DNS packet
-        }
-
-        // Skip DNS header (12 bytes)
-        let mut offset = 12;
-
-        // Skip question section - we need to find the answer section
-        let question_count = u16::from_be_bytes([data[4], data[5]]);
-        for _ in 0..question_count {
-            // Skip question name
-            offset = self.skip_dns_name(data, offset)?;
-            // Skip QTYPE (2 bytes) and QCLASS (2 bytes)
-            offset += 4;
+    /// Records answering the questions of the query in `data`, with the
+    /// questions they answer and whether a unicast response was requested.
+    /// `None` for responses and unrelated queries.
+    fn match_questions(&self, data: &[u8], config: &MdnsResponderConfig) -> Option<MatchedQuery> {
+        let message = DnsMessage::parse(data).ok()?;
+        if message.header().is_response() {
+            return None;
         }
 
-        // Parse answer section
-        let answer_count = u16::from_be_bytes([data[6], data[7]]);
-        for _ in 0..answer_count {
-            let _record_start = offset;
-
-            // Parse and store the name for this record
-            let hostname = self.parse_dns_name(data, offset)?;
-
-            // Skip name
-            offset = self.skip_dns_name(data, offset)?;
-
-            if offset + 10 > data.len() {
-                return None;
+        let mut answers: Vec<MdnsRecord, { 2 + 4 * MDNS_MAX_SERVICES }> = Vec::new();
+        let mut questions: Vec<MdnsQuestion, MDNS_MAX_QUESTIONS> = Vec::new();
+        let mut unicast = false;
+        for question in message.questions().take(MDNS_MAX_QUESTIONS) {
+            let question = question.ok()?;
+            if question.class != DNS_CLASS_IN {
+                continue;
             }
 
-            // Read TYPE (2 bytes)
-            let record_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
-            offset += 2;
-
-            // Skip CLASS (2 bytes) and TTL (4 bytes)
-            offset += 6;
-
-            // Read RDLENGTH (2 bytes)
-            let rdlength = u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
-            offset += 2;
-
-            if record_type == 1 && rdlength == 4 {
-                // A record type with 4-byte IPv4 address
-                if offset + 4 <= data.len() {
-                    let ip_addr = [
-                        data[offset],
-                        data[offset + 1],
-                        data[offset + 2],
-                        data[offset + 3],
-                    ];
-                    return Some((hostname, ip_addr));
+            let name = &question.name;
+            let wants = |record_type: u16| question.record_type == record_type || question.record_type == DNS_TYPE_ANY;
+            let mut matched: Vec<MdnsRecord, { 2 + 4 * MDNS_MAX_SERVICES }> = Vec::new();
+            if wants(DNS_TYPE_A) && name_matches(name, &[config.hostname, "local"]) {
+                let _ = matched.push(MdnsRecord::HostAddress);
+            }
+            if wants(DNS_TYPE_AAAA) && name_matches(name, &[config.hostname, "local"]) {
+                let _ = matched.push(MdnsRecord::HostAddressV6);
+            }
+            for (index, service) in config.services.iter().enumerate() {
+                if wants(DNS_TYPE_PTR) && name_matches(name, &[DNS_SERVICES_ENUMERATION, "local"]) {
+                    let _ = matched.push(MdnsRecord::ServiceType(index));
+                }
+                if wants(DNS_TYPE_PTR) && name_matches(name, &[service.service_type, "local"]) {
+                    let _ = matched.push(MdnsRecord::ServiceInstance(index));
+                }
+                let instance = [config.instance_name, service.service_type, "local"];
+                if wants(DNS_TYPE_SRV) && name_matches(name, &instance) {
+                    let _ = matched.push(MdnsRecord::ServiceLocation(index));
+                }
+                if wants(DNS_TYPE_TXT) && name_matches(name, &instance) {
+                    let _ = matched.push(MdnsRecord::ServiceText(index));
                 }
             }
 
-            // Skip to next record
-            offset += rdlength;
+            if !matched.is_empty() {
+                unicast |= question.unicast_response;
+                if let Some(name) = question.name.to_dotted_string() {
+                    let _ = questions.push((name, question.record_type));
+                }
+            }
+            for record in matched {
+                if !answers.contains(&record) {
+                    let _ = answers.push(record);
+                }
+            }
         }
 
-        None
+        (!answers.is_empty()).then_some(MatchedQuery { answers, questions, unicast })
     }
 
-    /// Parse a DNS name from the packet, handling compression
-    fn parse_dns_name(&self, data: &[u8], mut offset: usize) -> Option<heapless::String<64>> {
-        let mut result = heapless::String::<64>::new();
-        let mut first_label = true;
-
-        loop {
-            if offset >= data.len() {
-                return None;
-            }
-
-            let len = data[offset];
-
-            if len == 0 {
-                // End of name
-                break;
-            } else if len & 0xC0 == 0xC0 {
-                // Compression pointer - follow it
-                if offset + 1 >= data.len() {
-                    return None;
+    /// Writes an authoritative response with `answers` and `additionals` into
+    /// `buffer`, returning its length. A response repeating `questions` is a
+    /// legacy unicast response: its records have no cache-flush bit and a TTL
+    /// of at most `MDNS_LEGACY_TTL_SECS` (RFC 6762 section 6.7).
+    #[allow(clippy::too_many_arguments)]
+    fn write_response(
+        &self,
+        buffer: &mut [u8],
+        id: u16,
+        questions: &[MdnsQuestion],
+        answers: &[MdnsRecord],
+        additionals: &[MdnsRecord],
+        config: &MdnsResponderConfig,
+        addresses: &HostAddresses,
+    ) -> Option<usize> {
+        // Address records of a family the stack has no address for are left out
+        let present = |record: &&MdnsRecord| match record {
+            MdnsRecord::HostAddress => addresses.ipv4.is_some(),
+            MdnsRecord::HostAddressV6 => addresses.ipv6.is_some(),
+            _ => true,
+        };
+        let answers = answers.iter().filter(present);
+        let additionals = additionals.iter().filter(present);
+
+        let legacy = !questions.is_empty();
+        let unique = !legacy;
+        let ttl = |ttl: u32| if legacy { ttl.min(MDNS_LEGACY_TTL_SECS) } else { ttl };
+
+        let mut writer = DnsWriter::new(buffer);
+        writer.u16(id)?;
+        writer.u16(0x8400)?; // Response, authoritative answer
+        writer.u16(questions.len() as u16)?;
+        writer.u16(answers.clone().count() as u16)?;
+        writer.u16(0)?;
+        writer.u16(additionals.clone().count() as u16)?;
+
+        for (name, record_type) in questions {
+            writer.question(&[name], *record_type)?;
+        }
+        for record in answers.chain(additionals) {
+            let host = [config.hostname, "local"];
+            match *record {
+                MdnsRecord::HostAddress => {
+                    writer.record(&host, DNS_TYPE_A, unique, ttl(MDNS_HOST_TTL_SECS))?;
+                    let rdata = writer.begin_rdata()?;
+                    writer.bytes(&addresses.ipv4?)?;
+                    writer.end_rdata(rdata)?;
                 }
-                let pointer = ((len as u16 & 0x3F) << 8) | data[offset + 1] as u16;
-                offset = pointer as usize;
-                continue;
-            } else {
-                // Regular label
-                if !first_label {
-                    if result.push('.').is_err() {
-                        return None; // String full
-                    }
+                MdnsRecord::HostAddressV6 => {
+                    writer.record(&host, DNS_TYPE_AAAA, unique, ttl(MDNS_HOST_TTL_SECS))?;
+                    let rdata = writer.begin_rdata()?;
+                    writer.bytes(&addresses.ipv6?)?;
+                    writer.end_rdata(rdata)?;
                 }
-                first_label = false;
-
-                offset += 1;
-                if offset + len as usize > data.len() {
-                    return None;
+                MdnsRecord::ServiceType(index) => {
+                    let service = &config.services[index];
+                    writer.record(&[DNS_SERVICES_ENUMERATION, "local"], DNS_TYPE_PTR, false, ttl(MDNS_OTHER_TTL_SECS))?;
+                    let rdata = writer.begin_rdata()?;
+                    writer.name(&[service.service_type, "local"])?;
+                    writer.end_rdata(rdata)?;
                 }
-
-                for i in 0..len {
-                    let ch = data[offset + i as usize] as char;
-                    if result.push(ch).is_err() {
-                        return None; // String full
+                MdnsRecord::ServiceInstance(index) => {
+                    let service = &config.services[index];
+                    writer.record(&[service.service_type, "local"], DNS_TYPE_PTR, false, ttl(MDNS_OTHER_TTL_SECS))?;
+                    let rdata = writer.begin_rdata()?;
+                    writer.name(&[config.instance_name, service.service_type, "local"])?;
+                    writer.end_rdata(rdata)?;
+                }
+                MdnsRecord::ServiceLocation(index) => {
+                    let service = &config.services[index];
+                    let instance = [config.instance_name, service.service_type, "local"];
+                    writer.record(&instance, DNS_TYPE_SRV, unique, ttl(MDNS_HOST_TTL_SECS))?;
+                    let rdata = writer.begin_rdata()?;
+                    writer.u16(0)?; // Priority
+                    writer.u16(0)?; // Weight
+                    writer.u16(service.port)?;
+                    writer.name(&host)?;
+                    writer.end_rdata(rdata)?;
+                }
+                MdnsRecord::ServiceText(index) => {
+                    let service = &config.services[index];
+                    let instance = [config.instance_name, service.service_type, "local"];
+                    writer.record(&instance, DNS_TYPE_TXT, unique, ttl(MDNS_OTHER_TTL_SECS))?;
+                    let rdata = writer.begin_rdata()?;
+                    if service.txt.is_empty() {
+                        writer.bytes(&[0])?;
                     }
+                    for (key, value) in service.txt.iter() {
+                        writer.bytes(&[u8::try_from(key.len() + 1 + value.len()).ok()?])?;
+                        writer.bytes(key.as_bytes())?;
+                        writer.bytes(b"=")?;
+                        writer.bytes(value.as_bytes())?;
+                    }
+                    writer.end_rdata(rdata)?;
                 }
-                offset += len as usize;
             }
         }
 
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+        Some(writer.position())
+    }
+}
+
+/// Addresses published in the A and AAAA records of the host.
+struct HostAddresses {
+    ipv4: Option<[u8; 4]>,
+    ipv6: Option<[u8; 16]>,
+}
+
+impl HostAddresses {
+    fn of(stack: &Stack<'_>) -> Self {
+        Self {
+            ipv4: stack.config_v4().map(|config| config.address.address().octets()),
+            ipv6: stack.config_v6().map(|config| config.address.address().octets()),
+        }
+    }
+}
+
+/// mDNS groups of the address families the stack is configured for.
+fn mdns_groups(stack: &Stack<'_>) -> Vec<IpAddress, 2> {
+    let mut groups = Vec::new();
+    if stack.config_v4().is_some() {
+        let _ = groups.push(IpAddress::Ipv4(MDNS_IPV4_GROUP));
+    }
+    if stack.config_v6().is_some() {
+        let _ = groups.push(IpAddress::Ipv6(MDNS_IPV6_GROUP));
+    }
+    groups
+}
+
+/// Pseudo-random numbers seeded from the clock (xorshift32), only used to
+/// spread clients over equally weighted instances.
+pub fn random_source() -> impl FnMut() -> u32 {
+    let mut seed = (Instant::now().as_ticks() as u32) | 1;
+    move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    }
+}
+
+
+/// Whether `name` equals `parts` joined with dots, ignoring ASCII case.
+fn name_matches(name: &DnsName<'_>, parts: &[&str]) -> bool {
+    let mut labels = name.labels();
+    let mut expected = parts.iter().flat_map(|part| part.split('.'));
+    loop {
+        match (labels.next(), expected.next()) {
+            (Some(label), Some(expected_label)) if label.eq_ignore_ascii_case(expected_label.as_bytes()) => continue,
+            (None, None) => return true,
+            _ => return false,
         }
     }
 }
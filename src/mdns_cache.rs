@@ -0,0 +1,412 @@
+//! Cache of the records received over mDNS and unicast DNS-SD, joined into
+//! service instances when resolving. Only depends on `core`, `heapless` and
+//! `embassy-time`, so it can be exercised on the host without a network stack.
+
+use core::mem::discriminant;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+use log::info;
+
+use crate::discovery::{ServiceEndpoint, ServiceTxt, DISCOVERY_MAX_INSTANCES};
+use crate::dns::{DnsMessage, DnsRecord, DnsRecordData, DnsTxt};
+
+pub const MDNS_CACHE_CAPACITY: usize = 16;
+const MDNS_CACHE_TXT_SIZE: usize = 128;
+/// Records of a set flushed by a cache-flush record are kept if they were
+/// received this recently, as part of the same announcement (RFC 6762 section 10.2).
+const MDNS_CACHE_FLUSH_GRACE: Duration = Duration::from_secs(1);
+/// Goodbye records (TTL 0) remove a record after this delay (RFC 6762 section 10.1).
+const MDNS_GOODBYE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+enum CachedData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String<64>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String<64>,
+    },
+    /// Raw RDATA, only parsed into `ServiceTxt` when resolving.
+    Txt(Vec<u8, MDNS_CACHE_TXT_SIZE>),
+}
+
+struct CachedRecord {
+    name: String<64>,
+    data: CachedData,
+    received_at: Instant,
+    expires_at: Instant,
+}
+
+/// Records received from every responder, kept until their TTL runs out.
+/// When full, the record closest to expiring is evicted.
+pub struct MdnsCache {
+    records: Vec<CachedRecord, MDNS_CACHE_CAPACITY>,
+}
+
+impl MdnsCache {
+    pub const fn new() -> Self {
+        Self {
+            records: Vec::new(),
+        }
+    }
+
+    /// Caches the records of every section of a response. Queries are
+    /// ignored. Returns whether `data` was a response.
+    pub fn insert_packet(&mut self, data: &[u8], now: Instant) -> bool {
+        if !DnsMessage::parse(data).is_ok_and(|message| message.header().is_response()) {
+            return false;
+        }
+        for record in dns_records(data) {
+            self.insert(&record, now);
+        }
+        true
+    }
+
+    /// Caches an A, AAAA, PTR, SRV or TXT record. Other types are ignored.
+    pub fn insert(&mut self, record: &DnsRecord<'_>, now: Instant) {
+        let Some(name) = record.name.to_dotted_string::<64>() else {
+            info!("mDNS: Not caching {}, name too long", record.name);
+            return;
+        };
+        let data = match record.data {
+            DnsRecordData::A(address) => CachedData::A(address),
+            DnsRecordData::Aaaa(address) => CachedData::Aaaa(address),
+            DnsRecordData::Ptr(target) => match target.to_dotted_string() {
+                Some(target) => CachedData::Ptr(target),
+                None => return,
+            },
+            DnsRecordData::Srv { priority, weight, port, target } => match target.to_dotted_string() {
+                Some(target) => CachedData::Srv { priority, weight, port, target },
+                None => return,
+            },
+            DnsRecordData::Txt(txt) => match Vec::from_slice(txt.as_bytes()) {
+                Ok(txt) => CachedData::Txt(txt),
+                Err(_) => return,
+            },
+            _ => return,
+        };
+
+        let same_set = |cached: &CachedRecord| {
+            cached.name.eq_ignore_ascii_case(&name) && discriminant(&cached.data) == discriminant(&data)
+        };
+        // Goodbyes of unique records carry the cache-flush bit too, they must not
+        // remove the record before `MDNS_GOODBYE_DELAY`.
+        if record.cache_flush && record.ttl != 0 {
+            self.records.retain(|cached| {
+                !same_set(cached) || now.saturating_duration_since(cached.received_at) <= MDNS_CACHE_FLUSH_GRACE
+            });
+        }
+
+        if let Some(cached) = self.records.iter_mut().find(|cached| same_set(cached) && cached.data == data) {
+            if record.ttl == 0 {
+                cached.expires_at = now + MDNS_GOODBYE_DELAY;
+            } else {
+                cached.received_at = now;
+                cached.expires_at = now + Duration::from_secs(record.ttl as u64);
+            }
+            return;
+        }
+        if record.ttl == 0 {
+            return;
+        }
+
+        if self.records.is_full() {
+            let soonest = self.records.iter()
+                .enumerate()
+                .min_by_key(|(_, cached)| cached.expires_at)
+                .map(|(index, _)| index);
+            if let Some(index) = soonest {
+                self.records.swap_remove(index);
+            }
+        }
+        let _ = self.records.push(CachedRecord {
+            name,
+            data,
+            received_at: now,
+            expires_at: now + Duration::from_secs(record.ttl as u64),
+        });
+    }
+
+    /// Drops the records whose TTL ran out.
+    pub fn expire(&mut self, now: Instant) {
+        self.records.retain(|cached| cached.expires_at > now);
+    }
+
+    /// Instances of `service_name` (e.g. `_mqtt._tcp.local`) with an address,
+    /// joining their PTR, SRV, TXT and A or AAAA records, which may have been
+    /// received in separate packets and from several responders. Instances
+    /// with both get their IPv4 address, unless `prefer_ipv6`.
+    pub fn resolve(&self, service_name: &str, now: Instant, prefer_ipv6: bool) -> Vec<ServiceEndpoint, DISCOVERY_MAX_INSTANCES> {
+        let live = || self.records.iter().filter(move |cached| cached.expires_at > now);
+        let mut endpoints = Vec::new();
+
+        let instances = live().filter_map(|cached| match &cached.data {
+            CachedData::Ptr(instance) if cached.name.eq_ignore_ascii_case(service_name) => Some(instance),
+            _ => None,
+        });
+        for instance in instances {
+            let srv = live().find_map(|cached| match &cached.data {
+                CachedData::Srv { priority, weight, port, target } if cached.name.eq_ignore_ascii_case(instance) => {
+                    Some((*priority, *weight, *port, target))
+                }
+                _ => None,
+            });
+            let Some((priority, weight, port, target)) = srv else {
+                continue;
+            };
+            let ipv4 = live().find_map(|cached| match cached.data {
+                CachedData::A(address) if cached.name.eq_ignore_ascii_case(target) => Some(IpAddr::V4(address)),
+                _ => None,
+            });
+            let ipv6 = live().find_map(|cached| match cached.data {
+                CachedData::Aaaa(address) if cached.name.eq_ignore_ascii_case(target) => Some(IpAddr::V6(address)),
+                _ => None,
+            });
+            let address = if prefer_ipv6 { ipv6.or(ipv4) } else { ipv4.or(ipv6) };
+            let Some(address) = address else {
+                continue;
+            };
+            let txt = live().find_map(|cached| match &cached.data {
+                CachedData::Txt(txt) if cached.name.eq_ignore_ascii_case(instance) => {
+                    Some(ServiceTxt::parse(&DnsTxt::new(txt)))
+                }
+                _ => None,
+            });
+
+            let endpoint = ServiceEndpoint {
+                address,
+                port,
+                priority,
+                weight,
+                txt: txt.unwrap_or_default(),
+            };
+            if endpoints.push(endpoint).is_err() {
+                info!("mDNS: Ignoring instances beyond {}", DISCOVERY_MAX_INSTANCES);
+                break;
+            }
+        }
+        endpoints
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl Default for MdnsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records of every section of the packet in `data`, up to the first malformed one.
+fn dns_records(data: &[u8]) -> impl Iterator<Item = DnsRecord<'_>> {
+    DnsMessage::parse(data)
+        .map(|message| message.records())
+        .into_iter()
+        .flatten()
+        .map_while(|record| record.inspect_err(|e| info!("mDNS: Malformed packet: {:?}", e)).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsWriter, DNS_TYPE_A, DNS_TYPE_AAAA, DNS_TYPE_PTR, DNS_TYPE_SRV, DNS_TYPE_TXT};
+
+    enum Data<'a> {
+        A([u8; 4]),
+        Aaaa(Ipv6Addr),
+        Ptr(&'a str),
+        Srv { port: u16, target: &'a str },
+        Txt(&'a [u8]),
+    }
+
+    /// A response holding `records` as answers, unique ones with the cache-flush bit.
+    fn response(records: &[(&str, Data<'_>, bool, u32)]) -> std::vec::Vec<u8> {
+        let mut buffer = [0u8; 1024];
+        let mut writer = DnsWriter::new(&mut buffer);
+        writer.bytes(&[0x00, 0x00, 0x84, 0x00, 0x00, 0x00]).unwrap();
+        writer.u16(records.len() as u16).unwrap();
+        writer.bytes(&[0x00, 0x00, 0x00, 0x00]).unwrap();
+        for (name, data, unique, ttl) in records {
+            let record_type = match data {
+                Data::A(_) => DNS_TYPE_A,
+                Data::Aaaa(_) => DNS_TYPE_AAAA,
+                Data::Ptr(_) => DNS_TYPE_PTR,
+                Data::Srv { .. } => DNS_TYPE_SRV,
+                Data::Txt(_) => DNS_TYPE_TXT,
+            };
+            writer.record(&[name], record_type, *unique, *ttl).unwrap();
+            let length_position = writer.begin_rdata().unwrap();
+            match data {
+                Data::A(address) => writer.bytes(address).unwrap(),
+                Data::Aaaa(address) => writer.bytes(&address.octets()).unwrap(),
+                Data::Ptr(target) => writer.name(&[target]).unwrap(),
+                Data::Srv { port, target } => {
+                    writer.bytes(&[0x00, 0x00, 0x00, 0x00]).unwrap();
+                    writer.u16(*port).unwrap();
+                    writer.name(&[target]).unwrap();
+                }
+                Data::Txt(txt) => writer.bytes(txt).unwrap(),
+            }
+            writer.end_rdata(length_position).unwrap();
+        }
+        let length = writer.position();
+        buffer[..length].to_vec()
+    }
+
+    fn broker(address: [u8; 4], ttl: u32) -> std::vec::Vec<u8> {
+        response(&[
+            ("_mqtt._tcp.local", Data::Ptr("Broker._mqtt._tcp.local"), false, 4500),
+            ("Broker._mqtt._tcp.local", Data::Srv { port: 1883, target: "mqtt-pi.local" }, true, 120),
+            ("Broker._mqtt._tcp.local", Data::Txt(b"\x0bprefix=home"), true, 4500),
+            ("mqtt-pi.local", Data::A(address), true, ttl),
+        ])
+    }
+
+    fn at(secs: u64) -> Instant {
+        Instant::from_secs(secs)
+    }
+
+    fn addresses(cache: &MdnsCache, now: Instant) -> std::vec::Vec<IpAddr> {
+        cache.resolve("_mqtt._tcp.local", now, false).iter().map(|endpoint| endpoint.address).collect()
+    }
+
+    #[test]
+    fn instances_are_joined_across_packets() {
+        let mut cache = MdnsCache::new();
+        let ptr = response(&[("_mqtt._tcp.local", Data::Ptr("Broker._mqtt._tcp.local"), false, 4500)]);
+        let records = response(&[
+            ("Broker._mqtt._tcp.local", Data::Srv { port: 1883, target: "mqtt-pi.local" }, true, 120),
+            ("Broker._mqtt._tcp.local", Data::Txt(b"\x0bprefix=home"), true, 4500),
+            ("MQTT-PI.local", Data::A([192, 168, 1, 20]), true, 120),
+            ("mqtt-pi.local", Data::Aaaa(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x20)), true, 120),
+        ]);
+
+        assert!(cache.insert_packet(&ptr, at(0)));
+        assert!(cache.insert_packet(&records, at(1)));
+
+        let endpoints = cache.resolve("_mqtt._TCP.local", at(2), false);
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].socket_addr(), "192.168.1.20:1883".parse().unwrap());
+        assert_eq!(endpoints[0].txt.get("prefix"), Some("home"));
+        let endpoints = cache.resolve("_mqtt._tcp.local", at(2), true);
+        assert_eq!(endpoints[0].socket_addr(), "[fd00::20]:1883".parse().unwrap());
+    }
+
+    #[test]
+    fn queries_are_not_cached() {
+        let mut cache = MdnsCache::new();
+        let mut query = broker([192, 168, 1, 20], 120);
+        query[2] = 0x00;
+
+        assert!(!cache.insert_packet(&query, at(0)));
+        assert!(!cache.insert_packet(&[0x00, 0x00, 0x84], at(0)));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn records_expire_with_their_ttl() {
+        let mut cache = MdnsCache::new();
+        cache.insert_packet(&broker([192, 168, 1, 20], 120), at(0));
+
+        assert_eq!(addresses(&cache, at(119)), ["192.168.1.20".parse::<IpAddr>().unwrap()]);
+        assert!(addresses(&cache, at(120)).is_empty());
+
+        cache.expire(at(120));
+        // The SRV record had the same TTL as the address, PTR and TXT remain
+        assert_eq!(cache.len(), 2);
+        cache.expire(at(4500));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn refreshed_records_live_on() {
+        let mut cache = MdnsCache::new();
+        cache.insert_packet(&broker([192, 168, 1, 20], 120), at(0));
+        cache.insert_packet(&broker([192, 168, 1, 20], 120), at(100));
+
+        assert_eq!(cache.len(), 4);
+        assert_eq!(addresses(&cache, at(200)), ["192.168.1.20".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn goodbye_removes_a_record_after_a_delay() {
+        let mut cache = MdnsCache::new();
+        cache.insert_packet(&broker([192, 168, 1, 20], 120), at(0));
+        cache.insert_packet(&response(&[("mqtt-pi.local", Data::A([192, 168, 1, 20]), true, 0)]), at(10));
+
+        assert_eq!(addresses(&cache, at(10)).len(), 1);
+        assert!(addresses(&cache, at(11)).is_empty());
+
+        // A goodbye for a record that is not cached adds nothing
+        cache.insert_packet(&response(&[("other.local", Data::A([192, 168, 1, 30]), true, 0)]), at(10));
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn cache_flush_replaces_older_records_of_the_set() {
+        let mut cache = MdnsCache::new();
+        cache.insert_packet(&broker([192, 168, 1, 20], 120), at(0));
+
+        // The broker got a new address
+        cache.insert_packet(&response(&[("mqtt-pi.local", Data::A([192, 168, 1, 30]), true, 120)]), at(60));
+
+        assert_eq!(addresses(&cache, at(61)), ["192.168.1.30".parse::<IpAddr>().unwrap()]);
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn cache_flush_keeps_records_of_the_same_announcement() {
+        let mut cache = MdnsCache::new();
+        let first = response(&[("mqtt-pi.local", Data::A([192, 168, 1, 20]), true, 120)]);
+        let second = response(&[("mqtt-pi.local", Data::A([10, 0, 0, 20]), true, 120)]);
+
+        cache.insert_packet(&first, at(10));
+        cache.insert_packet(&second, at(10) + Duration::from_millis(500));
+        assert_eq!(cache.len(), 2);
+
+        // Shared records (no cache-flush bit) never replace the others
+        let shared = response(&[("mqtt-pi.local", Data::A([10, 0, 0, 30]), false, 120)]);
+        cache.insert_packet(&shared, at(20));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn full_cache_evicts_the_record_closest_to_expiring() {
+        let mut cache = MdnsCache::new();
+        for index in 0..MDNS_CACHE_CAPACITY {
+            let name = std::format!("host-{}.local", index);
+            // host-3 expires first
+            let ttl = if index == 3 { 60 } else { 120 + index as u32 };
+            cache.insert_packet(&response(&[(&name, Data::A([10, 0, 0, index as u8]), true, ttl)]), at(0));
+        }
+        assert_eq!(cache.len(), MDNS_CACHE_CAPACITY);
+
+        cache.insert_packet(&response(&[("new.local", Data::A([10, 0, 1, 1]), true, 120)]), at(1));
+
+        assert_eq!(cache.len(), MDNS_CACHE_CAPACITY);
+        assert!(cache.records.iter().any(|cached| cached.name == "new.local"));
+        assert!(!cache.records.iter().any(|cached| cached.name == "host-3.local"));
+    }
+
+    #[test]
+    fn instances_without_address_are_not_resolved() {
+        let mut cache = MdnsCache::new();
+        let records = response(&[
+            ("_mqtt._tcp.local", Data::Ptr("Broker._mqtt._tcp.local"), false, 4500),
+            ("Broker._mqtt._tcp.local", Data::Srv { port: 1883, target: "mqtt-pi.local" }, true, 120),
+        ]);
+        cache.insert_packet(&records, at(0));
+
+        assert!(addresses(&cache, at(1)).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use core::fmt::{self, Display, Write};
+use heapless::String;
+
+#[derive(Debug)]
+pub enum MetricsError {
+    BufferFull,
+}
+
+impl From<fmt::Error> for MetricsError {
+    fn from(_: fmt::Error) -> Self {
+        MetricsError::BufferFull
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+}
+
+/// Writes metrics in the Prometheus text exposition format into a
+/// fixed-capacity string. Every sample gets a `device_id` label.
+pub struct PrometheusWriter<'b, const N: usize> {
+    buffer: &'b mut String<N>,
+    device_id: &'b str,
+}
+
+impl<'b, const N: usize> PrometheusWriter<'b, N> {
+    pub fn new(buffer: &'b mut String<N>, device_id: &'b str) -> Self {
+        buffer.clear();
+        Self {
+            buffer,
+            device_id,
+        }
+    }
+
+    /// Writes the `# HELP` and `# TYPE` lines of a metric family. Must be
+    /// followed by the samples of that family.
+    pub fn family(&mut self, name: &str, metric_type: MetricType, help: &str) -> Result<(), MetricsError> {
+        let metric_type = match metric_type {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+        };
+        writeln!(self.buffer, "# HELP {} {}\n# TYPE {} {}", name, help, name, metric_type)?;
+        Ok(())
+    }
+
+    /// Writes a sample of `name` with the `device_id` label followed by `labels`.
+    pub fn sample<T: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: T) -> Result<(), MetricsError> {
+        write!(self.buffer, "{}{{device_id=\"", name)?;
+        write_label_value(self.buffer, self.device_id)?;
+        self.buffer.push('"').map_err(|_| MetricsError::BufferFull)?;
+        for (label, label_value) in labels {
+            write!(self.buffer, ",{}=\"", label)?;
+            write_label_value(self.buffer, label_value)?;
+            self.buffer.push('"').map_err(|_| MetricsError::BufferFull)?;
+        }
+        writeln!(self.buffer, "}} {}", value)?;
+        Ok(())
+    }
+}
+
+/// Label values escape backslashes, double quotes and line feeds.
+fn write_label_value<W: Write>(writer: &mut W, value: &str) -> fmt::Result {
+    for c in value.chars() {
+        match c {
+            '\\' => writer.write_str("\\\\")?,
+            '"' => writer.write_str("\\\"")?,
+            '\n' => writer.write_str("\\n")?,
+            c => writer.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn family_writes_help_and_type() {
+        let mut buffer: String<256> = String::new();
+        let mut writer = PrometheusWriter::new(&mut buffer, "aqm-01");
+
+        writer.family("aqm_co2_ppm", MetricType::Gauge, "CO2 concentration").unwrap();
+        writer.family("aqm_sensor_errors_total", MetricType::Counter, "Failed sensor reads").unwrap();
+
+        assert_eq!(
+            buffer.as_str(),
+            "# HELP aqm_co2_ppm CO2 concentration\n# TYPE aqm_co2_ppm gauge\n\
+             # HELP aqm_sensor_errors_total Failed sensor reads\n# TYPE aqm_sensor_errors_total counter\n",
+        );
+    }
+
+    #[test]
+    fn samples_start_with_the_device_id() {
+        let mut buffer: String<256> = String::new();
+        let mut writer = PrometheusWriter::new(&mut buffer, "aqm-01");
+
+        writer.sample("aqm_co2_ppm", &[], 612).unwrap();
+        writer.sample("aqm_sensor_errors_total", &[("sensor", "scd41"), ("bus", "i2c0")], 3).unwrap();
+        writer.sample("aqm_temperature_celsius", &[], 21.5).unwrap();
+
+        assert_eq!(
+            buffer.as_str(),
+            "aqm_co2_ppm{device_id=\"aqm-01\"} 612\n\
+             aqm_sensor_errors_total{device_id=\"aqm-01\",sensor=\"scd41\",bus=\"i2c0\"} 3\n\
+             aqm_temperature_celsius{device_id=\"aqm-01\"} 21.5\n",
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut escaped: String<64> = String::new();
+        write_label_value(&mut escaped, "C:\\sensors\n\"living room\"").unwrap();
+        assert_eq!(escaped.as_str(), "C:\\\\sensors\\n\\\"living room\\\"");
+
+        let mut buffer: String<128> = String::new();
+        let mut writer = PrometheusWriter::new(&mut buffer, "my \"monitor\"");
+        writer.sample("aqm_up", &[("location", "attic\\1")], 1).unwrap();
+        assert_eq!(buffer.as_str(), "aqm_up{device_id=\"my \\\"monitor\\\"\",location=\"attic\\\\1\"} 1\n");
+    }
+
+    #[test]
+    fn new_clears_the_buffer() {
+        let mut buffer: String<64> = String::try_from("stale").unwrap();
+
+        PrometheusWriter::new(&mut buffer, "aqm-01");
+
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn reports_full_buffer() {
+        let mut buffer: String<32> = String::new();
+        let mut writer = PrometheusWriter::new(&mut buffer, "aqm-01");
+        assert!(matches!(
+            writer.family("aqm_co2_ppm", MetricType::Gauge, "CO2 concentration"),
+            Err(MetricsError::BufferFull),
+        ));
+
+        // The sample takes 47 bytes, the last one missing is reported
+        let mut buffer: String<47> = String::new();
+        let mut writer = PrometheusWriter::new(&mut buffer, "aqm-01");
+        writer.sample("aqm_co2_ppm", &[("unit", "ppm")], 612).unwrap();
+        assert_eq!(buffer.len(), buffer.capacity());
+
+        let mut buffer: String<46> = String::new();
+        let mut writer = PrometheusWriter::new(&mut buffer, "aqm-01");
+        assert!(matches!(writer.sample("aqm_co2_ppm", &[("unit", "ppm")], 612), Err(MetricsError::BufferFull)));
+    }
+}
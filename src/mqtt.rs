@@ -1,62 +1,366 @@
-use core::net::IpAddr;
-use embassy_time::Timer;
-use embassy_net::{
-    tcp::client::{TcpClient, TcpClientState},
-    Stack,
-};
-use log::info;
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Instant, Timer};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use heapless::{String, Vec};
+use log::{error, info};
 use rust_mqtt::{
     client::{
-        client::MqttClient,
         client_config::{ClientConfig, MqttVersion},
+        raw_client::{Event, RawMqttClient},
     },
+    packet::v5::reason_codes::ReasonCode,
     utils::rng_generator::CountingRng,
-    
 };
 use rust_mqtt::packet::v5::publish_packet::QualityOfService;
-use embedded_nal_async::TcpConnect;
+use embedded_io_async::{ErrorType, Read, Write};
 use core::net::SocketAddr;
 
+use crate::json::JsonError;
+use crate::discovery::ServiceEndpoint;
+
+#[derive(Debug)]
+pub enum MqttError {
+    MessageTooLarge,
+    TooManySubscriptions,
+}
+
+impl From<JsonError> for MqttError {
+    fn from(_: JsonError) -> Self {
+        MqttError::MessageTooLarge
+    }
+}
+
+/// Topic and payloads used to announce whether the device is reachable. The
+/// offline payload is registered as the CONNECT Last Will, so the broker
+/// publishes it when the session dies without a clean disconnect.
+pub struct MqttAvailability {
+    pub topic: String<MQTT_TOPIC_SIZE>,
+    pub online_payload: &'static str,
+    pub offline_payload: &'static str,
+}
+
+/// Broker to connect to, with the options that apply to its sessions only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttBroker {
+    pub address: SocketAddr,
+    /// Sent as the CONNECT username.
+    pub username: Option<String<MQTT_USERNAME_SIZE>>,
+    /// Replaces `MqttFacadeConfig::topic_prefix`.
+    pub topic_prefix: Option<String<MQTT_TOPIC_PREFIX_SIZE>>,
+}
+
+impl MqttBroker {
+    /// Broker without options, e.g. configured by hand.
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            username: None,
+            topic_prefix: None,
+        }
+    }
+
+    /// Broker at `endpoint`, with the options it advertises in its DNS-SD TXT
+    /// record: `user` is sent as the CONNECT username and `prefix` replaces the
+    /// topic prefix. TLS is not supported, so brokers that require it (`tls=1`)
+    /// are rejected rather than connected to in plaintext.
+    pub fn advertised(endpoint: &ServiceEndpoint) -> Option<Self> {
+        if endpoint.txt.is_enabled("tls") {
+            error!("MqttBroker: Skipping {}, it requires TLS which is not supported", endpoint.socket_addr());
+            return None;
+        }
+        let mut broker = Self::new(endpoint.socket_addr());
+        if let Some(user) = endpoint.txt.get("user").filter(|user| !user.is_empty()) {
+            broker.username = String::try_from(user)
+                .inspect_err(|_| error!("MqttBroker: Ignoring username of {}, too long", broker.address))
+                .ok();
+        }
+        if let Some(prefix) = endpoint.txt.get("prefix").filter(|prefix| !prefix.is_empty()) {
+            broker.topic_prefix = String::try_from(prefix)
+                .inspect_err(|_| error!("MqttBroker: Ignoring topic prefix of {}, too long", broker.address))
+                .ok();
+        }
+        Some(broker)
+    }
+}
 
 pub struct MqttFacadeConfig {
-    pub broker_ip: IpAddr,
-    pub broker_port: u16,
+    /// Brokers in the order they are tried. `MqttFacade` moves on to the next
+    /// one when connecting fails, and wraps around after the last.
+    pub brokers: Vec<MqttBroker, MQTT_MAX_BROKERS>,
     pub client_id: &'static str,
+    /// Prepended to every topic published or subscribed to, unless the broker
+    /// has its own. Topics handed to `MqttFacade` are relative to it.
+    pub topic_prefix: &'static str,
+    pub availability: Option<MqttAvailability>,
 }
 
 impl MqttFacadeConfig {
-    pub fn new(broker_ip: IpAddr, broker_port: u16, client_id: &'static str) -> Self {
+    pub fn new(broker: MqttBroker, client_id: &'static str, topic_prefix: &'static str) -> Self {
+        let mut brokers = Vec::new();
+        let _ = brokers.push(broker);
         Self {
-            broker_ip,
-            broker_port,
+            brokers,
             client_id,
+            topic_prefix,
+            availability: None,
         }
     }
+
+    /// Adds a broker to try when the previous ones are unreachable.
+    pub fn with_fallback_broker(mut self, broker: MqttBroker) -> Self {
+        let address = broker.address;
+        if self.brokers.push(broker).is_err() {
+            error!("MqttFacadeConfig: Ignoring fallback broker {}, at most {} are supported", address, MQTT_MAX_BROKERS);
+        }
+        self
+    }
+
+    pub fn with_availability(mut self, availability: MqttAvailability) -> Self {
+        self.availability = Some(availability);
+        self
+    }
+
+    /// Index of the broker to try after the one at `broker_index` failed.
+    fn next_broker(&self, broker_index: usize) -> usize {
+        let next = (broker_index + 1) % self.brokers.len();
+        if next != broker_index {
+            info!("MqttFacade: Failing over to broker {}", self.brokers[next].address);
+        }
+        next
+    }
 }
 
-pub struct MqttMessage<'m> {
-    pub topic: &'m str,
-    pub content: &'m str,
+pub struct MqttMessage {
+    pub topic: String<MQTT_TOPIC_SIZE>,
+    pub content: String<MQTT_CONTENT_SIZE>,
 }
 
-impl<'m> MqttMessage<'m> {
-    pub fn new(mqtt_topic: &'m str, mqtt_message_content: &'m str) -> Self {
-        Self {
-            topic: mqtt_topic,
-            content: mqtt_message_content,
-        }
+impl MqttMessage {
+    pub fn new(mqtt_topic: &str, mqtt_message_content: &str) -> Result<Self, MqttError> {
+        let mut message = Self::with_topic(mqtt_topic)?;
+        message.content.push_str(mqtt_message_content).map_err(|_| {
+            error!("MqttMessage: Content for topic {:?} does not fit in {} bytes", mqtt_topic, MQTT_CONTENT_SIZE);
+            MqttError::MessageTooLarge
+        })?;
+        Ok(message)
+    }
+
+    /// Message with an empty content, to be filled in place by the caller.
+    pub fn with_topic(mqtt_topic: &str) -> Result<Self, MqttError> {
+        let mut topic = String::new();
+        topic.push_str(mqtt_topic).map_err(|_| {
+            error!("MqttMessage: Topic {:?} does not fit in {} bytes", mqtt_topic, MQTT_TOPIC_SIZE);
+            MqttError::MessageTooLarge
+        })?;
+        Ok(Self {
+            topic,
+            content: String::new(),
+        })
     }
 }
 
 
-const MQTT_SEND_BUFFER_SIZE: usize = 4096;
+const MQTT_SEND_BUFFER_SIZE: usize = MQTT_CONTENT_SIZE + 512;
 const MQTT_RECV_BUFFER_SIZE: usize = 2048;
 const TCP_SEND_BUFFER_SIZE: usize = 4096;
 const TCP_RECV_BUFFER_SIZE: usize = 2048;
 const QUALITY_OF_SERVICE: QualityOfService = QualityOfService::QoS1;
 
+pub const MQTT_TOPIC_SIZE: usize = 128;
+pub const MQTT_TOPIC_PREFIX_SIZE: usize = 32;
+/// Topic as sent to the broker, under the topic prefix.
+const MQTT_FULL_TOPIC_SIZE: usize = MQTT_TOPIC_PREFIX_SIZE + 1 + MQTT_TOPIC_SIZE;
+pub const MQTT_USERNAME_SIZE: usize = 64;
+/// Large enough for the biggest Home Assistant discovery message, which is sent
+/// one component at a time.
+pub const MQTT_CONTENT_SIZE: usize = 1024;
+const MQTT_OUTBOX_SIZE: usize = 2;
+const MQTT_MAX_SUBSCRIPTIONS: usize = 8;
+pub const MQTT_MAX_BROKERS: usize = 4;
+
+/// Keep-alive negotiated with the broker on CONNECT. A PINGREQ is sent after
+/// half of it has elapsed without any other traffic.
+const MQTT_KEEP_ALIVE_SECS: u16 = 60;
+/// Time the broker has to acknowledge a publish before the session is
+/// considered broken and the message is sent again on a new one.
+const MQTT_ACK_TIMEOUT_SECS: u64 = 10;
+const MQTT_RECONNECT_DELAY_MS: u64 = 2000;
+/// Consecutive failed connection attempts, over all brokers, after which the
+/// brokers are assumed to have moved and should be looked up again.
+const MQTT_REDISCOVERY_FAILURES: u32 = 3;
+
+static OUTBOX: Channel<CriticalSectionRawMutex, MqttMessage, MQTT_OUTBOX_SIZE> = Channel::new();
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static BROKER: Mutex<CriticalSectionRawMutex, Cell<Option<SocketAddr>>> = Mutex::new(Cell::new(None));
+static TOPIC_PREFIX: Mutex<CriticalSectionRawMutex, RefCell<String<MQTT_TOPIC_PREFIX_SIZE>>> =
+    Mutex::new(RefCell::new(String::new()));
+static NEW_BROKER: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static REDISCOVERY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static BROKER_UPDATE: Signal<CriticalSectionRawMutex, Vec<MqttBroker, MQTT_MAX_BROKERS>> = Signal::new();
+
+/// Handle used to hand messages over to the task running `MqttFacade::run`.
+#[derive(Clone, Copy)]
+pub struct MqttPublisher;
+
+impl MqttPublisher {
+    /// Queues `message` for publishing. Waits while the outbox is full.
+    pub async fn send_message(&self, message: MqttMessage) {
+        OUTBOX.send(message).await;
+    }
+
+    /// Whether a session with the broker is currently established.
+    pub fn is_connected(&self) -> bool {
+        CONNECTED.load(Ordering::Relaxed)
+    }
+
+    /// Broker currently connected to, or being connected to.
+    pub fn broker(&self) -> Option<SocketAddr> {
+        BROKER.lock(|broker| broker.get())
+    }
+
+    /// Topic prefix of the broker currently connected to, or being connected to.
+    pub fn topic_prefix(&self) -> String<MQTT_TOPIC_PREFIX_SIZE> {
+        TOPIC_PREFIX.lock(|topic_prefix| topic_prefix.borrow().clone())
+    }
+
+    /// Waits until a session is established with another broker than the
+    /// previous one, the first session included. Whoever follows that broker
+    /// (e.g. Home Assistant) has not seen the retained state of the device yet.
+    pub async fn wait_for_new_broker(&self) {
+        NEW_BROKER.wait().await
+    }
+
+    /// Waits until connecting failed `MQTT_REDISCOVERY_FAILURES` times in a
+    /// row, and again after as many further failures.
+    pub async fn wait_for_rediscovery(&self) {
+        REDISCOVERY.wait().await
+    }
+
+    /// Replaces the brokers to try, e.g. after they were discovered again.
+    /// Applied from the next connection attempt, an established session is
+    /// kept.
+    pub fn update_brokers(&self, brokers: Vec<MqttBroker, MQTT_MAX_BROKERS>) {
+        BROKER_UPDATE.signal(brokers);
+    }
+}
+
+/// Callback invoked from the MQTT task for every inbound message whose topic
+/// matches the filter it was registered with.
+pub type MqttHandler = fn(topic: &str, payload: &[u8]);
+
+struct Subscription {
+    topic_filter: String<MQTT_TOPIC_SIZE>,
+    handler: MqttHandler,
+}
+
+/// Routes inbound messages to the handlers registered for matching topic filters.
+pub struct MqttDispatcher {
+    _subscriptions: Vec<Subscription, MQTT_MAX_SUBSCRIPTIONS>,
+}
+
+impl Default for MqttDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MqttDispatcher {
+    pub const fn new() -> Self {
+        Self {
+            _subscriptions: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, topic_filter: &str, handler: MqttHandler) -> Result<(), MqttError> {
+        let mut subscription = Subscription {
+            topic_filter: String::new(),
+            handler,
+        };
+        subscription.topic_filter.push_str(topic_filter).map_err(|_| {
+            error!("MqttDispatcher: Topic filter {:?} does not fit in {} bytes", topic_filter, MQTT_TOPIC_SIZE);
+            MqttError::MessageTooLarge
+        })?;
+        self._subscriptions.push(subscription).map_err(|_| {
+            error!("MqttDispatcher: Cannot register more than {} subscriptions", MQTT_MAX_SUBSCRIPTIONS);
+            MqttError::TooManySubscriptions
+        })
+    }
+
+    pub fn topic_filters(&self) -> impl Iterator<Item = &str> {
+        self._subscriptions.iter().map(|subscription| subscription.topic_filter.as_str())
+    }
+
+    /// Calls every handler whose filter matches `topic`. Returns whether any did.
+    pub fn dispatch(&self, topic: &str, payload: &[u8]) -> bool {
+        let mut handled = false;
+        for subscription in self._subscriptions.iter() {
+            if topic_matches(subscription.topic_filter.as_str(), topic) {
+                (subscription.handler)(topic, payload);
+                handled = true;
+            }
+        }
+        handled
+    }
+}
+
+/// Matches `topic` against an MQTT topic filter, supporting the `+` (single
+/// level) and `#` (multi level) wildcards.
+pub fn topic_matches(topic_filter: &str, topic: &str) -> bool {
+    let mut filter_levels = topic_filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+type SharedSocket<'s> = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, TcpSocket<'s>>;
+
+/// Socket handed to the MQTT client, which reads and writes whole packets
+/// through it. `MqttFacade::run` keeps another reference to wait for inbound
+/// data: unlike reading a packet, waiting can be cancelled at any point, so
+/// the client only starts reading once data is there.
+struct MqttConnection<'c, 's> {
+    socket: &'c SharedSocket<'s>,
+}
+
+impl ErrorType for MqttConnection<'_, '_> {
+    type Error = embassy_net::tcp::Error;
+}
+
+impl Read for MqttConnection<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.socket.lock().await.read(buf).await
+    }
+}
+
+impl Write for MqttConnection<'_, '_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.socket.lock().await.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.lock().await.flush().await
+    }
+}
+
 pub struct MqttFacade {
     _config: MqttFacadeConfig,
+    _tcp_send_buffer: [u8; TCP_SEND_BUFFER_SIZE],
+    _tcp_receive_buffer: [u8; TCP_RECV_BUFFER_SIZE],
+    _dispatcher: MqttDispatcher,
     _send_buffer: [u8; MQTT_SEND_BUFFER_SIZE],
     _receive_buffer: [u8; MQTT_RECV_BUFFER_SIZE],
 }
@@ -65,93 +369,350 @@ impl MqttFacade {
     pub fn new(config: MqttFacadeConfig) -> Self {
         Self {
             _config: config,
+            _tcp_send_buffer: [0_u8; TCP_SEND_BUFFER_SIZE],
+            _tcp_receive_buffer: [0_u8; TCP_RECV_BUFFER_SIZE],
+            _dispatcher: MqttDispatcher::new(),
             _send_buffer: [0_u8; MQTT_SEND_BUFFER_SIZE],
             _receive_buffer: [0_u8; MQTT_RECV_BUFFER_SIZE],
         }
     }
 
-    pub async fn send_message<'s, 'm> (
-        &mut self,
-        stack: &'static Stack<'s>,
-        message: MqttMessage<'m>,
-    ) {
-        info!("MqttFacade: Sending message to host {:?}, port {:?}, topic {:?}, content {:?}",
-            self._config.broker_ip, self._config.broker_port, message.topic, message.content);
+    pub fn publisher(&self) -> MqttPublisher {
+        MqttPublisher
+    }
+
+    /// Registers `handler` for messages matching `topic_filter`. Subscriptions
+    /// are (re)sent to the broker every time the session is established, so
+    /// they must be registered before calling `run`. Like published topics,
+    /// `topic_filter` is relative to the topic prefix.
+    pub fn subscribe(&mut self, topic_filter: &str, handler: MqttHandler) -> Result<(), MqttError> {
+        self._dispatcher.register(topic_filter, handler)
+    }
+
+    /// Keeps a session with the broker open, publishes everything queued
+    /// through `MqttPublisher` and dispatches inbound messages to the
+    /// registered handlers. Broken connections are detected through failed
+    /// writes or reads and missing acknowledgements, after which the session
+    /// is re-established.
+    ///
+    /// Every inbound packet, including PUBACK and PINGRESP, is read in one
+    /// place, so a message arriving while a publish or ping is outstanding is
+    /// dispatched rather than mistaken for the acknowledgement. A read is only
+    /// started once data is available and is never cancelled half-way.
+    pub async fn run<'s>(&mut self, stack: &'static Stack<'s>) -> ! {
+        let mut pending: Option<MqttMessage> = None;
+        let mut broker_index = 0;
+        let mut failures: u32 = 0;
+        // Broker of the last established session.
+        let mut session_broker: Option<MqttBroker> = None;
+
         loop {
-            if !stack.is_link_up() {
-                info!("MqttFacade: Network is down. Waiting..");
-                Timer::after_millis(500).await;
-                continue;
-            } else {
-                info!("MqttFacade: Network is up!");
-            }
+            self.wait_for_network(stack).await;
 
-            if stack.config_v4().is_none() {
-                info!("MqttFacade: DHCP not configured yet. Waiting..");
-                Timer::after_millis(500).await;
-                continue;
-            } else {
-                info!("MqttFacade: DHCP configured!");
-                Timer::after_millis(1000).await;
+            if let Some(brokers) = BROKER_UPDATE.try_take() {
+                if !brokers.is_empty() && brokers != self._config.brokers {
+                    info!("MqttFacade: Brokers updated to {:?}", brokers);
+                    self._config.brokers = brokers;
+                    broker_index = 0;
+                }
             }
-            
-            info!("MqttFacade: Creating TCP client state...");
-            let state: TcpClientState<3, TCP_SEND_BUFFER_SIZE, TCP_RECV_BUFFER_SIZE> = TcpClientState::new();
-            info!("MqttFacade: TCP client state created");
-
-            let tcp_client = TcpClient::new(*stack, &state);
-            info!("MqttFacade: TCP client created, attempting connection...");
-
-            let tcp_connection = match tcp_client.connect(SocketAddr::new(
-                self._config.broker_ip, self._config.broker_port)).await {
-                Ok(tcp_connection) => {
-                    info!("MqttFacade: TCP connection established successfully");
-                    tcp_connection
-                },
+
+            let broker = self._config.brokers[broker_index].clone();
+            let topic_prefix = broker.topic_prefix.as_deref().unwrap_or(self._config.topic_prefix);
+            BROKER.lock(|current| current.set(Some(broker.address)));
+            TOPIC_PREFIX.lock(|current| {
+                let mut current = current.borrow_mut();
+                current.clear();
+                let _ = current.push_str(topic_prefix);
+            });
+            info!("MqttFacade: Connecting to host {:?}, port {:?}", broker.address.ip(), broker.address.port());
+
+            let mut socket = TcpSocket::new(*stack, &mut self._tcp_receive_buffer, &mut self._tcp_send_buffer);
+            socket.set_timeout(Some(Duration::from_secs(MQTT_KEEP_ALIVE_SECS as u64)));
+
+            match socket.connect(broker.address).await {
+                Ok(_) => info!("MqttFacade: TCP connection established successfully"),
                 Err(e) => {
                     info!("MqttFacade: TCP connection failed: {:?}", e);
-                    Timer::after_millis(500).await;
+                    broker_index = self._config.next_broker(broker_index);
+                    count_failure(&mut failures);
+                    Timer::after_millis(MQTT_RECONNECT_DELAY_MS).await;
                     continue;
                 }
             };
+            let socket = SharedSocket::new(socket);
 
-            info!("MqttFacade: Creating MQTT client...");
+            // Declared before the client configuration, which borrows it for the will
+            let availability_topic = match &self._config.availability {
+                Some(availability) => match prefixed_topic(topic_prefix, &availability.topic) {
+                    Ok(topic) => Some(topic),
+                    Err(e) => {
+                        error!("MqttFacade: Availability topic {:?} is too long: {:?}", availability.topic, e);
+                        None
+                    }
+                },
+                None => None,
+            };
             let mut mqtt_client_config: ClientConfig<'_, 5, CountingRng> =
                 ClientConfig::new(MqttVersion::MQTTv5, CountingRng(12345));
             mqtt_client_config.add_client_id(self._config.client_id);
-            let mut mqtt_client = MqttClient::new(
-                tcp_connection,
+            if let Some(username) = &broker.username {
+                mqtt_client_config.add_username(username.as_str());
+            }
+            mqtt_client_config.keep_alive = MQTT_KEEP_ALIVE_SECS;
+            mqtt_client_config.add_max_subscribe_qos(QUALITY_OF_SERVICE);
+            if let (Some(availability), Some(topic)) = (&self._config.availability, &availability_topic) {
+                mqtt_client_config.add_will(
+                    topic.as_str(),
+                    availability.offline_payload.as_bytes(),
+                    true);
+            }
+            let mut mqtt_client = RawMqttClient::new(
+                MqttConnection { socket: &socket },
                 &mut self._send_buffer,
                 MQTT_SEND_BUFFER_SIZE,
                 &mut self._receive_buffer,
                 MQTT_RECV_BUFFER_SIZE,
                 mqtt_client_config,
             );
-            info!("MqttFacade: MQTT client created, attempting broker connection...");
-            match mqtt_client.connect_to_broker().await {
-                Ok(_) => info!("MqttFacade: MQTT broker connection established"),
+
+            // Nothing but the CONNACK can arrive before the session exists, so
+            // it is the only packet waited for in line.
+            let connected = match mqtt_client.connect_to_broker().await {
+                Ok(_) => match mqtt_client.poll::<1>().await {
+                    Ok(Event::Connack) => Ok(()),
+                    Ok(Event::Disconnect(reason)) => Err(reason),
+                    Ok(_) => Err(ReasonCode::ImplementationSpecificError),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match connected {
+                Ok(_) => {
+                    info!("MqttFacade: MQTT broker connection established");
+                    failures = 0;
+                },
                 Err(e) => {
                     info!("MqttFacade: MQTT broker connection failed: {:?}", e);
-                    Timer::after_millis(500).await;
+                    broker_index = self._config.next_broker(broker_index);
+                    count_failure(&mut failures);
+                    Timer::after_millis(MQTT_RECONNECT_DELAY_MS).await;
                     continue;
                 }
             };
-            info!("MqttFacade: Attempting to send message...");
-            match mqtt_client.send_message(
-                message.topic, 
-                message.content.as_bytes(), 
-                QUALITY_OF_SERVICE, 
-                false).await {
-                    Ok(_) => {
-                        info!("MqttFacade: Message sent");
-                        break;
+
+            // Every filter goes in one SUBSCRIBE, whose SUBACK is waited for in
+            // line. Messages the broker sends ahead of it are dispatched. The
+            // PUBACK of the availability announcement is read by the loop below.
+            let mut topic_filters: Vec<String<MQTT_FULL_TOPIC_SIZE>, MQTT_MAX_SUBSCRIPTIONS> = Vec::new();
+            for topic_filter in self._dispatcher.topic_filters() {
+                match prefixed_topic(topic_prefix, topic_filter) {
+                    Ok(topic_filter) => {
+                        let _ = topic_filters.push(topic_filter);
+                    }
+                    Err(_) => error!("MqttFacade: Topic filter {:?} is too long", topic_filter),
+                }
+            }
+            let mut session_ready = true;
+            if !topic_filters.is_empty() {
+                let topic_names: heapless_08::Vec<&str, MQTT_MAX_SUBSCRIPTIONS> =
+                    topic_filters.iter().map(|topic_filter| topic_filter.as_str()).collect();
+                info!("MqttFacade: Subscribing to {:?}", topic_names);
+                let subscribed = match mqtt_client.subscribe_to_topics(&topic_names).await {
+                    Ok(packet_id) => loop {
+                        match mqtt_client.poll::<MQTT_MAX_SUBSCRIPTIONS>().await {
+                            Ok(Event::Suback(id)) if id == packet_id => break Ok(()),
+                            Ok(Event::Message(topic, payload)) =>
+                                dispatch_inbound(&self._dispatcher, topic_prefix, topic, payload),
+                            Ok(Event::Disconnect(reason)) => break Err(reason),
+                            Ok(_) => {}
+                            Err(e) => break Err(e),
+                        }
                     },
-                    Err(e) => {
-                        info!("MqttFacade: Message sending failed: {:?}", e);
-                        Timer::after_millis(500).await;
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = subscribed {
+                    info!("MqttFacade: Subscribing failed: {:?}", e);
+                    session_ready = false;
+                }
+            }
+            if session_ready {
+                if let (Some(availability), Some(topic)) = (&self._config.availability, &availability_topic) {
+                    match mqtt_client.send_message(
+                        topic.as_str(),
+                        availability.online_payload.as_bytes(),
+                        QUALITY_OF_SERVICE,
+                        true).await {
+                            Ok(_) => info!("MqttFacade: Announced availability on {:?}", topic),
+                            Err(e) => {
+                                info!("MqttFacade: Announcing availability failed: {:?}", e);
+                                session_ready = false;
+                            }
+                        };
+                }
+            }
+            if !session_ready {
+                broker_index = self._config.next_broker(broker_index);
+                count_failure(&mut failures);
+                Timer::after_millis(MQTT_RECONNECT_DELAY_MS).await;
+                continue;
+            }
+
+            CONNECTED.store(true, Ordering::Relaxed);
+            if session_broker.as_ref() != Some(&broker) {
+                NEW_BROKER.signal(());
+                session_broker = Some(broker.clone());
+            }
+            let ping_interval = Duration::from_secs(MQTT_KEEP_ALIVE_SECS as u64 / 2);
+            let ack_timeout = Duration::from_secs(MQTT_ACK_TIMEOUT_SECS);
+            let mut last_sent = Instant::now();
+            // Packet identifier and send time of `pending`, once published.
+            let mut awaiting_ack: Option<(u16, Instant)> = None;
+            let mut awaiting_ping = false;
+
+            loop {
+                if awaiting_ack.is_none() {
+                    if let Some(message) = &pending {
+                        let Ok(topic) = prefixed_topic(topic_prefix, &message.topic) else {
+                            error!("MqttFacade: Dropping message to {:?}, topic is too long", message.topic);
+                            pending = None;
+                            continue;
+                        };
+                        info!("MqttFacade: Sending message to topic {:?}, content {:?}",
+                            topic, message.content);
+                        match mqtt_client.send_message(
+                            topic.as_str(),
+                            message.content.as_bytes(),
+                            QUALITY_OF_SERVICE,
+                            false).await {
+                                Ok(packet_id) => {
+                                    last_sent = Instant::now();
+                                    awaiting_ack = Some((packet_id, last_sent));
+                                },
+                                Err(e) => {
+                                    info!("MqttFacade: Message sending failed: {:?}", e);
+                                    break;
+                                }
+                            };
                         continue;
                     }
+                }
+
+                let ping_at = last_sent + ping_interval;
+                let deadline = match awaiting_ack {
+                    Some((_, sent_at)) => ping_at.min(sent_at + ack_timeout),
+                    None => ping_at,
                 };
+                let ready_to_send = awaiting_ack.is_none();
+                // Bound to a variable so the futures, and with them the socket
+                // lock, are dropped before the client is used again.
+                let event = select3(
+                    async {
+                        if !ready_to_send {
+                            core::future::pending::<()>().await;
+                        }
+                        OUTBOX.receive().await
+                    },
+                    async { socket.lock().await.wait_read_ready().await },
+                    Timer::at(deadline),
+                ).await;
+
+                match event {
+                    Either3::First(message) => pending = Some(message),
+                    Either3::Second(_) => match mqtt_client.poll::<1>().await {
+                        Ok(Event::Message(topic, payload)) =>
+                            dispatch_inbound(&self._dispatcher, topic_prefix, topic, payload),
+                        Ok(Event::Puback(packet_id)) if awaiting_ack.is_some_and(|(id, _)| id == packet_id) => {
+                            info!("MqttFacade: Message sent");
+                            awaiting_ack = None;
+                            pending = None;
+                        }
+                        Ok(Event::Pingresp) => awaiting_ping = false,
+                        Ok(Event::Disconnect(reason)) => {
+                            info!("MqttFacade: Broker closed the session: {:?}", reason);
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(ReasonCode::NetworkError) => {
+                            info!("MqttFacade: Receiving failed: network error");
+                            break;
+                        }
+                        Err(e) => info!("MqttFacade: Ignoring inbound packet: {:?}", e),
+                    },
+                    Either3::Third(_) => {
+                        if awaiting_ack.is_some_and(|(_, sent_at)| sent_at + ack_timeout <= Instant::now()) {
+                            info!("MqttFacade: Message was not acknowledged in time");
+                            break;
+                        }
+                        if Instant::now() < ping_at {
+                            continue;
+                        }
+                        if awaiting_ping {
+                            info!("MqttFacade: Ping was not answered in time");
+                            break;
+                        }
+                        if let Err(e) = mqtt_client.send_ping().await {
+                            info!("MqttFacade: Ping failed: {:?}", e);
+                            break;
+                        }
+                        awaiting_ping = true;
+                        last_sent = Instant::now();
+                    }
+                }
+            }
+
+            CONNECTED.store(false, Ordering::Relaxed);
+            info!("MqttFacade: Connection lost. Reconnecting..");
+            Timer::after_millis(MQTT_RECONNECT_DELAY_MS).await;
+        }
+    }
+
+    async fn wait_for_network<'s>(&self, stack: &'static Stack<'s>) {
+        loop {
+            if !stack.is_link_up() {
+                info!("MqttFacade: Network is down. Waiting..");
+                Timer::after_millis(500).await;
+                continue;
+            }
+
+            if !stack.is_config_up() {
+                info!("MqttFacade: IP not configured yet. Waiting..");
+                Timer::after_millis(500).await;
+                continue;
+            }
+
+            info!("MqttFacade: Network is up and IP configured!");
+            break;
         }
     }
 }
+
+/// Hands a message received on `topic` to the handlers of `dispatcher`, with
+/// `topic_prefix` stripped.
+fn dispatch_inbound(dispatcher: &MqttDispatcher, topic_prefix: &str, topic: &str, payload: &[u8]) {
+    info!("MqttFacade: Received message on topic {:?}", topic);
+    let relative_topic = topic.strip_prefix(topic_prefix)
+        .and_then(|topic| topic.strip_prefix('/'));
+    if !relative_topic.is_some_and(|topic| dispatcher.dispatch(topic, payload)) {
+        info!("MqttFacade: No handler registered for topic {:?}", topic);
+    }
+}
+
+/// `topic` under `topic_prefix`, as sent to the broker.
+fn prefixed_topic(topic_prefix: &str, topic: &str) -> Result<String<MQTT_FULL_TOPIC_SIZE>, MqttError> {
+    let mut prefixed: String<MQTT_FULL_TOPIC_SIZE> = String::new();
+    prefixed.push_str(topic_prefix).map_err(|_| MqttError::MessageTooLarge)?;
+    prefixed.push('/').map_err(|_| MqttError::MessageTooLarge)?;
+    prefixed.push_str(topic).map_err(|_| MqttError::MessageTooLarge)?;
+    Ok(prefixed)
+}
+
+/// Counts a failed connection attempt, and asks for the brokers to be looked
+/// up again every `MQTT_REDISCOVERY_FAILURES` failures in a row.
+fn count_failure(failures: &mut u32) {
+    *failures += 1;
+    if *failures % MQTT_REDISCOVERY_FAILURES == 0 {
+        info!("MqttFacade: Connecting failed {} times in a row, requesting broker rediscovery", failures);
+        REDISCOVERY.signal(());
+    }
+}
@@ -0,0 +1,149 @@
+//! Async driver for the Plantower PMS5003 particulate matter sensor over any
+//! `embedded_io_async` serial port. In its default active mode the sensor
+//! sends a frame about every second; a read that is abandoned half-way is
+//! harmless, the next one resynchronizes on the frame header.
+
+use embedded_io_async::{Read, ReadExactError, Write};
+
+pub const PMS5003_FRAME_SIZE: usize = 32;
+const PMS5003_FRAME_HEADER: [u8; 2] = [0x42, 0x4D];
+/// Length announced in the frame: 13 data words and the checksum.
+const PMS5003_FRAME_LENGTH: u16 = 28;
+/// Sleep/wake-up command with data 1 (wake up), followed by its checksum.
+const PMS5003_WAKE_UP: [u8; 7] = [0x42, 0x4D, 0xE4, 0x00, 0x01, 0x01, 0x74];
+
+#[derive(Debug)]
+pub enum Pms5003Error<E> {
+    Serial(E),
+    /// The port was closed in the middle of a frame.
+    EndOfFile,
+    /// The frame does not announce the expected length.
+    InvalidFrame,
+    Checksum,
+}
+
+/// Concentrations (µg/m³, atmospheric environment) and particle counts
+/// (per 0.1 L of air) of one frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pms5003Frame {
+    pub pm1_0_atm: u16,
+    pub pm2_5_atm: u16,
+    pub pm10_atm: u16,
+    pub beyond_0_3: u16,
+    pub beyond_0_5: u16,
+    pub beyond_1_0: u16,
+    pub beyond_2_5: u16,
+    pub beyond_5_0: u16,
+    pub beyond_10_0: u16,
+}
+
+impl Pms5003Frame {
+    /// Decodes a whole frame, header included.
+    pub fn parse<E>(frame: &[u8; PMS5003_FRAME_SIZE]) -> Result<Self, Pms5003Error<E>> {
+        let word = |offset: usize| u16::from_be_bytes([frame[offset], frame[offset + 1]]);
+        if frame[..2] != PMS5003_FRAME_HEADER || word(2) != PMS5003_FRAME_LENGTH {
+            return Err(Pms5003Error::InvalidFrame);
+        }
+        let checksum = frame[..PMS5003_FRAME_SIZE - 2].iter().map(|&byte| byte as u16).fold(0u16, u16::wrapping_add);
+        if checksum != word(PMS5003_FRAME_SIZE - 2) {
+            return Err(Pms5003Error::Checksum);
+        }
+
+        // Words 1 to 3 are the concentrations under the factory (CF=1) conditions.
+        Ok(Self {
+            pm1_0_atm: word(10),
+            pm2_5_atm: word(12),
+            pm10_atm: word(14),
+            beyond_0_3: word(16),
+            beyond_0_5: word(18),
+            beyond_1_0: word(20),
+            beyond_2_5: word(22),
+            beyond_5_0: word(24),
+            beyond_10_0: word(26),
+        })
+    }
+}
+
+pub struct Pms5003<S> {
+    serial: S,
+}
+
+impl<S: Read + Write> Pms5003<S> {
+    pub fn new(serial: S) -> Self {
+        Self { serial }
+    }
+
+    pub async fn wake(&mut self) -> Result<(), Pms5003Error<S::Error>> {
+        self.serial.write_all(&PMS5003_WAKE_UP).await.map_err(Pms5003Error::Serial)?;
+        self.serial.flush().await.map_err(Pms5003Error::Serial)
+    }
+
+    /// Waits for the next frame, skipping bytes until its header.
+    pub async fn read(&mut self) -> Result<Pms5003Frame, Pms5003Error<S::Error>> {
+        let mut frame = [0u8; PMS5003_FRAME_SIZE];
+        let mut matched = 0;
+        while matched < PMS5003_FRAME_HEADER.len() {
+            self.read_exact(&mut frame[matched..matched + 1]).await?;
+            matched = match frame[matched] {
+                byte if byte == PMS5003_FRAME_HEADER[matched] => matched + 1,
+                byte if byte == PMS5003_FRAME_HEADER[0] => {
+                    frame[0] = byte;
+                    1
+                }
+                _ => 0,
+            };
+        }
+        self.read_exact(&mut frame[PMS5003_FRAME_HEADER.len()..]).await?;
+        Pms5003Frame::parse(&frame)
+    }
+
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Pms5003Error<S::Error>> {
+        self.serial.read_exact(buffer).await.map_err(|e| match e {
+            ReadExactError::UnexpectedEof => Pms5003Error::EndOfFile,
+            ReadExactError::Other(e) => Pms5003Error::Serial(e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame as sent by the PMS5003 in indoor air: PM2.5 of 12 µg/m³.
+    const FRAME: [u8; PMS5003_FRAME_SIZE] = [
+        0x42, 0x4D, 0x00, 0x1C, // header, length 28
+        0x00, 0x08, 0x00, 0x0C, 0x00, 0x0E, // PM1.0, PM2.5, PM10 (CF=1)
+        0x00, 0x08, 0x00, 0x0C, 0x00, 0x0E, // PM1.0, PM2.5, PM10 (atmospheric)
+        0x07, 0x3A, 0x02, 0x1F, 0x00, 0x5C, // particles beyond 0.3, 0.5 and 1.0 µm
+        0x00, 0x09, 0x00, 0x02, 0x00, 0x01, // particles beyond 2.5, 5.0 and 10 µm
+        0x97, 0x00, // firmware version, error code
+        0x02, 0x50, // checksum
+    ];
+
+    #[test]
+    fn parses_frame() {
+        let frame = Pms5003Frame::parse::<()>(&FRAME).unwrap();
+        assert_eq!(frame, Pms5003Frame {
+            pm1_0_atm: 8,
+            pm2_5_atm: 12,
+            pm10_atm: 14,
+            beyond_0_3: 1850,
+            beyond_0_5: 543,
+            beyond_1_0: 92,
+            beyond_2_5: 9,
+            beyond_5_0: 2,
+            beyond_10_0: 1,
+        });
+    }
+
+    #[test]
+    fn rejects_bad_checksum_and_length() {
+        let mut frame = FRAME;
+        frame[12] ^= 0x01;
+        assert!(matches!(Pms5003Frame::parse::<()>(&frame), Err(Pms5003Error::Checksum)));
+
+        let mut frame = FRAME;
+        frame[3] = 0x14;
+        assert!(matches!(Pms5003Frame::parse::<()>(&frame), Err(Pms5003Error::InvalidFrame)));
+    }
+}
@@ -0,0 +1,238 @@
+use core::fmt::Write;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::{tcp::TcpSocket, udp, IpAddress, IpEndpoint, Stack};
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
+use log::{error, info};
+
+use crate::captive_portal::{build_dhcp_response, build_dns_response, DhcpLeases};
+use crate::config_record::{DeviceConfig, StoredWiFiNetwork};
+use crate::http;
+use crate::http_codec::{self, HttpMethod, HttpRequest};
+use crate::wifi::ACCESS_POINT_ADDRESS;
+use crate::wifi_networks::MAX_KNOWN_NETWORKS;
+
+/// The access point SSID is this prefix followed by the device id.
+pub const PROVISIONING_SSID_PREFIX: &str = "AirQuality-";
+
+const HTTP_PORT: u16 = 80;
+const HTTP_BUFFER_SIZE: usize = 2048;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const PAGE_SIZE: usize = 3072;
+
+const DNS_PORT: u16 = 53;
+const DNS_BUFFER_SIZE: usize = 512;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_BUFFER_SIZE: usize = 1024;
+
+/// Captive portal served on the access point started by
+/// `WiFiFacade::start_access_point`. Clients get an address from a minimal
+/// DHCP server and every DNS name resolves to the device, so that phones and
+/// laptops open the configuration form on their own.
+pub struct ProvisioningPortal {
+    _config: &'static DeviceConfig,
+}
+
+impl ProvisioningPortal {
+    /// `config` pre-fills the form and provides the values that are not edited.
+    pub fn new(config: &'static DeviceConfig) -> Self {
+        Self {
+            _config: config,
+        }
+    }
+
+    /// Serves the portal until the form is submitted, then returns the
+    /// configuration to persist.
+    pub async fn run(&self, stack: Stack<'_>) -> DeviceConfig {
+        info!("ProvisioningPortal: Waiting for configuration on http://{}/", ACCESS_POINT_ADDRESS);
+        match select3(self.serve_http(stack), Self::serve_dns(stack), Self::serve_dhcp(stack)).await {
+            Either3::First(config) => config,
+            Either3::Second(never) | Either3::Third(never) => never,
+        }
+    }
+
+    async fn serve_http(&self, stack: Stack<'_>) -> DeviceConfig {
+        let mut rx_buffer = [0_u8; HTTP_BUFFER_SIZE];
+        let mut tx_buffer = [0_u8; HTTP_BUFFER_SIZE];
+        let mut request_buffer = [0_u8; HTTP_BUFFER_SIZE];
+
+        loop {
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(HTTP_TIMEOUT));
+            if let Err(e) = socket.accept(HTTP_PORT).await {
+                error!("ProvisioningPortal: Failed to accept connection: {:?}", e);
+                continue;
+            }
+
+            let saved_config = match http::read_request(&mut socket, &mut request_buffer).await {
+                Ok(request) => self.handle_request(&mut socket, &request).await,
+                Err(e) => {
+                    error!("ProvisioningPortal: Invalid request: {:?}", e);
+                    None
+                }
+            };
+
+            let _ = socket.flush().await;
+            socket.close();
+            Timer::after_millis(100).await;
+            socket.abort();
+
+            if let Some(config) = saved_config {
+                return config;
+            }
+        }
+    }
+
+    async fn handle_request(&self, socket: &mut TcpSocket<'_>, request: &HttpRequest<'_>) -> Option<DeviceConfig> {
+        let mut page: String<PAGE_SIZE> = String::new();
+
+        let (status, saved_config) = match (request.method, request.path) {
+            (HttpMethod::Post, "/save") => match self.apply_form(request) {
+                Ok(config) => {
+                    let _ = write!(&mut page, "{}", SAVED_PAGE);
+                    ("200 OK", Some(config))
+                }
+                Err(reason) => {
+                    let _ = self.write_form(&mut page, Some(reason));
+                    ("400 Bad Request", None)
+                }
+            },
+            // Any other URL gets the form, which is what triggers the captive
+            // portal prompt on the OS connectivity checks.
+            _ => {
+                let _ = self.write_form(&mut page, None);
+                ("200 OK", None)
+            }
+        };
+
+        if let Err(e) = http::write_response(socket, status, "text/html; charset=utf-8", page.as_bytes()).await {
+            error!("ProvisioningPortal: Failed to send page: {:?}", e);
+        }
+        saved_config
+    }
+
+    /// Builds the new configuration from the submitted form. The submitted
+    /// network becomes the preferred one, the other known networks are kept.
+    fn apply_form(&self, request: &HttpRequest<'_>) -> Result<DeviceConfig, &'static str> {
+        let ssid: String<32> = request.form_field("ssid")
+            .filter(|ssid: &String<32>| !ssid.is_empty())
+            .ok_or("SSID is required (at most 32 characters)")?;
+        let password: String<64> = request.form_field("password")
+            .ok_or("Password must be at most 64 characters")?;
+        let device_name: String<64> = request.form_field("device_name")
+            .ok_or("Device name must be at most 64 characters")?;
+        let mqtt_broker: String<64> = request.form_field("mqtt_broker")
+            .ok_or("Broker must be at most 64 characters")?;
+
+        let mut config = self._config.clone();
+        config.set("mqtt.broker", &mqtt_broker)
+            .map_err(|_| "Broker must be an ip:port or host:port address, or empty to only use discovery")?;
+        let mut wifi_networks: Vec<StoredWiFiNetwork, MAX_KNOWN_NETWORKS> = Vec::new();
+        let _ = wifi_networks.push(StoredWiFiNetwork { ssid: ssid.clone(), password });
+        for network in self._config.wifi_networks.iter().filter(|network| network.ssid != ssid) {
+            let _ = wifi_networks.push(network.clone());
+        }
+        config.wifi_networks = wifi_networks;
+        if !device_name.is_empty() {
+            config.device_name = device_name;
+        }
+
+        info!("ProvisioningPortal: Received configuration for network {:?}", ssid);
+        Ok(config)
+    }
+
+    fn write_form<const N: usize>(&self, page: &mut String<N>, error: Option<&str>) -> core::fmt::Result {
+        write!(page, "{}", FORM_PAGE_START)?;
+        if let Some(error) = error {
+            write!(page, "<p class=\"error\">")?;
+            http_codec::write_html_escaped(page, error)?;
+            write!(page, "</p>")?;
+        }
+        write!(page, "<form method=\"post\" action=\"/save\">\
+            <label>Wi-Fi network<input name=\"ssid\" maxlength=\"32\" required value=\"")?;
+        if let Some(network) = self._config.wifi_networks.first() {
+            http_codec::write_html_escaped(page, &network.ssid)?;
+        }
+        write!(page, "\"></label>\
+            <label>Password<input name=\"password\" type=\"password\" maxlength=\"64\"></label>\
+            <label>Device name<input name=\"device_name\" maxlength=\"64\" value=\"")?;
+        http_codec::write_html_escaped(page, &self._config.device_name)?;
+        write!(page, "\"></label>\
+            <label>MQTT broker (ip:port or host:port, used when discovery finds none)<input name=\"mqtt_broker\" maxlength=\"64\" value=\"")?;
+        http_codec::write_html_escaped(page, &self._config.mqtt_broker)?;
+        write!(page, "\"></label><button type=\"submit\">Save and reboot</button></form>{}", PAGE_END)
+    }
+
+    /// Answers every A query with the access point address.
+    async fn serve_dns(stack: Stack<'_>) -> ! {
+        let mut rx_meta = [udp::PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0_u8; DNS_BUFFER_SIZE];
+        let mut tx_meta = [udp::PacketMetadata::EMPTY; 4];
+        let mut tx_buffer = [0_u8; DNS_BUFFER_SIZE];
+        let mut socket = udp::UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+        if let Err(e) = socket.bind(DNS_PORT) {
+            error!("ProvisioningPortal: Failed to bind DNS socket: {:?}", e);
+        }
+
+        let mut packet = [0_u8; DNS_BUFFER_SIZE];
+        loop {
+            let (length, peer) = match socket.recv_from(&mut packet).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("ProvisioningPortal: DNS receive failed: {:?}", e);
+                    continue;
+                }
+            };
+            if let Some(response_length) = build_dns_response(&mut packet, length, ACCESS_POINT_ADDRESS) {
+                if let Err(e) = socket.send_to(&packet[..response_length], peer.endpoint).await {
+                    error!("ProvisioningPortal: DNS send failed: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Hands out an address of the access point subnet to each client MAC address.
+    async fn serve_dhcp(stack: Stack<'_>) -> ! {
+        let mut rx_meta = [udp::PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0_u8; DHCP_BUFFER_SIZE];
+        let mut tx_meta = [udp::PacketMetadata::EMPTY; 4];
+        let mut tx_buffer = [0_u8; DHCP_BUFFER_SIZE];
+        let mut socket = udp::UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+        if let Err(e) = socket.bind(DHCP_SERVER_PORT) {
+            error!("ProvisioningPortal: Failed to bind DHCP socket: {:?}", e);
+        }
+
+        let mut leases = DhcpLeases::new();
+        let mut packet = [0_u8; DHCP_BUFFER_SIZE];
+        loop {
+            let length = match socket.recv_from(&mut packet).await {
+                Ok((length, _)) => length,
+                Err(e) => {
+                    error!("ProvisioningPortal: DHCP receive failed: {:?}", e);
+                    continue;
+                }
+            };
+            if let Some(response_length) = build_dhcp_response(&mut packet, length, &mut leases, ACCESS_POINT_ADDRESS) {
+                // Clients have no address yet, so replies are broadcast.
+                let broadcast = IpEndpoint::new(IpAddress::v4(255, 255, 255, 255), DHCP_CLIENT_PORT);
+                if let Err(e) = socket.send_to(&packet[..response_length], broadcast).await {
+                    error!("ProvisioningPortal: DHCP send failed: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+const FORM_PAGE_START: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+    <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+    <title>Air Quality Monitor setup</title><style>\
+    body{font-family:sans-serif;max-width:24em;margin:2em auto;padding:0 1em}\
+    label{display:block;margin:1em 0}input{display:block;width:100%;padding:.4em;box-sizing:border-box}\
+    button{padding:.6em 1.2em}.error{color:#b00}\
+    </style></head><body><h1>Air Quality Monitor setup</h1>";
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+    <title>Air Quality Monitor setup</title></head><body><h1>Configuration saved</h1>\
+    <p>The monitor is rebooting and will join the configured network.</p></body></html>";
+const PAGE_END: &str = "</body></html>";
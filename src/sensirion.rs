@@ -0,0 +1,231 @@
+//! Async drivers for the Sensirion SCD41 (CO2, temperature, humidity) and
+//! SGP41 (VOC and NOx indices) over any `embedded_hal_async` I2C bus, so
+//! reads can be awaited and abandoned on a timeout.
+
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c;
+use gas_index_algorithm::{AlgorithmType, GasIndexAlgorithm};
+
+const SCD41_ADDRESS: u8 = 0x62;
+const SGP41_ADDRESS: u8 = 0x59;
+
+const SCD41_WAKE_UP: u16 = 0x36F6;
+const SCD41_STOP_PERIODIC_MEASUREMENT: u16 = 0x3F86;
+const SCD41_REINIT: u16 = 0x3646;
+const SCD41_START_PERIODIC_MEASUREMENT: u16 = 0x21B1;
+const SCD41_READ_MEASUREMENT: u16 = 0xEC05;
+const SCD41_FORCED_RECALIBRATION: u16 = 0x362F;
+/// Returned instead of the correction when forced recalibration failed.
+const SCD41_RECALIBRATION_FAILED: u16 = 0xFFFF;
+
+const SGP41_EXECUTE_CONDITIONING: u16 = 0x2612;
+const SGP41_MEASURE_RAW_SIGNALS: u16 = 0x2619;
+/// Compensation arguments meaning 50 %RH and 25 °C, used when no humidity and
+/// temperature are passed to the SGP41.
+const SGP41_DEFAULT_HUMIDITY: u16 = 0x8000;
+const SGP41_DEFAULT_TEMPERATURE: u16 = 0x6666;
+/// Seconds between two samples fed to the gas index algorithms.
+const SGP41_SAMPLING_INTERVAL: f32 = 1.0;
+
+#[derive(Debug)]
+pub enum SensirionError<E> {
+    I2c(E),
+    /// A word read back did not match its CRC.
+    Crc,
+    /// The SCD41 could not run the forced recalibration.
+    RecalibrationFailed,
+}
+
+/// CRC-8 over a 16-bit word, as appended by Sensirion sensors (polynomial 0x31,
+/// initial value 0xFF).
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Splits the `N` words, each followed by its CRC, out of a response. `None` if
+/// the length or a CRC does not match.
+pub fn decode_words<const N: usize>(data: &[u8]) -> Option<[u16; N]> {
+    if data.len() != 3 * N {
+        return None;
+    }
+    let mut words = [0; N];
+    for (word, chunk) in words.iter_mut().zip(data.chunks_exact(3)) {
+        if crc8(&chunk[..2]) != chunk[2] {
+            return None;
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Some(words)
+}
+
+/// CO2, temperature and humidity of one SCD41 sample.
+#[derive(Debug, Clone, Copy)]
+pub struct Scd41Measurement {
+    pub co2: u16,
+    pub temperature: f32,
+    pub humidity: f32,
+}
+
+impl Scd41Measurement {
+    pub fn from_words([co2, temperature, humidity]: [u16; 3]) -> Self {
+        Self {
+            co2,
+            temperature: -45.0 + 175.0 * temperature as f32 / 65535.0,
+            humidity: 100.0 * humidity as f32 / 65535.0,
+        }
+    }
+}
+
+struct SensirionDevice<I> {
+    i2c: I,
+    address: u8,
+}
+
+impl<I: I2c> SensirionDevice<I> {
+    /// Sends `command` followed by up to two arguments, each with its CRC.
+    async fn command(&mut self, command: u16, arguments: &[u16]) -> Result<(), SensirionError<I::Error>> {
+        let mut data = [0u8; 8];
+        data[..2].copy_from_slice(&command.to_be_bytes());
+        let mut length = 2;
+        for argument in arguments.iter().take(2) {
+            data[length..length + 2].copy_from_slice(&argument.to_be_bytes());
+            data[length + 2] = crc8(&data[length..length + 2]);
+            length += 3;
+        }
+        self.i2c.write(self.address, &data[..length]).await.map_err(SensirionError::I2c)
+    }
+
+    async fn read_words<const N: usize>(&mut self) -> Result<[u16; N], SensirionError<I::Error>> {
+        let mut data = [0u8; 9];
+        let data = &mut data[..3 * N];
+        self.i2c.read(self.address, data).await.map_err(SensirionError::I2c)?;
+        decode_words(data).ok_or(SensirionError::Crc)
+    }
+}
+
+pub struct Scd41<I> {
+    device: SensirionDevice<I>,
+}
+
+impl<I: I2c> Scd41<I> {
+    pub fn new(i2c: I) -> Self {
+        Self {
+            device: SensirionDevice { i2c, address: SCD41_ADDRESS },
+        }
+    }
+
+    /// Wakes the sensor from sleep. It does not acknowledge the command, so
+    /// the I2C error is ignored.
+    pub async fn wake_up(&mut self) {
+        let _ = self.device.command(SCD41_WAKE_UP, &[]).await;
+        Timer::after_millis(30).await;
+    }
+
+    pub async fn stop_periodic_measurement(&mut self) -> Result<(), SensirionError<I::Error>> {
+        self.device.command(SCD41_STOP_PERIODIC_MEASUREMENT, &[]).await?;
+        Timer::after_millis(500).await;
+        Ok(())
+    }
+
+    pub async fn reinit(&mut self) -> Result<(), SensirionError<I::Error>> {
+        self.device.command(SCD41_REINIT, &[]).await?;
+        Timer::after_millis(30).await;
+        Ok(())
+    }
+
+    /// Starts producing a new sample every 5 seconds.
+    pub async fn start_periodic_measurement(&mut self) -> Result<(), SensirionError<I::Error>> {
+        self.device.command(SCD41_START_PERIODIC_MEASUREMENT, &[]).await
+    }
+
+    pub async fn measurement(&mut self) -> Result<Scd41Measurement, SensirionError<I::Error>> {
+        self.device.command(SCD41_READ_MEASUREMENT, &[]).await?;
+        Timer::after_millis(1).await;
+        self.device.read_words::<3>().await.map(Scd41Measurement::from_words)
+    }
+
+    /// Recalibrates to the reference `target_ppm`, which the sensor must be
+    /// exposed to. Periodic measurement must be stopped. Returns the applied
+    /// correction in ppm.
+    pub async fn forced_recalibration(&mut self, target_ppm: u16) -> Result<i32, SensirionError<I::Error>> {
+        self.device.command(SCD41_FORCED_RECALIBRATION, &[target_ppm]).await?;
+        Timer::after_millis(400).await;
+        let [correction] = self.device.read_words::<1>().await?;
+        if correction == SCD41_RECALIBRATION_FAILED {
+            return Err(SensirionError::RecalibrationFailed);
+        }
+        Ok(correction as i32 - 0x8000)
+    }
+}
+
+pub struct Sgp41<I> {
+    device: SensirionDevice<I>,
+    voc_algorithm: GasIndexAlgorithm,
+    nox_algorithm: GasIndexAlgorithm,
+}
+
+impl<I: I2c> Sgp41<I> {
+    pub fn new(i2c: I) -> Self {
+        Self {
+            device: SensirionDevice { i2c, address: SGP41_ADDRESS },
+            voc_algorithm: GasIndexAlgorithm::new(AlgorithmType::Voc, SGP41_SAMPLING_INTERVAL),
+            nox_algorithm: GasIndexAlgorithm::new(AlgorithmType::Nox, SGP41_SAMPLING_INTERVAL),
+        }
+    }
+
+    /// Heats the NOx pixel. Run once a second for up to 10 seconds after
+    /// power-up, before the first measurement. Returns the raw VOC signal.
+    pub async fn execute_conditioning(&mut self) -> Result<u16, SensirionError<I::Error>> {
+        self.device.command(SGP41_EXECUTE_CONDITIONING, &[SGP41_DEFAULT_HUMIDITY, SGP41_DEFAULT_TEMPERATURE]).await?;
+        Timer::after_millis(50).await;
+        let [voc_raw] = self.device.read_words::<1>().await?;
+        Ok(voc_raw)
+    }
+
+    /// Measures the raw signals and feeds them to the gas index algorithms.
+    /// Must be called once per `SGP41_SAMPLING_INTERVAL`. Returns the VOC and
+    /// NOx indices.
+    pub async fn measure_indices(&mut self) -> Result<(u16, u16), SensirionError<I::Error>> {
+        self.device.command(SGP41_MEASURE_RAW_SIGNALS, &[SGP41_DEFAULT_HUMIDITY, SGP41_DEFAULT_TEMPERATURE]).await?;
+        Timer::after_millis(50).await;
+        let [voc_raw, nox_raw] = self.device.read_words::<2>().await?;
+        let voc_index = self.voc_algorithm.process(voc_raw as i32);
+        let nox_index = self.nox_algorithm.process(nox_raw as i32);
+        Ok((voc_index.clamp(0, u16::MAX as i32) as u16, nox_index.clamp(0, u16::MAX as i32) as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_datasheet_example() {
+        // SCD4x datasheet, section 3.11: CRC of 0xBEEF is 0x92.
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn decodes_scd41_measurement() {
+        // 1000 ppm, 25 °C (0x6667) and 50 %RH (0x8000), as sent by the sensor.
+        let data = [0x03, 0xE8, crc8(&[0x03, 0xE8]), 0x66, 0x67, crc8(&[0x66, 0x67]), 0x80, 0x00, crc8(&[0x80, 0x00])];
+        let measurement = Scd41Measurement::from_words(decode_words::<3>(&data).unwrap());
+        assert_eq!(measurement.co2, 1000);
+        assert!((measurement.temperature - 25.0).abs() < 0.01);
+        assert!((measurement.humidity - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn rejects_corrupted_word() {
+        let data = [0x03, 0xE8, crc8(&[0x03, 0xE8]) ^ 0x01];
+        assert!(decode_words::<1>(&data).is_none());
+        assert!(decode_words::<2>(&data).is_none());
+    }
+}
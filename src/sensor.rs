@@ -0,0 +1,293 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use log::{error, info};
+
+#[derive(Debug, Clone, Copy)]
+pub enum SensorError {
+    InitFailed,
+    WarmUpFailed,
+    ReadFailed,
+    /// The sensor did not answer within `SENSOR_TIMEOUT`.
+    Timeout,
+}
+
+/// CO2, temperature and humidity, as reported by the SCD41.
+#[derive(Debug, Clone, Copy)]
+pub struct ClimateReading {
+    pub co2: u16,
+    pub temperature: f32,
+    pub humidity: f32,
+}
+
+/// VOC and NOx indices, as reported by the SGP41.
+#[derive(Debug, Clone, Copy)]
+pub struct GasIndexReading {
+    pub voc_index: u16,
+    pub nox_index: u16,
+}
+
+/// Particulate matter concentrations (µg/m³) and particle counts (per 0.1L),
+/// as reported by the PMS5003.
+#[derive(Debug, Clone, Copy)]
+pub struct ParticulateReading {
+    pub pm1_0_atm: u16,
+    pub pm2_5_atm: u16,
+    pub pm10_0_atm: u16,
+    pub beyond_0_3: u16,
+    pub beyond_0_5: u16,
+    pub beyond_1_0: u16,
+    pub beyond_2_5: u16,
+    pub beyond_5_0: u16,
+    pub beyond_10_0: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Measurement {
+    Climate(ClimateReading),
+    GasIndex(GasIndexReading),
+    Particulate(ParticulateReading),
+}
+
+/// Latest value received from each kind of sensor. A sensor that has not
+/// reported recently enough is `None`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Readings {
+    pub climate: Option<ClimateReading>,
+    pub gas_index: Option<GasIndexReading>,
+    pub particulate: Option<ParticulateReading>,
+    climate_received_at: Option<Instant>,
+    gas_index_received_at: Option<Instant>,
+    particulate_received_at: Option<Instant>,
+}
+
+impl Readings {
+    pub fn update(&mut self, measurement: Measurement, now: Instant) {
+        match measurement {
+            Measurement::Climate(reading) => {
+                self.climate = Some(reading);
+                self.climate_received_at = Some(now);
+            }
+            Measurement::GasIndex(reading) => {
+                self.gas_index = Some(reading);
+                self.gas_index_received_at = Some(now);
+            }
+            Measurement::Particulate(reading) => {
+                self.particulate = Some(reading);
+                self.particulate_received_at = Some(now);
+            }
+        }
+    }
+
+    /// Drops every reading received more than `max_age` before `now`, so that a
+    /// sensor which stopped reporting is not published with its last value.
+    pub fn expire(&mut self, now: Instant, max_age: Duration) {
+        let is_stale = |received_at: Option<Instant>| {
+            received_at.is_none_or(|received_at| now.saturating_duration_since(received_at) > max_age)
+        };
+
+        if is_stale(self.climate_received_at) {
+            self.climate = None;
+        }
+        if is_stale(self.gas_index_received_at) {
+            self.gas_index = None;
+        }
+        if is_stale(self.particulate_received_at) {
+            self.particulate = None;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.climate.is_none() && self.gas_index.is_none() && self.particulate.is_none()
+    }
+}
+
+/// `Readings` updated by the aggregator and read by the console and other consumers.
+pub struct SharedReadings {
+    readings: Mutex<CriticalSectionRawMutex, Cell<Readings>>,
+}
+
+impl Default for SharedReadings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedReadings {
+    pub const fn new() -> Self {
+        Self {
+            readings: Mutex::new(Cell::new(Readings {
+                climate: None,
+                gas_index: None,
+                particulate: None,
+                climate_received_at: None,
+                gas_index_received_at: None,
+                particulate_received_at: None,
+            })),
+        }
+    }
+
+    pub fn get(&self) -> Readings {
+        self.readings.lock(|readings| readings.get())
+    }
+
+    pub fn update(&self, measurement: Measurement, now: Instant) {
+        self.modify(|readings| readings.update(measurement, now));
+    }
+
+    pub fn expire(&self, now: Instant, max_age: Duration) {
+        self.modify(|readings| readings.expire(now, max_age));
+    }
+
+    fn modify(&self, f: impl FnOnce(&mut Readings)) {
+        self.readings.lock(|readings| {
+            let mut value = readings.get();
+            f(&mut value);
+            readings.set(value);
+        });
+    }
+}
+
+/// Failure counters and status of a sensor, as tracked by `run_sensor`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SensorHealth {
+    pub consecutive_failures: u32,
+    pub total_failures: u32,
+    pub last_error: Option<SensorError>,
+    pub last_success_at: Option<Instant>,
+    /// Whether `Sensor::warm_up` succeeded (e.g. SGP41 conditioning).
+    pub warmed_up: bool,
+}
+
+/// `SensorHealth` of one sensor, updated by its task and read by publishers.
+pub struct SharedSensorHealth {
+    id: &'static str,
+    name: &'static str,
+    health: Mutex<CriticalSectionRawMutex, Cell<SensorHealth>>,
+}
+
+impl SharedSensorHealth {
+    /// `id` is used in payload keys and unique ids, `name` in entity names.
+    pub const fn new(id: &'static str, name: &'static str) -> Self {
+        Self {
+            id,
+            name,
+            health: Mutex::new(Cell::new(SensorHealth {
+                consecutive_failures: 0,
+                total_failures: 0,
+                last_error: None,
+                last_success_at: None,
+                warmed_up: false,
+            })),
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn get(&self) -> SensorHealth {
+        self.health.lock(|health| health.get())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SensorHealth)) {
+        self.health.lock(|health| {
+            let mut value = health.get();
+            f(&mut value);
+            health.set(value);
+        });
+    }
+
+    fn record_success(&self, now: Instant) {
+        self.update(|health| {
+            health.consecutive_failures = 0;
+            health.last_success_at = Some(now);
+        });
+    }
+
+    fn record_failure(&self, error: SensorError) {
+        self.update(|health| {
+            health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+            health.total_failures = health.total_failures.saturating_add(1);
+            health.last_error = Some(error);
+        });
+    }
+
+    fn record_warm_up(&self, warmed_up: bool) {
+        self.update(|health| health.warmed_up = warmed_up);
+    }
+}
+
+const MEASUREMENT_CHANNEL_SIZE: usize = 8;
+const SENSOR_INIT_RETRY_DELAY_MS: u64 = 5000;
+/// Longest time `Sensor::init` or `Sensor::read` may take before the attempt is
+/// abandoned and counted as a failure.
+pub const SENSOR_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub type MeasurementChannel = Channel<CriticalSectionRawMutex, Measurement, MEASUREMENT_CHANNEL_SIZE>;
+
+/// A sensor driven by `run_sensor`. Implementations log the driver error and
+/// map it to a `SensorError`.
+#[allow(async_fn_in_trait)]
+pub trait Sensor {
+    fn name(&self) -> &'static str;
+
+    /// Time to wait between two reads.
+    fn read_interval(&self) -> Duration;
+
+    async fn init(&mut self) -> Result<(), SensorError>;
+
+    /// Runs once after `init`, before the first read.
+    async fn warm_up(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Measurement, SensorError>;
+}
+
+/// Initializes `sensor`, then reads it forever and sends every measurement to
+/// `measurements`. Failures, including a sensor not answering within
+/// `SENSOR_TIMEOUT`, are logged, counted in `health` and never stop the loop.
+pub async fn run_sensor<S: Sensor>(
+    mut sensor: S,
+    measurements: &'static MeasurementChannel,
+    health: &'static SharedSensorHealth,
+) -> ! {
+    info!("{}: Initializing..", sensor.name());
+    while let Err(e) = with_timeout(SENSOR_TIMEOUT, sensor.init()).await.unwrap_or(Err(SensorError::Timeout)) {
+        error!("{}: Initialization failed: {:?}. Retrying..", sensor.name(), e);
+        health.record_failure(e);
+        Timer::after_millis(SENSOR_INIT_RETRY_DELAY_MS).await;
+    }
+
+    info!("{}: Warming up..", sensor.name());
+    match sensor.warm_up().await {
+        Ok(_) => health.record_warm_up(true),
+        Err(e) => {
+            error!("{}: Warm-up failed: {:?}", sensor.name(), e);
+            health.record_warm_up(false);
+            health.record_failure(e);
+        }
+    }
+
+    loop {
+        Timer::after(sensor.read_interval()).await;
+
+        match with_timeout(SENSOR_TIMEOUT, sensor.read()).await.unwrap_or(Err(SensorError::Timeout)) {
+            Ok(measurement) => {
+                health.record_success(Instant::now());
+                measurements.send(measurement).await;
+            }
+            Err(e) => {
+                error!("{}: Read failed: {:?}", sensor.name(), e);
+                health.record_failure(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,24 @@
+/// Snapshot of the device state, published next to the measurements to help
+/// debugging monitors in the field.
+pub struct DeviceTelemetry<'a> {
+    /// Signal strength of the access point, `None` while disconnected.
+    pub rssi: Option<i32>,
+    pub uptime_secs: u64,
+    pub heap_free: usize,
+    pub heap_used: usize,
+    pub reset_reason: &'a str,
+}
+
+impl<'a> DeviceTelemetry<'a> {
+    /// Reads the heap of the ESP32, so it is the only part not built for the host.
+    #[cfg(target_arch = "xtensa")]
+    pub fn collect(rssi: Option<i32>, reset_reason: &'a str) -> Self {
+        Self {
+            rssi,
+            uptime_secs: embassy_time::Instant::now().as_secs(),
+            heap_free: esp_alloc::HEAP.free(),
+            heap_used: esp_alloc::HEAP.used(),
+            reset_reason,
+        }
+    }
+}
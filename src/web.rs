@@ -0,0 +1,424 @@
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+use log::{error, info};
+
+use crate::http;
+use crate::http_codec::HttpMethod;
+use crate::json::{JsonError, JsonWriter};
+use crate::metrics::{MetricType, MetricsError, PrometheusWriter};
+use crate::mqtt::MqttPublisher;
+use crate::sensor::{SharedReadings, SharedSensorHealth};
+use crate::telemetry::DeviceTelemetry;
+use crate::wifi::{WiFiLinkMonitor, WiFiLinkState};
+
+pub const WEB_SERVER_PORT: u16 = 80;
+
+const WEB_RX_BUFFER_SIZE: usize = 1024;
+const WEB_TX_BUFFER_SIZE: usize = 2048;
+const WEB_REQUEST_BUFFER_SIZE: usize = 1024;
+const WEB_RESPONSE_SIZE: usize = 8192;
+const WEB_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+pub struct WebServerConfig {
+    pub port: u16,
+    pub device_id: &'static str,
+    pub device_name: &'static str,
+    pub reset_reason: &'static str,
+}
+
+impl WebServerConfig {
+    pub fn new(device_id: &'static str, device_name: &'static str, reset_reason: &'static str) -> Self {
+        Self {
+            port: WEB_SERVER_PORT,
+            device_id,
+            device_name,
+            reset_reason,
+        }
+    }
+}
+
+struct Response<'r> {
+    status: &'static str,
+    content_type: &'static str,
+    body: &'r [u8],
+}
+
+/// Serves the dashboard and the JSON API on the local network:
+/// - `GET /`: HTML dashboard, refreshed from the API
+/// - `GET /api/readings`: latest value of every sensor, `null` when missing
+/// - `GET /api/status`: Wi-Fi, MQTT, device and sensor health
+/// - `GET /metrics`: readings and diagnostics for Prometheus
+pub struct WebServerFacade {
+    _config: WebServerConfig,
+    _readings: &'static SharedReadings,
+    _sensor_health: &'static [&'static SharedSensorHealth],
+    _wifi_link: WiFiLinkMonitor,
+    _mqtt_publisher: MqttPublisher,
+    _response: String<WEB_RESPONSE_SIZE>,
+}
+
+impl WebServerFacade {
+    pub fn new(
+        config: WebServerConfig,
+        readings: &'static SharedReadings,
+        sensor_health: &'static [&'static SharedSensorHealth],
+        wifi_link: WiFiLinkMonitor,
+        mqtt_publisher: MqttPublisher,
+    ) -> Self {
+        Self {
+            _config: config,
+            _readings: readings,
+            _sensor_health: sensor_health,
+            _wifi_link: wifi_link,
+            _mqtt_publisher: mqtt_publisher,
+            _response: String::new(),
+        }
+    }
+
+    /// Accepts connections forever, one request per connection.
+    pub async fn run(&mut self, stack: Stack<'static>) -> ! {
+        let mut rx_buffer = [0_u8; WEB_RX_BUFFER_SIZE];
+        let mut tx_buffer = [0_u8; WEB_TX_BUFFER_SIZE];
+        let mut request_buffer = [0_u8; WEB_REQUEST_BUFFER_SIZE];
+
+        info!("WebServerFacade: Listening on port {}", self._config.port);
+        loop {
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(WEB_TIMEOUT));
+            if let Err(e) = socket.accept(self._config.port).await {
+                error!("WebServerFacade: Failed to accept connection: {:?}", e);
+                Timer::after_millis(100).await;
+                continue;
+            }
+
+            match http::read_request(&mut socket, &mut request_buffer).await {
+                Ok(request) => {
+                    info!("WebServerFacade: {:?} {}", request.method, request.path);
+                    let response = self.respond(request.method, request.path);
+                    if let Err(e) = http::write_response(
+                        &mut socket, response.status, response.content_type, response.body).await {
+                        error!("WebServerFacade: Failed to send response: {:?}", e);
+                    }
+                }
+                Err(e) => error!("WebServerFacade: Invalid request: {:?}", e),
+            }
+
+            let _ = socket.flush().await;
+            socket.close();
+            Timer::after_millis(50).await;
+            socket.abort();
+        }
+    }
+
+    fn respond(&mut self, method: HttpMethod, path: &str) -> Response<'_> {
+        if method != HttpMethod::Get {
+            return Response {
+                status: "405 Method Not Allowed",
+                content_type: CONTENT_TYPE_JSON,
+                body: b"{\"error\":\"method not allowed\"}",
+            };
+        }
+
+        if path == "/metrics" {
+            return match self.write_metrics() {
+                Ok(_) => Response {
+                    status: "200 OK",
+                    content_type: CONTENT_TYPE_PROMETHEUS,
+                    body: self._response.as_bytes(),
+                },
+                Err(e) => {
+                    error!("WebServerFacade: Failed to render metrics: {:?}", e);
+                    Response {
+                        status: "500 Internal Server Error",
+                        content_type: CONTENT_TYPE_PROMETHEUS,
+                        body: b"# metrics too large\n",
+                    }
+                }
+            };
+        }
+
+        let rendered = match path {
+            "/" | "/index.html" => return Response {
+                status: "200 OK",
+                content_type: CONTENT_TYPE_HTML,
+                body: DASHBOARD_PAGE.as_bytes(),
+            },
+            "/api/readings" => self.write_readings(),
+            "/api/status" => self.write_status(),
+            _ => return Response {
+                status: "404 Not Found",
+                content_type: CONTENT_TYPE_JSON,
+                body: b"{\"error\":\"not found\"}",
+            },
+        };
+
+        match rendered {
+            Ok(_) => Response {
+                status: "200 OK",
+                content_type: CONTENT_TYPE_JSON,
+                body: self._response.as_bytes(),
+            },
+            Err(e) => {
+                error!("WebServerFacade: Failed to render {}: {:?}", path, e);
+                Response {
+                    status: "500 Internal Server Error",
+                    content_type: CONTENT_TYPE_JSON,
+                    body: b"{\"error\":\"response too large\"}",
+                }
+            }
+        }
+    }
+
+    fn write_readings(&mut self) -> Result<(), JsonError> {
+        let readings = self._readings.get();
+
+        let mut json = JsonWriter::new(&mut self._response);
+        json.begin_object()?;
+        json.field_str("device_id", self._config.device_id)?;
+        json.field_str("device_name", self._config.device_name)?;
+
+        json.key("climate")?;
+        match &readings.climate {
+            Some(climate) => {
+                json.begin_object()?;
+                json.field_number("co2", climate.co2)?;
+                json.field_number("temperature", climate.temperature)?;
+                json.field_number("humidity", climate.humidity)?;
+                json.end_object()?;
+            }
+            None => json.null()?,
+        }
+
+        json.key("gas_index")?;
+        match &readings.gas_index {
+            Some(gas_index) => {
+                json.begin_object()?;
+                json.field_number("voc_index", gas_index.voc_index)?;
+                json.field_number("nox_index", gas_index.nox_index)?;
+                json.end_object()?;
+            }
+            None => json.null()?,
+        }
+
+        json.key("particulate")?;
+        match &readings.particulate {
+            Some(particulate) => {
+                json.begin_object()?;
+                json.field_number("pm1_0_atm", particulate.pm1_0_atm)?;
+                json.field_number("pm2_5_atm", particulate.pm2_5_atm)?;
+                json.field_number("pm10_0_atm", particulate.pm10_0_atm)?;
+                json.field_number("beyond_0_3", particulate.beyond_0_3)?;
+                json.field_number("beyond_0_5", particulate.beyond_0_5)?;
+                json.field_number("beyond_1_0", particulate.beyond_1_0)?;
+                json.field_number("beyond_2_5", particulate.beyond_2_5)?;
+                json.field_number("beyond_5_0", particulate.beyond_5_0)?;
+                json.field_number("beyond_10_0", particulate.beyond_10_0)?;
+                json.end_object()?;
+            }
+            None => json.null()?,
+        }
+
+        json.end_object()
+    }
+
+    fn write_metrics(&mut self) -> Result<(), MetricsError> {
+        let now = Instant::now();
+        let readings = self._readings.get();
+        let telemetry = DeviceTelemetry::collect(self._wifi_link.rssi(), self._config.reset_reason);
+
+        let mut metrics = PrometheusWriter::new(&mut self._response, self._config.device_id);
+
+        metrics.family("air_quality_co2_ppm", MetricType::Gauge, "CO2 concentration (SCD41)")?;
+        if let Some(climate) = &readings.climate {
+            metrics.sample("air_quality_co2_ppm", &[], climate.co2)?;
+        }
+        metrics.family("air_quality_temperature_celsius", MetricType::Gauge, "Temperature (SCD41)")?;
+        if let Some(climate) = &readings.climate {
+            metrics.sample("air_quality_temperature_celsius", &[], climate.temperature)?;
+        }
+        metrics.family("air_quality_humidity_percent", MetricType::Gauge, "Relative humidity (SCD41)")?;
+        if let Some(climate) = &readings.climate {
+            metrics.sample("air_quality_humidity_percent", &[], climate.humidity)?;
+        }
+
+        metrics.family("air_quality_voc_index", MetricType::Gauge, "VOC index (SGP41)")?;
+        if let Some(gas_index) = &readings.gas_index {
+            metrics.sample("air_quality_voc_index", &[], gas_index.voc_index)?;
+        }
+        metrics.family("air_quality_nox_index", MetricType::Gauge, "NOx index (SGP41)")?;
+        if let Some(gas_index) = &readings.gas_index {
+            metrics.sample("air_quality_nox_index", &[], gas_index.nox_index)?;
+        }
+
+        metrics.family("air_quality_pm_micrograms_per_cubic_meter", MetricType::Gauge,
+            "Particulate matter concentration by particle size in µm, atmospheric environment (PMS5003)")?;
+        if let Some(particulate) = &readings.particulate {
+            for (size, value) in [
+                ("1.0", particulate.pm1_0_atm),
+                ("2.5", particulate.pm2_5_atm),
+                ("10", particulate.pm10_0_atm),
+            ] {
+                metrics.sample("air_quality_pm_micrograms_per_cubic_meter", &[("size", size)], value)?;
+            }
+        }
+        metrics.family("air_quality_particles_per_deciliter", MetricType::Gauge,
+            "Particles beyond a diameter in µm per 0.1 L of air (PMS5003)")?;
+        if let Some(particulate) = &readings.particulate {
+            for (size, value) in [
+                ("0.3", particulate.beyond_0_3),
+                ("0.5", particulate.beyond_0_5),
+                ("1.0", particulate.beyond_1_0),
+                ("2.5", particulate.beyond_2_5),
+                ("5.0", particulate.beyond_5_0),
+                ("10", particulate.beyond_10_0),
+            ] {
+                metrics.sample("air_quality_particles_per_deciliter", &[("size", size)], value)?;
+            }
+        }
+
+        metrics.family("air_quality_sensor_consecutive_failures", MetricType::Gauge,
+            "Failed reads since the last successful one")?;
+        for shared_health in self._sensor_health.iter() {
+            metrics.sample("air_quality_sensor_consecutive_failures", &[("sensor", shared_health.id())],
+                shared_health.get().consecutive_failures)?;
+        }
+        metrics.family("air_quality_sensor_failures_total", MetricType::Counter, "Failed initializations, warm-ups and reads")?;
+        for shared_health in self._sensor_health.iter() {
+            metrics.sample("air_quality_sensor_failures_total", &[("sensor", shared_health.id())],
+                shared_health.get().total_failures)?;
+        }
+        metrics.family("air_quality_sensor_last_success_age_seconds", MetricType::Gauge,
+            "Time since the last successful read")?;
+        for shared_health in self._sensor_health.iter() {
+            if let Some(last_success_at) = shared_health.get().last_success_at {
+                metrics.sample("air_quality_sensor_last_success_age_seconds", &[("sensor", shared_health.id())],
+                    now.saturating_duration_since(last_success_at).as_secs())?;
+            }
+        }
+        metrics.family("air_quality_sensor_warmed_up", MetricType::Gauge, "Whether the sensor warm-up succeeded")?;
+        for shared_health in self._sensor_health.iter() {
+            metrics.sample("air_quality_sensor_warmed_up", &[("sensor", shared_health.id())],
+                shared_health.get().warmed_up as u8)?;
+        }
+
+        metrics.family("air_quality_device_info", MetricType::Gauge, "Device name and last reset reason")?;
+        metrics.sample("air_quality_device_info",
+            &[("device_name", self._config.device_name), ("reset_reason", telemetry.reset_reason)], 1)?;
+        metrics.family("air_quality_uptime_seconds", MetricType::Counter, "Time since boot")?;
+        metrics.sample("air_quality_uptime_seconds", &[], telemetry.uptime_secs)?;
+        metrics.family("air_quality_heap_free_bytes", MetricType::Gauge, "Free heap")?;
+        metrics.sample("air_quality_heap_free_bytes", &[], telemetry.heap_free)?;
+        metrics.family("air_quality_heap_used_bytes", MetricType::Gauge, "Used heap")?;
+        metrics.sample("air_quality_heap_used_bytes", &[], telemetry.heap_used)?;
+        metrics.family("air_quality_wifi_connected", MetricType::Gauge, "Whether the Wi-Fi link is up")?;
+        metrics.sample("air_quality_wifi_connected", &[],
+            (self._wifi_link.state() == WiFiLinkState::Connected) as u8)?;
+        metrics.family("air_quality_wifi_rssi_dbm", MetricType::Gauge, "Signal strength of the access point")?;
+        if let Some(rssi) = telemetry.rssi {
+            metrics.sample("air_quality_wifi_rssi_dbm", &[], rssi)?;
+        }
+        metrics.family("air_quality_mqtt_connected", MetricType::Gauge, "Whether the MQTT session is established")?;
+        metrics.sample("air_quality_mqtt_connected", &[], self._mqtt_publisher.is_connected() as u8)?;
+
+        Ok(())
+    }
+
+    fn write_status(&mut self) -> Result<(), JsonError> {
+        let now = Instant::now();
+        let telemetry = DeviceTelemetry::collect(self._wifi_link.rssi(), self._config.reset_reason);
+
+        let mut json = JsonWriter::new(&mut self._response);
+        json.begin_object()?;
+        json.field_str("device_id", self._config.device_id)?;
+        json.field_str("device_name", self._config.device_name)?;
+        json.field_number("uptime", telemetry.uptime_secs)?;
+        json.field_number("heap_free", telemetry.heap_free)?;
+        json.field_number("heap_used", telemetry.heap_used)?;
+        json.field_str("reset_reason", telemetry.reset_reason)?;
+
+        json.key("wifi")?;
+        json.begin_object()?;
+        json.field_fmt("state", format_args!("{:?}", self._wifi_link.state()))?;
+        json.key("rssi")?;
+        match telemetry.rssi {
+            Some(rssi) => json.number(rssi)?,
+            None => json.null()?,
+        }
+        json.end_object()?;
+
+        json.key("mqtt")?;
+        json.begin_object()?;
+        json.field_bool("connected", self._mqtt_publisher.is_connected())?;
+        json.end_object()?;
+
+        json.key("sensors")?;
+        json.begin_array()?;
+        for shared_health in self._sensor_health.iter() {
+            let health = shared_health.get();
+            json.begin_object()?;
+            json.field_str("id", shared_health.id())?;
+            json.field_str("name", shared_health.name())?;
+            json.field_bool("warmed_up", health.warmed_up)?;
+            json.field_number("consecutive_failures", health.consecutive_failures)?;
+            json.field_number("total_failures", health.total_failures)?;
+            json.key("last_error")?;
+            match health.last_error {
+                Some(last_error) => json.string_fmt(format_args!("{:?}", last_error))?,
+                None => json.null()?,
+            }
+            json.key("last_success_age")?;
+            match health.last_success_at {
+                Some(last_success_at) => json.number(now.saturating_duration_since(last_success_at).as_secs())?,
+                None => json.null()?,
+            }
+            json.end_object()?;
+        }
+        json.end_array()?;
+
+        json.end_object()
+    }
+}
+
+const DASHBOARD_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width,initial-scale=1">
+<title>Air Quality Monitor</title>
+<style>
+body{font-family:sans-serif;max-width:40em;margin:1em auto;padding:0 1em;color:#222}
+table{border-collapse:collapse;width:100%;margin-bottom:1.5em}
+td,th{text-align:left;padding:.3em .5em;border-bottom:1px solid #ddd}
+td.v{text-align:right;font-variant-numeric:tabular-nums}.bad{color:#b00}
+</style></head><body>
+<h1 id="title">Air Quality Monitor</h1>
+<h2>Readings</h2><table id="readings"></table>
+<h2>Status</h2><table id="status"></table>
+<h2>Sensors</h2><table id="sensors"></table>
+<script>
+const READINGS=[["climate","co2","CO2","ppm"],["climate","temperature","Temperature","°C"],
+["climate","humidity","Humidity","%"],["gas_index","voc_index","VOC index",""],
+["gas_index","nox_index","NOx index",""],["particulate","pm1_0_atm","PM1.0","µg/m³"],
+["particulate","pm2_5_atm","PM2.5","µg/m³"],["particulate","pm10_0_atm","PM10","µg/m³"]];
+function row(name,value,bad){const tr=document.createElement("tr");const th=document.createElement("th");
+th.textContent=name;const td=document.createElement("td");td.className="v"+(bad?" bad":"");
+td.textContent=value;tr.append(th,td);return tr}
+function fmt(v){return typeof v==="number"?(Number.isInteger(v)?v:v.toFixed(1)):v}
+async function refresh(){try{
+const r=await(await fetch("/api/readings")).json();
+document.getElementById("title").textContent=r.device_name;
+document.getElementById("readings").replaceChildren(...READINGS.map(([g,k,n,u])=>
+r[g]?row(n,fmt(r[g][k])+" "+u):row(n,"no data",true)));
+const s=await(await fetch("/api/status")).json();
+document.getElementById("status").replaceChildren(row("Wi-Fi",s.wifi.state+(s.wifi.rssi!==null?" ("+s.wifi.rssi+" dBm)":""),s.wifi.state!=="Connected"),
+row("MQTT",s.mqtt.connected?"connected":"disconnected",!s.mqtt.connected),
+row("Uptime",s.uptime+" s"),row("Free heap",s.heap_free+" B"),row("Reset reason",s.reset_reason));
+document.getElementById("sensors").replaceChildren(...s.sensors.map(x=>row(x.name,
+x.consecutive_failures?x.consecutive_failures+" failures ("+x.last_error+")":
+x.last_success_age!==null?"OK, read "+x.last_success_age+" s ago":"waiting",x.consecutive_failures>0)));
+}catch(e){console.error(e)}}
+refresh();setInterval(refresh,5000);
+</script></body></html>
+"#;
@@ -1,13 +1,25 @@
+use core::sync::atomic::{AtomicI32, Ordering};
 use log::{error, info};
 use esp_wifi::wifi::{
     Interfaces,
+    AccessPointConfiguration,
     ClientConfiguration, 
     Configuration, 
     WifiController,
+    WifiEvent,
+    WifiState,
 };
-use embassy_time::Timer;
-use embassy_net::{Config, Stack, StackResources, Runner};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{Receiver, Watch};
+use embassy_time::{Duration, Timer};
+use embassy_net::{Config, ConfigV6, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr, Stack, StackResources, StaticConfigV4, StaticConfigV6, Runner};
+use embassy_net::driver::{Driver, HardwareAddress};
 use esp_wifi::wifi::WifiDevice;
+use heapless::{String, Vec};
+
+use crate::wifi_networks::{rank_networks, WiFiNetwork, MAX_KNOWN_NETWORKS};
 
 #[derive(Debug)]
 pub enum WiFiError {
@@ -19,72 +31,277 @@ pub enum WiFiError {
 }
 use esp_wifi::wifi::sta_state;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WiFiLinkState {
+    Connected,
+    Disconnected,
+}
+
+const WIFI_LINK_STATE_RECEIVERS: usize = 4;
+const WIFI_RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const WIFI_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const WIFI_RSSI_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+const RSSI_UNKNOWN: i32 = i32::MIN;
+
+static LINK_STATE: Watch<CriticalSectionRawMutex, WiFiLinkState, WIFI_LINK_STATE_RECEIVERS> = Watch::new();
+static LAST_RSSI: AtomicI32 = AtomicI32::new(RSSI_UNKNOWN);
+static SCAN_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SCAN_RESULT: Signal<CriticalSectionRawMutex, Vec<WiFiScanResult, WIFI_SCAN_MAX_APS>> = Signal::new();
+
+/// Access point seen during a scan.
+#[derive(Debug, Clone)]
+pub struct WiFiScanResult {
+    pub ssid: String<32>,
+    pub rssi: i8,
+    pub channel: u8,
+}
+
+/// Handle used by other components to follow the link maintained by `WiFiFacade::supervise`.
+#[derive(Clone, Copy)]
+pub struct WiFiLinkMonitor;
+
+impl WiFiLinkMonitor {
+    pub fn state(&self) -> WiFiLinkState {
+        LINK_STATE.try_get().unwrap_or(WiFiLinkState::Disconnected)
+    }
+
+    /// Receiver notified on every link state change. `None` once all
+    /// `WIFI_LINK_STATE_RECEIVERS` receivers are taken.
+    pub fn receiver(&self) -> Option<Receiver<'static, CriticalSectionRawMutex, WiFiLinkState, WIFI_LINK_STATE_RECEIVERS>> {
+        LINK_STATE.receiver()
+    }
+
+    /// Signal strength of the access point, refreshed periodically while connected.
+    pub fn rssi(&self) -> Option<i32> {
+        match LAST_RSSI.load(Ordering::Relaxed) {
+            RSSI_UNKNOWN => None,
+            rssi => Some(rssi),
+        }
+    }
+
+    /// Asks the task running `WiFiFacade::supervise` for a scan and waits for
+    /// its results. While reconnecting, the scan of the next attempt is returned.
+    pub async fn scan(&self) -> Vec<WiFiScanResult, WIFI_SCAN_MAX_APS> {
+        SCAN_RESULT.reset();
+        SCAN_REQUEST.signal(());
+        SCAN_RESULT.wait().await
+    }
+}
+
+pub const WIFI_SCAN_MAX_APS: usize = 10;
+
 pub struct WiFiFacadeConfig {
-    pub ssid: &'static str,
-    pub password: &'static str,
+    pub networks: Vec<WiFiNetwork, MAX_KNOWN_NETWORKS>,
 }
 
 impl WiFiFacadeConfig {
     pub fn new(wifi_ssid: &'static str, wifi_password: &'static str) -> Self {
         Self {
-            ssid: wifi_ssid,
-            password: wifi_password,
+            networks: Vec::new(),
+        }.with_network(wifi_ssid, wifi_password)
+    }
+
+    /// Adds a known network. Networks beyond `MAX_KNOWN_NETWORKS` are ignored.
+    pub fn with_network(mut self, wifi_ssid: &'static str, wifi_password: &'static str) -> Self {
+        if self.networks.push(WiFiNetwork { ssid: wifi_ssid, password: wifi_password }).is_err() {
+            error!("WiFiFacadeConfig: Ignoring network {:?}, at most {} are supported", wifi_ssid, MAX_KNOWN_NETWORKS);
         }
+        self
     }
 
+    /// `WIFI_SSID`/`WIFI_PASSWORD`, plus the optional `WIFI_SSID_2`/`WIFI_PASSWORD_2`
+    /// up to `WIFI_SSID_4`/`WIFI_PASSWORD_4`.
     pub fn from_env() -> Self {
-        Self {
-            ssid: env!("WIFI_SSID"),
-            password: env!("WIFI_PASSWORD"),
+        let mut config = Self::new(env!("WIFI_SSID"), env!("WIFI_PASSWORD"));
+        let extra_networks = [
+            (option_env!("WIFI_SSID_2"), option_env!("WIFI_PASSWORD_2")),
+            (option_env!("WIFI_SSID_3"), option_env!("WIFI_PASSWORD_3")),
+            (option_env!("WIFI_SSID_4"), option_env!("WIFI_PASSWORD_4")),
+        ];
+        for (ssid, password) in extra_networks {
+            if let Some(ssid) = ssid {
+                config = config.with_network(ssid, password.unwrap_or(""));
+            }
         }
+        config
     }
 }
 
+/// Address of the device on the network of its own access point.
+pub const ACCESS_POINT_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+const ACCESS_POINT_PREFIX_LENGTH: u8 = 24;
+
 pub struct WiFiFacade<'lifetime> {
     _config: WiFiFacadeConfig,
     _wifi_controller: WifiController<'lifetime>,
+    _access_point_device: Option<WifiDevice<'lifetime>>,
 }
 
 
 impl <'lifetime> WiFiFacade<'lifetime> {
-    pub fn new(
+    pub fn new<const SOCKETS: usize>(
         config: WiFiFacadeConfig, 
         wifi_controller: WifiController<'lifetime>, 
         interfaces: Interfaces<'lifetime>,
-        stack_resources: &'lifetime mut StackResources<5>,
+        stack_resources: &'lifetime mut StackResources<SOCKETS>,
     ) -> (Self, Stack<'lifetime>, Runner<'lifetime, WifiDevice<'lifetime>>) {
         let facade = Self {
             _config: config,
             _wifi_controller: wifi_controller,
+            _access_point_device: Some(interfaces.ap),
         };
 
-        let dhcpconfig = Config::dhcpv4(Default::default());
+        // DHCPv4 alongside a link-local IPv6 address (embassy-net has no SLAAC),
+        // so that brokers and mDNS peers on the link are reachable over IPv6 too
+        let mut dhcpconfig = Config::dhcpv4(Default::default());
+        if let Some(address) = link_local_address(interfaces.sta.hardware_address()) {
+            dhcpconfig.ipv6 = ConfigV6::Static(StaticConfigV6 {
+                address: Ipv6Cidr::new(address, 64),
+                gateway: None,
+                dns_servers: Default::default(),
+            });
+        }
         let (stack, runner) = embassy_net::new(
             interfaces.sta, 
             dhcpconfig, 
             stack_resources, 
             3845834);
-            
-        return (facade, stack, runner)
+
+        (facade, stack, runner)
     }
 
     pub async fn connect(&mut self) -> Result<(), WiFiError> {
-        self.configure().unwrap();
-        self.connect_to_wifi().await.unwrap();
-        
-        Ok(())
+        self.start()?;
+        self.connect_to_best_network().await
+    }
+
+    /// Switches the radio to an open access point named `ssid`, reachable at
+    /// `ACCESS_POINT_ADDRESS`, and returns the network stack serving it.
+    /// Clients get their address from a DHCP server run on top of that stack.
+    pub fn start_access_point<const SOCKETS: usize>(
+        &mut self,
+        ssid: &str,
+        stack_resources: &'lifetime mut StackResources<SOCKETS>,
+    ) -> Result<(Stack<'lifetime>, Runner<'lifetime, WifiDevice<'lifetime>>), WiFiError> {
+        let access_point_device = self._access_point_device.take().ok_or_else(|| {
+            error!("❌ Access point already started");
+            WiFiError::InitializationFailed
+        })?;
+
+        if matches!(self._wifi_controller.is_started(), Ok(true)) {
+            self._wifi_controller.stop().map_err(|e| {
+                error!("❌ Failed to stop WiFi: {:?}", e);
+                WiFiError::InitializationFailed
+            })?;
+        }
+
+        self._wifi_controller.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+            ssid: ssid.into(),
+            ..Default::default()
+        })).map_err(|e| {
+            error!("❌ Failed to set access point configuration: {:?}", e);
+            WiFiError::ConfigurationError
+        })?;
+        self.start()?;
+        info!("WiFiFacade: Access point {:?} started", ssid);
+
+        let static_config = Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(ACCESS_POINT_ADDRESS, ACCESS_POINT_PREFIX_LENGTH),
+            gateway: Some(ACCESS_POINT_ADDRESS),
+            dns_servers: Default::default(),
+        });
+        Ok(embassy_net::new(
+            access_point_device,
+            static_config,
+            stack_resources,
+            3845835))
+    }
+
+    pub fn link_monitor(&self) -> WiFiLinkMonitor {
+        WiFiLinkMonitor
+    }
+
+    /// Watches the link established by `connect` and reconnects with exponential
+    /// backoff whenever the access point drops us. Link state changes are
+    /// broadcast through `WiFiLinkMonitor`.
+    pub async fn supervise(&mut self) -> ! {
+        let sender = LINK_STATE.sender();
+        let mut backoff = WIFI_RECONNECT_INITIAL_BACKOFF;
+
+        loop {
+            if matches!(sta_state(), WifiState::StaConnected) {
+                sender.send_if_modified(|state| Self::set_link_state(state, WiFiLinkState::Connected));
+                self.refresh_rssi();
+                backoff = WIFI_RECONNECT_INITIAL_BACKOFF;
+
+                match select3(
+                    self._wifi_controller.wait_for_event(WifiEvent::StaDisconnected),
+                    Timer::after(WIFI_RSSI_REFRESH_INTERVAL),
+                    SCAN_REQUEST.wait(),
+                ).await {
+                    Either3::First(_) => info!("WiFiFacade: Disconnected from access point"),
+                    Either3::Second(_) => continue,
+                    Either3::Third(_) => {
+                        SCAN_RESULT.signal(self.scan().await);
+                        continue;
+                    }
+                }
+            }
+
+            sender.send_if_modified(|state| Self::set_link_state(state, WiFiLinkState::Disconnected));
+            LAST_RSSI.store(RSSI_UNKNOWN, Ordering::Relaxed);
+
+            info!("WiFiFacade: Reconnecting..");
+            match self.connect_to_best_network().await {
+                Ok(_) => info!("WiFiFacade: Reconnected"),
+                Err(e) => {
+                    error!("WiFiFacade: Reconnection failed: {:?}. Retrying in {} ms", e, backoff.as_millis());
+                    Timer::after(backoff).await;
+                    backoff = (backoff * 2).min(WIFI_RECONNECT_MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    fn set_link_state(state: &mut Option<WiFiLinkState>, new_state: WiFiLinkState) -> bool {
+        if *state == Some(new_state) {
+            return false;
+        }
+        info!("WiFiFacade: Link state changed to {:?}", new_state);
+        *state = Some(new_state);
+        true
+    }
+
+    fn refresh_rssi(&self) {
+        match self._wifi_controller.rssi() {
+            Ok(rssi) => LAST_RSSI.store(rssi, Ordering::Relaxed),
+            Err(e) => info!("WiFiFacade: Failed to read RSSI: {:?}", e),
+        }
+    }
+
+    /// Scans without blocking the executor, so the other tasks keep running
+    /// while the radio hops through the channels.
+    async fn scan(&mut self) -> Vec<WiFiScanResult, WIFI_SCAN_MAX_APS> {
+        match self._wifi_controller.scan_n_async(WIFI_SCAN_MAX_APS).await {
+            Ok(aps) => aps.iter()
+                .map(|ap| {
+                    info!("{:?}", ap);
+                    let mut ssid = String::new();
+                    let _ = ssid.push_str(ap.ssid.as_str());
+                    WiFiScanResult { ssid, rssi: ap.signal_strength, channel: ap.channel }
+                })
+                .collect(),
+            Err(err) => {
+                info!("Scan error: {:?}", err);
+                Vec::new()
+            }
+        }
     }
 
-    fn configure(&mut self) -> Result<(), WiFiError> {
+    fn configure(&mut self, network: WiFiNetwork) -> Result<(), WiFiError> {
         self._wifi_controller.set_configuration(&Configuration::Client(ClientConfiguration {
-            ssid: self._config.ssid.try_into().map_err(|e| {
-                error!("❌ Failed to convert SSID to WiFi format: {:?}", e);
-                WiFiError::ConfigurationError
-            })?,
-            password: self._config.password.try_into().map_err(|e| {
-                error!("❌ Failed to convert password to WiFi format: {:?}", e);
-                WiFiError::ConfigurationError
-            })?,
+            ssid: network.ssid.into(),
+            password: network.password.into(),
             ..Default::default()
         })).map_err(|e| {
             error!("❌ Failed to set WiFi configuration: {:?}", e);
@@ -94,44 +311,61 @@ impl <'lifetime> WiFiFacade<'lifetime> {
         Ok(())
     }
 
-    async fn connect_to_wifi(&mut self) -> Result<(), WiFiError> {
+    fn start(&mut self) -> Result<(), WiFiError> {
+        if matches!(self._wifi_controller.is_started(), Ok(true)) {
+            return Ok(());
+        }
+
         self._wifi_controller.start().map_err(|e| {
             error!("❌ Failed to start WiFi: {:?}", e);
             WiFiError::InitializationFailed
-        })?;
-        self._wifi_controller.connect().map_err(|e| {
-            error!("❌ Failed to connect to WiFi: {:?}", e);
-            WiFiError::ConnectionFailed
-        })?;
+        })
+    }
 
-        match self._wifi_controller.scan_n(10) {
-            Ok(aps) => {
-                for ap in aps {
-                    info!("{:?}", ap);
-                }
+    /// Scans, then tries the known networks from the strongest to the weakest,
+    /// falling back to the next one when a connection (e.g. authentication) fails.
+    async fn connect_to_best_network(&mut self) -> Result<(), WiFiError> {
+        let aps = self.scan().await;
+        if SCAN_REQUEST.try_take().is_some() {
+            SCAN_RESULT.signal(aps.clone());
+        }
+
+        let candidates = rank_networks(
+            &self._config.networks,
+            aps.iter().map(|ap| (ap.ssid.as_str(), ap.rssi)));
+        for (network, rssi) in candidates {
+            info!("Wifi Connecting to {:?} (RSSI: {:?})..", network.ssid, rssi);
+            if self.configure(network).is_err() {
+                continue;
             }
-            Err(err) => info!("Scan error: {:?}", err)
-        };
 
-        loop {
-            match self._wifi_controller.is_connected() {
-                Ok(true) => {
+            match self._wifi_controller.connect_async().await {
+                Ok(_) => {
                     let state = sta_state();
                     info!("Wifi Connected: {:?}", state);
-                    
-                    break;
-                },
-                Ok(false) => {
-                    info!("Wifi Connecting..");
-                    Timer::after_millis(500).await;
+                    return Ok(());
                 }
                 Err(err) => {
-                    error!("Error when connecting to Wifi: {:?}", err);
-                    Timer::after_millis(500).await;
-                },
+                    error!("Error when connecting to {:?}: {:?}. Trying next network..", network.ssid, err);
+                }
             }
         }
-    
-        Ok(())
+
+        error!("❌ Could not connect to any known WiFi network");
+        Err(WiFiError::ConnectionFailed)
     }
-}
\ No newline at end of file
+}
+/// Link-local IPv6 address of an Ethernet-like interface, with the modified
+/// EUI-64 interface identifier derived from its MAC address (RFC 4291).
+fn link_local_address(hardware_address: HardwareAddress) -> Option<Ipv6Address> {
+    let HardwareAddress::Ethernet(mac) = hardware_address else {
+        return None;
+    };
+    let mut octets = [0u8; 16];
+    octets[..2].copy_from_slice(&[0xfe, 0x80]);
+    octets[8..11].copy_from_slice(&mac[..3]);
+    octets[8] ^= 0x02;
+    octets[11..13].copy_from_slice(&[0xff, 0xfe]);
+    octets[13..].copy_from_slice(&mac[3..]);
+    Some(Ipv6Address::from(octets))
+}
@@ -0,0 +1,96 @@
+//! Known Wi-Fi networks and the order they are tried in. Only depends on
+//! `core` and `heapless`, so it can be exercised on the host without a radio.
+
+use heapless::Vec;
+
+pub const MAX_KNOWN_NETWORKS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WiFiNetwork {
+    pub ssid: &'static str,
+    pub password: &'static str,
+}
+
+/// Orders `networks` for connection attempts: networks seen in `scan` (pairs
+/// of SSID and RSSI) first, strongest signal first, then the ones that were
+/// not seen, which may be hidden.
+pub fn rank_networks<'s>(
+    networks: &[WiFiNetwork],
+    scan: impl Iterator<Item = (&'s str, i8)> + Clone,
+) -> Vec<(WiFiNetwork, Option<i8>), MAX_KNOWN_NETWORKS> {
+    let mut ranked: Vec<(usize, WiFiNetwork, Option<i8>), MAX_KNOWN_NETWORKS> = networks.iter()
+        .take(MAX_KNOWN_NETWORKS)
+        .enumerate()
+        .map(|(index, network)| {
+            let best_rssi = scan.clone()
+                .filter(|(ssid, _)| *ssid == network.ssid)
+                .map(|(_, rssi)| rssi)
+                .max();
+            (index, *network, best_rssi)
+        })
+        .collect();
+
+    // `None` orders before any `Some`, so a descending sort puts unseen networks
+    // last. Ties keep the configured order.
+    ranked.sort_unstable_by(|(index_a, _, rssi_a), (index_b, _, rssi_b)| {
+        rssi_b.cmp(rssi_a).then(index_a.cmp(index_b))
+    });
+    ranked.into_iter().map(|(_, network, rssi)| (network, rssi)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOME: WiFiNetwork = WiFiNetwork { ssid: "home", password: "secret" };
+    const OFFICE: WiFiNetwork = WiFiNetwork { ssid: "office", password: "secret" };
+    const GARAGE: WiFiNetwork = WiFiNetwork { ssid: "garage", password: "" };
+
+    fn ssids(ranked: &[(WiFiNetwork, Option<i8>)]) -> std::vec::Vec<(&'static str, Option<i8>)> {
+        ranked.iter().map(|(network, rssi)| (network.ssid, *rssi)).collect()
+    }
+
+    #[test]
+    fn seen_networks_are_ordered_by_rssi() {
+        let scan = [("home", -80), ("office", -50), ("garage", -65)];
+
+        let ranked = rank_networks(&[HOME, OFFICE, GARAGE], scan.into_iter());
+
+        assert_eq!(ssids(&ranked), [("office", Some(-50)), ("garage", Some(-65)), ("home", Some(-80))]);
+    }
+
+    #[test]
+    fn unknown_networks_are_ignored() {
+        let scan = [("neighbour", -30), ("home", -70), ("cafe", -40)];
+
+        let ranked = rank_networks(&[HOME], scan.into_iter());
+
+        assert_eq!(ssids(&ranked), [("home", Some(-70))]);
+    }
+
+    #[test]
+    fn unseen_networks_come_last_in_configured_order() {
+        // Hidden access points are reported with an empty SSID
+        let scan = [("", -40), ("office", -75)];
+
+        let ranked = rank_networks(&[GARAGE, HOME, OFFICE], scan.into_iter());
+
+        assert_eq!(ssids(&ranked), [("office", Some(-75)), ("garage", None), ("home", None)]);
+    }
+
+    #[test]
+    fn duplicate_ssids_rank_by_their_strongest_access_point() {
+        let scan = [("home", -85), ("office", -60), ("home", -55), ("home", -90)];
+
+        let ranked = rank_networks(&[OFFICE, HOME], scan.into_iter());
+
+        assert_eq!(ssids(&ranked), [("home", Some(-55)), ("office", Some(-60))]);
+    }
+
+    #[test]
+    fn empty_scan_keeps_configured_order() {
+        let ranked = rank_networks(&[OFFICE, HOME], core::iter::empty());
+
+        assert_eq!(ssids(&ranked), [("office", None), ("home", None)]);
+    }
+}